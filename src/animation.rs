@@ -0,0 +1,288 @@
+use std::{fmt, io::Cursor};
+
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        png::PngDecoder,
+        webp::WebPDecoder,
+    },
+    AnimationDecoder, Frame, ImageError, ImageFormat,
+};
+
+/// more frames than this is a 422 at upload.
+pub const MAX_FRAMES: u32 = 300;
+/// every frame is decoded to a full RGBA canvas, this caps all of them together.
+pub const MAX_DECODED_BYTES: u64 = 256 * 1_024 * 1_024;
+
+#[derive(Debug)]
+pub enum AnimationError {
+    TooManyFrames(u32),
+    TooLarge {
+        frames: u32,
+        width: u32,
+        height: u32,
+    },
+    NoSuchFrame {
+        frame: u32,
+        frames: u32,
+    },
+    BadFrame(String),
+    Image(ImageError),
+}
+
+impl fmt::Display for AnimationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnimationError::TooManyFrames(frames) => {
+                write!(f, "{frames} frames is too many, the limit is {MAX_FRAMES}")
+            }
+            AnimationError::TooLarge {
+                frames,
+                width,
+                height,
+            } => write!(
+                f,
+                "{frames} frames of {width}x{height} decode to more than {MAX_DECODED_BYTES} bytes"
+            ),
+            AnimationError::NoSuchFrame { frame, frames } => {
+                write!(f, "no frame {frame}, the image has {frames}")
+            }
+            AnimationError::BadFrame(value) => {
+                write!(
+                    f,
+                    "`frame` must be a whole number below {MAX_FRAMES}, got {value:?}"
+                )
+            }
+            AnimationError::Image(err) => write!(f, "could not decode frames: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for AnimationError {}
+
+impl From<ImageError> for AnimationError {
+    fn from(err: ImageError) -> Self {
+        AnimationError::Image(err)
+    }
+}
+
+impl AnimationError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            AnimationError::NoSuchFrame { .. } | AnimationError::BadFrame(_) => 400,
+            _ => 422,
+        }
+    }
+}
+
+/// `frame=N`, which frame to render as a still.
+pub fn parse_frame(value: &str) -> Result<u32, AnimationError> {
+    match value.trim().parse::<u32>() {
+        Ok(frame) if frame < MAX_FRAMES => Ok(frame),
+        _ => Err(AnimationError::BadFrame(value.to_string())),
+    }
+}
+
+/// both caps, from the frame count and the canvas size alone.
+pub fn check(frames: u32, width: u32, height: u32) -> Result<(), AnimationError> {
+    if frames > MAX_FRAMES {
+        return Err(AnimationError::TooManyFrames(frames));
+    }
+    if frames as u64 * width as u64 * height as u64 * 4 > MAX_DECODED_BYTES {
+        return Err(AnimationError::TooLarge {
+            frames,
+            width,
+            height,
+        });
+    }
+    Ok(())
+}
+
+/// frames announced by the container, counted without decoding any pixels.
+/// 1 for still images and for anything we cannot walk, the decoder has the last word.
+pub fn count_frames(bytes: &[u8], format: ImageFormat) -> u32 {
+    let counted = match format {
+        ImageFormat::Gif => gif_frames(bytes),
+        ImageFormat::Png => apng_frames(bytes),
+        ImageFormat::WebP => webp_frames(bytes),
+        _ => None,
+    };
+    counted.unwrap_or(1).max(1)
+}
+
+/// skips data sub-blocks, returns the position after the terminator.
+fn skip_sub_blocks(bytes: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *bytes.get(pos)? as usize;
+        pos += 1 + len;
+        if len == 0 {
+            return Some(pos);
+        }
+    }
+}
+
+/// one image descriptor per frame.
+fn gif_frames(bytes: &[u8]) -> Option<u32> {
+    let flags = *bytes.get(10)?;
+    let mut pos = 13;
+    if flags & 0x80 != 0 {
+        pos += 3 << ((flags & 0x07) + 1);
+    }
+    let mut frames = 0;
+    loop {
+        match *bytes.get(pos)? {
+            //? extension: label, then sub-blocks
+            0x21 => pos = skip_sub_blocks(bytes, pos + 2)?,
+            0x2C => {
+                let flags = *bytes.get(pos + 9)?;
+                pos += 10;
+                if flags & 0x80 != 0 {
+                    pos += 3 << ((flags & 0x07) + 1);
+                }
+                //? LZW minimum code size, then the image data
+                pos = skip_sub_blocks(bytes, pos + 1)?;
+                frames += 1;
+                if frames > MAX_FRAMES {
+                    return Some(frames);
+                }
+            }
+            //? the trailer, or garbage after the last frame
+            _ => return Some(frames),
+        }
+    }
+}
+
+/// `acTL` carries the frame count, it has to come before the first `IDAT`.
+fn apng_frames(bytes: &[u8]) -> Option<u32> {
+    let mut pos = 8;
+    loop {
+        let len = u32::from_be_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        match bytes.get(pos + 4..pos + 8)? {
+            b"acTL" => {
+                let data = bytes.get(pos + 8..pos + 12)?;
+                return Some(u32::from_be_bytes(data.try_into().ok()?));
+            }
+            b"IDAT" | b"IEND" => return Some(1),
+            _ => pos += 12 + len,
+        }
+    }
+}
+
+/// one `ANMF` chunk per frame, none at all in a still WebP.
+fn webp_frames(bytes: &[u8]) -> Option<u32> {
+    let mut pos = 12;
+    let mut frames = 0;
+    while let Some(header) = bytes.get(pos..pos + 8) {
+        let len = u32::from_le_bytes(header[4..8].try_into().ok()?) as usize;
+        if &header[..4] == b"ANMF" {
+            frames += 1;
+        }
+        //? chunks are padded to an even length
+        pos += 8 + len + (len & 1);
+    }
+    Some(frames)
+}
+
+/// the first `limit` frames as full canvases with their delays,
+/// stops with an error as soon as the caps are crossed.
+pub fn decode_frames(
+    bytes: &[u8],
+    format: ImageFormat,
+    limit: u32,
+) -> Result<Vec<Frame>, AnimationError> {
+    let frames = match format {
+        ImageFormat::Gif => GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+        ImageFormat::Png => PngDecoder::new(Cursor::new(bytes))?.apng().into_frames(),
+        ImageFormat::WebP => WebPDecoder::new(Cursor::new(bytes))?.into_frames(),
+        _ => {
+            let still = image::load_from_memory_with_format(bytes, format)?.to_rgba8();
+            return Ok(vec![Frame::new(still)]);
+        }
+    };
+    let mut decoded = vec![];
+    for frame in frames.take(limit.min(MAX_FRAMES + 1) as usize) {
+        let frame = frame?;
+        let (width, height) = frame.buffer().dimensions();
+        check(decoded.len() as u32 + 1, width, height)?;
+        decoded.push(frame);
+    }
+    Ok(decoded)
+}
+
+/// loops forever like the browsers expect from an animated GIF.
+pub fn encode_gif(frames: Vec<Frame>) -> Result<Vec<u8>, ImageError> {
+    let mut bytes = vec![];
+    {
+        //? speed 10 of 30: a good palette without taking seconds per frame
+        let mut encoder = GifEncoder::new_with_speed(&mut bytes, 10);
+        encoder.set_repeat(Repeat::Infinite)?;
+        encoder.encode_frames(frames)?;
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{Delay, Rgba, RgbaImage};
+
+    fn gif(frames: u32) -> Vec<u8> {
+        let frames = (0..frames).map(|i| {
+            Frame::from_parts(
+                RgbaImage::from_pixel(8, 4, Rgba([i as u8 * 40, 0, 0, 255])),
+                0,
+                0,
+                Delay::from_numer_denom_ms(100, 1),
+            )
+        });
+        encode_gif(frames.collect()).unwrap()
+    }
+
+    #[test]
+    fn counts_frames_from_the_container() {
+        assert_eq!(count_frames(&gif(3), ImageFormat::Gif), 3);
+        assert_eq!(count_frames(&gif(1), ImageFormat::Gif), 1);
+        assert_eq!(count_frames(b"GIF89a", ImageFormat::Gif), 1);
+
+        let mut apng = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (kind, data) in [
+            (&b"IHDR"[..], &[0u8; 13][..]),
+            (b"acTL", &[0, 0, 0, 7, 0, 0, 0, 0]),
+        ] {
+            apng.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            apng.extend_from_slice(kind);
+            apng.extend_from_slice(data);
+            apng.extend_from_slice(&[0; 4]);
+        }
+        assert_eq!(count_frames(&apng, ImageFormat::Png), 7);
+
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8X\x0a\0\0\0".to_vec();
+        webp.extend_from_slice(&[0; 10]);
+        for _ in 0..2 {
+            webp.extend_from_slice(b"ANMF\x03\0\0\0abc\0");
+        }
+        assert_eq!(count_frames(&webp, ImageFormat::WebP), 2);
+    }
+
+    #[test]
+    fn keeps_frames_and_delays() {
+        let frames = decode_frames(&gif(3), ImageFormat::Gif, u32::MAX).unwrap();
+        assert_eq!(frames.len(), 3);
+        assert_eq!(frames[1].delay().numer_denom_ms(), (100, 1));
+        assert_eq!(frames[2].buffer().get_pixel(0, 0)[0], 80);
+        assert_eq!(
+            decode_frames(&gif(3), ImageFormat::Gif, 2).unwrap().len(),
+            2
+        );
+
+        assert!(matches!(
+            check(MAX_FRAMES + 1, 1, 1),
+            Err(AnimationError::TooManyFrames(_))
+        ));
+        assert!(matches!(
+            check(100, 2_000, 2_000),
+            Err(AnimationError::TooLarge { .. })
+        ));
+        assert!(check(100, 200, 200).is_ok());
+    }
+}
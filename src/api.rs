@@ -0,0 +1,80 @@
+use serde::Serialize;
+
+use crate::upload::{Upload, UploadError};
+
+#[derive(Debug, Serialize)]
+pub struct ApiError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// `{"error": {"code": .., "message": ..}}` with the given status.
+pub fn error(status: u16, code: &'static str, message: impl ToString) -> rouille::Response {
+    #[derive(Serialize)]
+    struct Body {
+        error: ApiError,
+    }
+    rouille::Response::json(&Body {
+        error: ApiError {
+            code,
+            message: message.to_string(),
+        },
+    })
+    .with_status_code(status)
+}
+
+/// generic code for errors that only carry an http status.
+pub fn code_for(status: u16) -> &'static str {
+    match status {
+        413 => "payload_too_large",
+        415 => "unsupported_media_type",
+        401 => "unauthorized",
+        403 => "forbidden",
+        404 => "not_found",
+        429 => "rate_limited",
+        503 => "busy",
+        400..=499 => "bad_request",
+        _ => "internal_error",
+    }
+}
+
+/// one entry per file part, a failing file does not sink the batch.
+#[derive(Debug, Serialize)]
+pub struct FileResult {
+    pub index: usize,
+    pub filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<Upload>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ApiError>,
+}
+
+impl FileResult {
+    pub fn new(
+        index: usize,
+        filename: Option<String>,
+        result: Result<Upload, UploadError>,
+    ) -> Self {
+        let (image, error) = match result {
+            Ok(upload) => (Some(upload), None),
+            Err(err) => (
+                None,
+                Some(ApiError {
+                    code: err.code(),
+                    message: err.to_string(),
+                }),
+            ),
+        };
+        Self {
+            index,
+            filename,
+            image,
+            error,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UploadResponse {
+    pub files: Vec<FileResult>,
+}
@@ -0,0 +1,286 @@
+use std::{collections::HashSet, fmt, fs, io, path::Path};
+
+use serde::Deserialize;
+
+use crate::config::Secret;
+use crate::signing::{SecretTooShort, Signer};
+
+pub const TOKENS_FILE: &str = "tokens.toml";
+/// anything shorter is guessable.
+const MIN_TOKEN_LEN: usize = 16;
+
+/// one `[[tokens]]` entry.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenSpec {
+    owner: String,
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenFile {
+    /// reads need a token or a signed url too, not only uploads.
+    #[serde(default)]
+    private: bool,
+    /// on-the-fly `/img` renders need a signed url (or a token), see [`Signer`].
+    #[serde(default)]
+    signed_urls: bool,
+    #[serde(default)]
+    tokens: Vec<TokenSpec>,
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    BadOwner(String),
+    ShortToken(String),
+    DuplicateToken(String),
+    Secret(SecretTooShort),
+    NoSecret,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::Io(err) => write!(f, "cannot read tokens: {err}"),
+            AuthError::Toml(err) => write!(f, "invalid tokens file: {err}"),
+            AuthError::BadOwner(owner) => write!(
+                f,
+                "owner {owner:?} must be 1 to 64 lowercase letters, digits, `-` or `_`"
+            ),
+            AuthError::ShortToken(owner) => write!(
+                f,
+                "the token of `{owner}` must be at least {MIN_TOKEN_LEN} characters"
+            ),
+            AuthError::DuplicateToken(owner) => {
+                write!(f, "the token of `{owner}` is used twice")
+            }
+            AuthError::Secret(err) => err.fmt(f),
+            AuthError::NoSecret => write!(f, "signed urls need URL_SIGNING_SECRET"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// every byte is compared, the time taken says nothing about the secret.
+pub fn same_secret(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+fn valid_owner(owner: &str) -> bool {
+    (1..=64).contains(&owner.len())
+        && owner
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-' || b == b'_')
+}
+
+/// what the server config says on top of the tokens file.
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    /// overrides the file's `private` when set.
+    pub private: Option<bool>,
+    /// overrides the file's `signed_urls` when set.
+    pub signed_urls: Option<bool>,
+    /// enables signed urls.
+    pub url_signing_secret: Option<Secret>,
+    /// sweeps, scrapes and reads whatever the owners uploaded.
+    pub admin_token: Option<Secret>,
+}
+
+/// who may upload, and who may read when the server is private.
+pub struct Auth {
+    /// (token, owner).
+    tokens: Vec<(String, String)>,
+    pub private: bool,
+    pub signed_urls: bool,
+    /// mints and checks the time-limited links.
+    pub signer: Option<Signer>,
+    pub admin_token: Option<Secret>,
+}
+
+impl Auth {
+    fn parse(text: &str) -> Result<TokenFile, AuthError> {
+        let file: TokenFile = toml::from_str(text).map_err(AuthError::Toml)?;
+        let mut seen = HashSet::new();
+        for spec in &file.tokens {
+            if !valid_owner(&spec.owner) {
+                return Err(AuthError::BadOwner(spec.owner.clone()));
+            }
+            if spec.token.len() < MIN_TOKEN_LEN {
+                return Err(AuthError::ShortToken(spec.owner.clone()));
+            }
+            if !seen.insert(&spec.token) {
+                return Err(AuthError::DuplicateToken(spec.owner.clone()));
+            }
+        }
+        Ok(file)
+    }
+
+    /// `path` (see [`TOKENS_FILE`]) for the tokens, `settings` override the file.
+    /// without a file only local clients can upload, anonymously.
+    pub fn load(path: &Path, settings: &Settings) -> Result<Self, AuthError> {
+        let file = match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text)?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                println!(">> no {}, only local clients can upload", path.display());
+                TokenFile {
+                    private: false,
+                    signed_urls: false,
+                    tokens: vec![],
+                }
+            }
+            Err(err) => return Err(AuthError::Io(err)),
+        };
+        let signer = match &settings.url_signing_secret {
+            Some(secret) => {
+                Some(Signer::new(secret.expose().as_bytes()).map_err(AuthError::Secret)?)
+            }
+            None => None,
+        };
+        let signed_urls = settings.signed_urls.unwrap_or(file.signed_urls);
+        if signed_urls && signer.is_none() {
+            return Err(AuthError::NoSecret);
+        }
+        Ok(Self {
+            tokens: file
+                .tokens
+                .into_iter()
+                .map(|spec| (spec.token, spec.owner))
+                .collect(),
+            private: settings.private.unwrap_or(file.private),
+            signed_urls,
+            signer,
+            admin_token: settings.admin_token.clone(),
+        })
+    }
+
+    pub fn has_tokens(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// the owner `token` belongs to.
+    pub fn owner(&self, token: &str) -> Option<&str> {
+        //? no early exit, every token is compared
+        self.tokens
+            .iter()
+            .filter(|(known, _)| same_secret(token, known))
+            .fold(None, |found, (_, owner)| found.or(Some(owner.as_str())))
+    }
+
+    /// never true without an admin token configured.
+    pub fn is_admin_token(&self, token: &str) -> bool {
+        self.admin_token
+            .as_ref()
+            .is_some_and(|admin| same_secret(token, admin.expose()))
+    }
+}
+
+/// `Authorization: Bearer {token}`.
+pub fn bearer(header: Option<&str>) -> Option<&str> {
+    header?.strip_prefix("Bearer ").map(str::trim)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_tokens() {
+        let file = Auth::parse(
+            r#"
+            private = true
+
+            [[tokens]]
+            owner = "alice"
+            token = "alice-0123456789abcdef"
+
+            [[tokens]]
+            owner = "bob"
+            token = "bob-0123456789abcdef"
+            "#,
+        )
+        .unwrap();
+        assert!(file.private);
+        assert!(!file.signed_urls);
+        let auth = Auth {
+            tokens: file
+                .tokens
+                .into_iter()
+                .map(|spec| (spec.token, spec.owner))
+                .collect(),
+            private: file.private,
+            signed_urls: file.signed_urls,
+            signer: None,
+            admin_token: Some(Secret::new("admin-0123456789abcdef")),
+        };
+        assert_eq!(auth.owner("alice-0123456789abcdef"), Some("alice"));
+        assert_eq!(auth.owner("bob-0123456789abcdef"), Some("bob"));
+        assert_eq!(auth.owner("bob-0123456789abcdeF"), None);
+        assert_eq!(auth.owner(""), None);
+        assert!(auth.is_admin_token("admin-0123456789abcdef"));
+        assert!(!auth.is_admin_token("alice-0123456789abcdef"));
+        assert_eq!(bearer(Some("Bearer abc ")), Some("abc"));
+        assert_eq!(bearer(Some("Basic abc")), None);
+
+        let entry = |owner: &str, token: &str| {
+            format!("[[tokens]]\nowner = {owner:?}\ntoken = {token:?}\n")
+        };
+        assert!(matches!(
+            Auth::parse(&entry("Alice", "0123456789abcdef")),
+            Err(AuthError::BadOwner(_))
+        ));
+        assert!(matches!(
+            Auth::parse(&entry("alice", "short")),
+            Err(AuthError::ShortToken(_))
+        ));
+        assert!(matches!(
+            Auth::parse(&(entry("a", "0123456789abcdef") + &entry("b", "0123456789abcdef"))),
+            Err(AuthError::DuplicateToken(_))
+        ));
+    }
+
+    #[test]
+    fn settings_override_the_file() {
+        let missing = Path::new("/no/such/tokens.toml");
+        let auth = Auth::load(
+            missing,
+            &Settings {
+                private: Some(true),
+                ..Settings::default()
+            },
+        )
+        .unwrap();
+        //? private alone does not ask for signatures
+        assert!(auth.private);
+        assert!(!auth.signed_urls);
+        assert!(auth.signer.is_none());
+
+        let signed = Settings {
+            signed_urls: Some(true),
+            ..Settings::default()
+        };
+        assert!(matches!(
+            Auth::load(missing, &signed),
+            Err(AuthError::NoSecret)
+        ));
+        let auth = Auth::load(
+            missing,
+            &Settings {
+                url_signing_secret: Some(Secret::new("0123456789abcdef")),
+                ..signed
+            },
+        )
+        .unwrap();
+        assert!(auth.signed_urls);
+        assert!(!auth.private);
+        assert!(auth.signer.is_some());
+    }
+}
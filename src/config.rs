@@ -0,0 +1,850 @@
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs, io,
+    net::ToSocketAddrs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{auth, form, jobs, overlay, presets, resize, retention, signing, storage, throttle};
+
+/// read when it exists, `--config` or `CONFIG` point somewhere else.
+pub const CONFIG_FILE: &str = "server.toml";
+/// a job worker decodes whole images, past this the machine only thrashes.
+const MAX_WORKERS: usize = 64;
+
+const POSITIVE: &str = "a whole number greater than 0";
+const FRACTION: &str = "a number above 0 and up to 1";
+const FLAG: &str = "1, true, 0 or false";
+
+/// one knob, under the name it has in each source.
+struct Setting {
+    key: &'static str,
+    env: &'static str,
+    flag: &'static str,
+    expected: &'static str,
+}
+
+static SETTINGS: [Setting; 30] = [
+    Setting {
+        key: "bind",
+        env: "BIND",
+        flag: "--bind",
+        expected: "a host:port to listen on",
+    },
+    Setting {
+        key: "data_dir",
+        env: "DATA_DIR",
+        flag: "--data-dir",
+        expected: "an existing directory",
+    },
+    Setting {
+        key: "presets",
+        env: "PRESETS_FILE",
+        flag: "--presets",
+        expected: "a path",
+    },
+    Setting {
+        key: "tokens",
+        env: "TOKENS_FILE",
+        flag: "--tokens",
+        expected: "a path",
+    },
+    Setting {
+        key: "workers",
+        env: "WORKERS",
+        flag: "--workers",
+        expected: "a whole number between 1 and 64",
+    },
+    Setting {
+        key: "max_file_size",
+        env: "MAX_FILE_SIZE",
+        flag: "--max-file-size",
+        expected: "a number of bytes greater than 0",
+    },
+    Setting {
+        key: "max_request_size",
+        env: "MAX_REQUEST_SIZE",
+        flag: "--max-request-size",
+        expected: "a number of bytes, at least max_file_size",
+    },
+    Setting {
+        key: "max_resumable_size",
+        env: "MAX_RESUMABLE_SIZE",
+        flag: "--max-resumable-size",
+        expected: "a number of bytes greater than 0",
+    },
+    Setting {
+        key: "thumbnail",
+        env: "THUMBNAIL_SIZE",
+        flag: "--thumbnail",
+        expected: "`{width}x{height}`, each between 1 and 4096",
+    },
+    Setting {
+        key: "storage",
+        env: "STORAGE",
+        flag: "--storage",
+        expected: "one of: fs, memory, s3",
+    },
+    Setting {
+        key: "s3_endpoint",
+        env: "S3_ENDPOINT",
+        flag: "--s3-endpoint",
+        expected: "a url starting with http:// or https://",
+    },
+    Setting {
+        key: "s3_bucket",
+        env: "S3_BUCKET",
+        flag: "--s3-bucket",
+        expected: "a bucket name",
+    },
+    Setting {
+        key: "s3_region",
+        env: "S3_REGION",
+        flag: "--s3-region",
+        expected: "a region name",
+    },
+    Setting {
+        key: "s3_access_key_id",
+        env: "S3_ACCESS_KEY_ID",
+        flag: "--s3-access-key-id",
+        expected: "an access key id",
+    },
+    Setting {
+        key: "s3_secret_access_key",
+        env: "S3_SECRET_ACCESS_KEY",
+        flag: "--s3-secret-access-key",
+        expected: "a secret access key",
+    },
+    Setting {
+        key: "retention_max_age_secs",
+        env: "RETENTION_MAX_AGE_SECS",
+        flag: "--retention-max-age-secs",
+        expected: POSITIVE,
+    },
+    Setting {
+        key: "retention_max_bytes",
+        env: "RETENTION_MAX_BYTES",
+        flag: "--retention-max-bytes",
+        expected: POSITIVE,
+    },
+    Setting {
+        key: "retention_sweep_secs",
+        env: "RETENTION_SWEEP_SECS",
+        flag: "--retention-sweep-secs",
+        expected: POSITIVE,
+    },
+    Setting {
+        key: "uploads_per_minute",
+        env: "UPLOADS_PER_MINUTE",
+        flag: "--uploads-per-minute",
+        expected: POSITIVE,
+    },
+    Setting {
+        key: "upload_burst",
+        env: "UPLOAD_BURST",
+        flag: "--upload-burst",
+        expected: POSITIVE,
+    },
+    Setting {
+        key: "max_decodes",
+        env: "MAX_DECODES",
+        flag: "--max-decodes",
+        expected: POSITIVE,
+    },
+    Setting {
+        key: "watermark_image",
+        env: "WATERMARK_IMAGE",
+        flag: "--watermark-image",
+        expected: "the path of a PNG",
+    },
+    Setting {
+        key: "watermark_position",
+        env: "WATERMARK_POSITION",
+        flag: "--watermark-position",
+        expected: "one of: top-left, top, top-right, left, center, right, bottom-left, bottom, bottom-right",
+    },
+    Setting {
+        key: "watermark_margin",
+        env: "WATERMARK_MARGIN",
+        flag: "--watermark-margin",
+        expected: "a whole number of pixels",
+    },
+    Setting {
+        key: "watermark_opacity",
+        env: "WATERMARK_OPACITY",
+        flag: "--watermark-opacity",
+        expected: FRACTION,
+    },
+    Setting {
+        key: "watermark_scale",
+        env: "WATERMARK_SCALE",
+        flag: "--watermark-scale",
+        expected: FRACTION,
+    },
+    Setting {
+        key: "private",
+        env: "PRIVATE",
+        flag: "--private",
+        expected: FLAG,
+    },
+    Setting {
+        key: "signed_urls",
+        env: "SIGNED_URLS",
+        flag: "--signed-urls",
+        expected: FLAG,
+    },
+    Setting {
+        key: "url_signing_secret",
+        env: "URL_SIGNING_SECRET",
+        flag: "--url-signing-secret",
+        expected: "at least 16 bytes",
+    },
+    Setting {
+        key: "admin_token",
+        env: "ADMIN_TOKEN",
+        flag: "--admin-token",
+        expected: "a token",
+    },
+];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Toml(PathBuf, toml::de::Error),
+    UnknownKey(PathBuf, String),
+    UnknownFlag(String),
+    MissingValue(&'static str),
+    Invalid {
+        /// where the value came from: the key, variable or flag.
+        name: String,
+        value: String,
+        expected: String,
+    },
+    /// `key` has no value but `by` needs one.
+    Needs {
+        key: &'static str,
+        by: &'static str,
+    },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "cannot read {}: {err}", path.display()),
+            ConfigError::Toml(path, err) => write!(f, "invalid {}: {err}", path.display()),
+            ConfigError::UnknownKey(path, key) => {
+                write!(f, "unknown key `{key}` in {}, see --help", path.display())
+            }
+            ConfigError::UnknownFlag(flag) => {
+                write!(f, "unknown flag {flag:?}, see --help")
+            }
+            ConfigError::MissingValue(flag) => write!(f, "{flag} needs a value"),
+            ConfigError::Invalid {
+                name,
+                value,
+                expected,
+            } => write!(f, "{name} must be {expected}, got {value:?}"),
+            ConfigError::Needs { key, by } => {
+                write!(f, "{by} needs {key} ({})", setting(key).env)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// a setting kept out of `{:?}`, the config is printed at startup.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into())
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"***\"")
+    }
+}
+
+/// a value as one source gave it, `name` is what an error shows: the key, variable or flag.
+#[derive(Debug)]
+struct Given {
+    name: String,
+    value: String,
+}
+
+/// what one source sets, by key, the rest is left to the sources below it.
+#[derive(Debug, Default)]
+struct Overrides(BTreeMap<&'static str, Given>);
+
+impl Overrides {
+    fn set(&mut self, setting: &Setting, name: &str, value: &str) -> Result<(), ConfigError> {
+        let trimmed = value.trim();
+        if trimmed.is_empty() {
+            return Err(ConfigError::Invalid {
+                name: name.to_string(),
+                value: value.to_string(),
+                expected: setting.expected.to_string(),
+            });
+        }
+        self.0.insert(
+            setting.key,
+            Given {
+                name: name.to_string(),
+                value: trimmed.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// a flat table of settings, by key.
+    fn from_toml(path: &Path, text: &str) -> Result<Self, ConfigError> {
+        let table: toml::Table =
+            toml::from_str(text).map_err(|err| ConfigError::Toml(path.to_path_buf(), err))?;
+        let mut overrides = Self::default();
+        for (key, value) in table {
+            let Some(setting) = SETTINGS.iter().find(|setting| setting.key == key) else {
+                return Err(ConfigError::UnknownKey(path.to_path_buf(), key));
+            };
+            let value = match value {
+                toml::Value::String(value) => value,
+                toml::Value::Integer(n) => n.to_string(),
+                toml::Value::Float(n) => n.to_string(),
+                toml::Value::Boolean(b) => b.to_string(),
+                other => {
+                    return Err(ConfigError::Invalid {
+                        name: key,
+                        value: other.to_string(),
+                        expected: setting.expected.to_string(),
+                    })
+                }
+            };
+            overrides.set(setting, setting.key, &value)?;
+        }
+        Ok(overrides)
+    }
+
+    fn from_env(var: impl Fn(&str) -> Option<String>) -> Result<Self, ConfigError> {
+        let mut overrides = Self::default();
+        for setting in &SETTINGS {
+            if let Some(value) = var(setting.env) {
+                overrides.set(setting, setting.env, &value)?;
+            }
+        }
+        Ok(overrides)
+    }
+
+    /// later sources win: `self` is below `other`.
+    fn merge(mut self, other: Self) -> Self {
+        self.0.extend(other.0);
+        self
+    }
+
+    /// `key` through `parse`, `None` means the value is not one `key` takes.
+    fn value<T>(
+        &self,
+        key: &'static str,
+        parse: impl FnOnce(&str) -> Option<T>,
+    ) -> Result<Option<T>, ConfigError> {
+        let Some(given) = self.0.get(key) else {
+            return Ok(None);
+        };
+        match parse(&given.value) {
+            Some(value) => Ok(Some(value)),
+            None => Err(ConfigError::Invalid {
+                name: given.name.clone(),
+                value: given.value.clone(),
+                expected: setting(key).expected.to_string(),
+            }),
+        }
+    }
+}
+
+fn text(value: &str) -> Option<String> {
+    Some(value.to_string())
+}
+
+fn number<T: FromStr>(value: &str) -> Option<T> {
+    value.parse().ok()
+}
+
+fn positive<T: FromStr + Default + PartialOrd>(value: &str) -> Option<T> {
+    number(value).filter(|n| *n > T::default())
+}
+
+fn fraction(value: &str) -> Option<f32> {
+    number(value).filter(|n| *n > 0.0 && *n <= 1.0)
+}
+
+fn flag(value: &str) -> Option<bool> {
+    match value {
+        "1" | "true" => Some(true),
+        "0" | "false" => Some(false),
+        _ => None,
+    }
+}
+
+/// the command line: `--config`, the overrides, `--check` and `--help`.
+#[derive(Debug, Default)]
+pub struct Args {
+    config: Option<PathBuf>,
+    overrides: Overrides,
+    /// validate and print the settings, then exit.
+    pub check: bool,
+    pub help: bool,
+}
+
+impl Args {
+    /// `--flag value` or `--flag=value`, without the program name.
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, ConfigError> {
+        let mut parsed = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.split_once('=') {
+                Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+                None => (arg.clone(), None),
+            };
+            match flag.as_str() {
+                "--help" | "-h" if inline.is_none() => parsed.help = true,
+                "--check" if inline.is_none() => parsed.check = true,
+                "--config" => {
+                    let value = inline
+                        .or_else(|| args.next())
+                        .ok_or(ConfigError::MissingValue("--config"))?;
+                    parsed.config = Some(value.into());
+                }
+                _ => {
+                    let setting = SETTINGS
+                        .iter()
+                        .find(|setting| setting.flag == flag)
+                        .ok_or(ConfigError::UnknownFlag(arg.clone()))?;
+                    let value = inline
+                        .or_else(|| args.next())
+                        .ok_or(ConfigError::MissingValue(setting.flag))?;
+                    parsed.overrides.set(setting, setting.flag, &value)?;
+                }
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+pub fn usage() -> String {
+    let mut usage = format!(
+        "usage: async-lambda-rust [--config FILE] [--check] [FLAG VALUE]...\n\n\
+         settings come from {CONFIG_FILE} (or --config, CONFIG), then the environment, then flags.\n\n"
+    );
+    for setting in &SETTINGS {
+        usage.push_str(&format!(
+            "  {:<24} {:<24} {:<24} {}\n",
+            setting.flag, setting.env, setting.key, setting.expected
+        ));
+    }
+    usage
+}
+
+/// everything that differs between a dev, staging and prod instance of the same binary.
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub bind: String,
+    /// the fs storage root, the job files and the similarity index live under it.
+    pub data_dir: PathBuf,
+    pub presets: PathBuf,
+    pub tokens: PathBuf,
+    pub workers: usize,
+    pub uploads: form::Limits,
+    /// the `Upload-Length` a resumable upload may announce.
+    pub max_resumable_bytes: u64,
+    /// what an upload is resized to when it asks for no size.
+    pub thumbnail: (u32, u32),
+    pub storage: storage::Backend,
+    pub retention: retention::Policy,
+    pub limits: throttle::Limits,
+    /// `None` unless `watermark_image` is set.
+    pub watermark: Option<overlay::WatermarkSettings>,
+    /// on top of the tokens file.
+    pub auth: auth::Settings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        let thumbnail = resize::ResizeOptions::default();
+        Self {
+            bind: "localhost:8000".to_string(),
+            data_dir: PathBuf::from("."),
+            presets: PathBuf::from(presets::PRESETS_FILE),
+            tokens: PathBuf::from(auth::TOKENS_FILE),
+            workers: jobs::default_workers(),
+            uploads: form::Limits::default(),
+            //? resumable uploads are for what does not fit in one request
+            max_resumable_bytes: 64 * 1_024 * 1_024,
+            thumbnail: (thumbnail.width, thumbnail.height.unwrap_or(thumbnail.width)),
+            storage: storage::Backend::Fs,
+            retention: retention::Policy::default(),
+            limits: throttle::Limits::default(),
+            watermark: None,
+            auth: auth::Settings::default(),
+        }
+    }
+}
+
+fn setting(key: &str) -> &'static Setting {
+    SETTINGS
+        .iter()
+        .find(|setting| setting.key == key)
+        .expect("every key is in SETTINGS")
+}
+
+fn invalid(key: &str, value: impl fmt::Display) -> ConfigError {
+    ConfigError::Invalid {
+        name: key.to_string(),
+        value: value.to_string(),
+        expected: setting(key).expected.to_string(),
+    }
+}
+
+fn parse_thumbnail(value: &str) -> Option<(u32, u32)> {
+    let (width, height) = value.trim().split_once('x')?;
+    let dimension = |value: &str| {
+        value
+            .trim()
+            .parse::<u32>()
+            .ok()
+            .filter(|n| (1..=resize::MAX_DIMENSION).contains(n))
+    };
+    Some((dimension(width)?, dimension(height)?))
+}
+
+fn parse_endpoint(value: &str) -> Option<String> {
+    (value.starts_with("http://") || value.starts_with("https://"))
+        .then(|| value.trim_end_matches('/').to_string())
+}
+
+fn parse_storage(overrides: &Overrides) -> Result<storage::Backend, ConfigError> {
+    let kind = overrides.value("storage", |value| {
+        ["fs", "memory", "s3"]
+            .contains(&value)
+            .then(|| value.to_string())
+    })?;
+    let required = |key: &'static str| {
+        overrides.value(key, text)?.ok_or(ConfigError::Needs {
+            key,
+            by: "storage = \"s3\"",
+        })
+    };
+    Ok(match kind.as_deref() {
+        None | Some("fs") => storage::Backend::Fs,
+        Some("memory") => storage::Backend::Memory,
+        _ => storage::Backend::S3(storage::S3Config {
+            endpoint: overrides.value("s3_endpoint", parse_endpoint)?.ok_or(
+                ConfigError::Needs {
+                    key: "s3_endpoint",
+                    by: "storage = \"s3\"",
+                },
+            )?,
+            bucket: required("s3_bucket")?,
+            region: overrides
+                .value("s3_region", text)?
+                .unwrap_or_else(|| "us-east-1".to_string()),
+            access_key: required("s3_access_key_id")?,
+            secret_key: Secret::new(required("s3_secret_access_key")?),
+        }),
+    })
+}
+
+fn parse_watermark(
+    overrides: &Overrides,
+) -> Result<Option<overlay::WatermarkSettings>, ConfigError> {
+    let position = overrides.value("watermark_position", overlay::Position::parse)?;
+    let margin = overrides.value("watermark_margin", number)?;
+    let opacity = overrides.value("watermark_opacity", fraction)?;
+    let scale = overrides.value("watermark_scale", fraction)?;
+    let Some(image) = overrides.value("watermark_image", |value| Some(PathBuf::from(value)))?
+    else {
+        return Ok(None);
+    };
+    let defaults = overlay::WatermarkSettings::new(image);
+    Ok(Some(overlay::WatermarkSettings {
+        position: position.unwrap_or(defaults.position),
+        margin: margin.unwrap_or(defaults.margin),
+        opacity: opacity.unwrap_or(defaults.opacity),
+        scale: scale.unwrap_or(defaults.scale),
+        ..defaults
+    }))
+}
+
+impl Config {
+    /// the file, then the environment, then `args`, checked as a whole.
+    pub fn load(args: Args) -> Result<Self, ConfigError> {
+        let (path, required) = match args.config.clone() {
+            Some(path) => (path, true),
+            None => match env::var("CONFIG") {
+                Ok(path) => (PathBuf::from(path), true),
+                Err(_) => (PathBuf::from(CONFIG_FILE), false),
+            },
+        };
+        let file = match fs::read_to_string(&path) {
+            Ok(text) => {
+                println!(">> config: {}", path.display());
+                Overrides::from_toml(&path, &text)?
+            }
+            //? the defaults are a working dev setup
+            Err(err) if err.kind() == io::ErrorKind::NotFound && !required => Overrides::default(),
+            Err(err) => return Err(ConfigError::Io(path, err)),
+        };
+        let env = Overrides::from_env(|var| env::var(var).ok())?;
+        Self::resolve(file.merge(env).merge(args.overrides))
+    }
+
+    fn resolve(overrides: Overrides) -> Result<Self, ConfigError> {
+        let defaults = Self::default();
+        let config = Self {
+            bind: overrides.value("bind", text)?.unwrap_or(defaults.bind),
+            data_dir: overrides
+                .value("data_dir", |value| Some(PathBuf::from(value)))?
+                .unwrap_or(defaults.data_dir),
+            presets: overrides
+                .value("presets", |value| Some(PathBuf::from(value)))?
+                .unwrap_or(defaults.presets),
+            tokens: overrides
+                .value("tokens", |value| Some(PathBuf::from(value)))?
+                .unwrap_or(defaults.tokens),
+            workers: overrides
+                .value("workers", number)?
+                .unwrap_or(defaults.workers),
+            uploads: form::Limits {
+                max_file_bytes: overrides
+                    .value("max_file_size", number)?
+                    .unwrap_or(defaults.uploads.max_file_bytes),
+                max_request_bytes: overrides
+                    .value("max_request_size", number)?
+                    .unwrap_or(defaults.uploads.max_request_bytes),
+                ..defaults.uploads
+            },
+            max_resumable_bytes: overrides
+                .value("max_resumable_size", number)?
+                .unwrap_or(defaults.max_resumable_bytes),
+            thumbnail: overrides
+                .value("thumbnail", parse_thumbnail)?
+                .unwrap_or(defaults.thumbnail),
+            storage: parse_storage(&overrides)?,
+            retention: retention::Policy {
+                max_age: overrides
+                    .value("retention_max_age_secs", positive)?
+                    .map(Duration::from_secs),
+                max_bytes: overrides.value("retention_max_bytes", positive)?,
+                interval: overrides
+                    .value("retention_sweep_secs", positive)?
+                    .map_or(defaults.retention.interval, Duration::from_secs),
+            },
+            limits: throttle::Limits {
+                uploads_per_minute: overrides
+                    .value("uploads_per_minute", positive)?
+                    .unwrap_or(defaults.limits.uploads_per_minute),
+                upload_burst: overrides
+                    .value("upload_burst", positive)?
+                    .unwrap_or(defaults.limits.upload_burst),
+                max_decodes: overrides
+                    .value("max_decodes", positive)?
+                    .unwrap_or(defaults.limits.max_decodes),
+            },
+            watermark: parse_watermark(&overrides)?,
+            auth: auth::Settings {
+                private: overrides.value("private", flag)?,
+                signed_urls: overrides.value("signed_urls", flag)?,
+                url_signing_secret: overrides.value("url_signing_secret", |value| {
+                    (value.len() >= signing::MIN_SECRET_BYTES).then(|| Secret::new(value))
+                })?,
+                admin_token: overrides.value("admin_token", |value| Some(Secret::new(value)))?,
+            },
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !self
+            .bind
+            .to_socket_addrs()
+            .is_ok_and(|mut addrs| addrs.next().is_some())
+        {
+            return Err(invalid("bind", &self.bind));
+        }
+        if !self.data_dir.is_dir() {
+            return Err(invalid("data_dir", self.data_dir.display()));
+        }
+        if !(1..=MAX_WORKERS).contains(&self.workers) {
+            return Err(invalid("workers", self.workers));
+        }
+        if self.uploads.max_file_bytes == 0 {
+            return Err(invalid("max_file_size", self.uploads.max_file_bytes));
+        }
+        if self.max_resumable_bytes == 0 {
+            return Err(invalid("max_resumable_size", self.max_resumable_bytes));
+        }
+        //? a file over the request limit could never arrive
+        if self.uploads.max_request_bytes < self.uploads.max_file_bytes {
+            return Err(invalid("max_request_size", self.uploads.max_request_bytes));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args, ConfigError> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn file(text: &str) -> Result<Overrides, ConfigError> {
+        Overrides::from_toml(Path::new(CONFIG_FILE), text)
+    }
+
+    #[test]
+    fn later_sources_win() {
+        let file = file(
+            r#"
+            bind = "127.0.0.1:9000"
+            workers = 2
+            max_file_size = 1000
+            thumbnail = "64x48"
+            "#,
+        )
+        .unwrap();
+        let env = Overrides::from_env(|var| match var {
+            "WORKERS" => Some("3".to_string()),
+            "MAX_REQUEST_SIZE" => Some("5000".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        let flags = args(&["--workers=4", "--bind", "127.0.0.1:9001", "--check"]).unwrap();
+        assert!(flags.check && !flags.help);
+
+        let config = Config::resolve(file.merge(env).merge(flags.overrides)).unwrap();
+        assert_eq!(config.bind, "127.0.0.1:9001");
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.uploads.max_file_bytes, 1000);
+        assert_eq!(config.uploads.max_request_bytes, 5000);
+        assert_eq!(config.uploads.max_parts, form::Limits::default().max_parts);
+        assert_eq!(config.thumbnail, (64, 48));
+        assert_eq!(config.data_dir, PathBuf::from("."));
+
+        let defaults = Config::resolve(Overrides::default()).unwrap();
+        assert_eq!(defaults.bind, "localhost:8000");
+        assert_eq!(defaults.thumbnail, (100, 100));
+        assert!(matches!(defaults.storage, storage::Backend::Fs));
+        assert_eq!(defaults.retention, retention::Policy::default());
+        assert!(defaults.watermark.is_none());
+    }
+
+    #[test]
+    fn folds_in_every_setting() {
+        let file = file(
+            r#"
+            storage = "s3"
+            s3_endpoint = "http://localhost:9000/"
+            s3_bucket = "images"
+            retention_max_age_secs = 3600
+            max_decodes = 2
+            watermark_image = "mark.png"
+            watermark_opacity = 0.8
+            private = true
+            "#,
+        )
+        .unwrap();
+        let env = Overrides::from_env(|var| match var {
+            "S3_ACCESS_KEY_ID" => Some("minio".to_string()),
+            "S3_SECRET_ACCESS_KEY" => Some("minio-secret".to_string()),
+            "SIGNED_URLS" => Some("0".to_string()),
+            "ADMIN_TOKEN" => Some("admin-0123456789".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        let config = Config::resolve(file.merge(env)).unwrap();
+        let storage::Backend::S3(s3) = &config.storage else {
+            panic!("expected s3, got {:?}", config.storage);
+        };
+        assert_eq!(s3.endpoint, "http://localhost:9000");
+        assert_eq!(s3.region, "us-east-1");
+        assert_eq!(s3.secret_key.expose(), "minio-secret");
+        assert_eq!(config.retention.max_age, Some(Duration::from_secs(3600)));
+        assert_eq!(config.limits.max_decodes, 2);
+        let watermark = config.watermark.as_ref().unwrap();
+        assert_eq!(watermark.opacity, 0.8);
+        assert_eq!(watermark.position, overlay::Position::BottomRight);
+        assert_eq!(config.auth.private, Some(true));
+        assert_eq!(config.auth.signed_urls, Some(false));
+        assert_eq!(
+            config.auth.admin_token.as_ref().map(Secret::expose),
+            Some("admin-0123456789")
+        );
+        //? printed at startup
+        let printed = format!("{config:?}");
+        assert!(!printed.contains("minio-secret") && !printed.contains("admin-0123456789"));
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        let error = |text: &str| {
+            Config::resolve(file(text).unwrap())
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error("workers = 0").starts_with("workers must be"));
+        assert!(error("thumbnail = \"100\"").starts_with("thumbnail must be"));
+        assert!(error("thumbnail = \"0x10\"").starts_with("thumbnail must be"));
+        assert!(error("max_file_size = 10\nmax_request_size = 5").starts_with("max_request_size"));
+        assert!(error("bind = \"localhost\"").starts_with("bind must be"));
+        assert!(error("data_dir = \"/no/such/dir\"").starts_with("data_dir must be"));
+        assert!(error("retention_max_bytes = 0").starts_with("retention_max_bytes must be"));
+        assert!(error("watermark_scale = 2").starts_with("watermark_scale must be"));
+        assert!(error("url_signing_secret = \"short\"").starts_with("url_signing_secret"));
+        assert_eq!(
+            error("storage = \"s3\"\ns3_endpoint = \"http://minio:9000\""),
+            "storage = \"s3\" needs s3_bucket (S3_BUCKET)"
+        );
+        assert!(matches!(
+            file("port = 1"),
+            Err(ConfigError::UnknownKey(_, key)) if key == "port"
+        ));
+        assert!(matches!(
+            file("workers = [1]"),
+            Err(ConfigError::Invalid { .. })
+        ));
+
+        let env = Overrides::from_env(|var| (var == "MAX_FILE_SIZE").then(|| "1mb".to_string()));
+        assert_eq!(
+            Config::resolve(env.unwrap()).unwrap_err().to_string(),
+            "MAX_FILE_SIZE must be a number of bytes greater than 0, got \"1mb\""
+        );
+        let env = Overrides::from_env(|var| (var == "PRIVATE").then(|| "yes".to_string()));
+        assert_eq!(
+            Config::resolve(env.unwrap()).unwrap_err().to_string(),
+            "PRIVATE must be 1, true, 0 or false, got \"yes\""
+        );
+        assert!(matches!(
+            args(&["--nope"]),
+            Err(ConfigError::UnknownFlag(_))
+        ));
+        assert!(matches!(
+            args(&["--workers"]),
+            Err(ConfigError::MissingValue("--workers"))
+        ));
+        let flags = args(&["--workers", "many"]).unwrap();
+        assert!(matches!(
+            Config::resolve(flags.overrides),
+            Err(ConfigError::Invalid { name, .. }) if name == "--workers"
+        ));
+    }
+}
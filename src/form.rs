@@ -0,0 +1,363 @@
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    fmt,
+    io::{self, Read},
+    rc::Rc,
+};
+
+use multipart::server::Multipart;
+
+/// hard caps for one multipart request, enforced while the body streams in.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_file_bytes: u64,
+    pub max_request_bytes: u64,
+    pub max_parts: usize,
+    /// plain form fields (`w`, `fit`, ...) are tiny, anything bigger is abuse.
+    pub max_field_bytes: u64,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_file_bytes: 1_024 * 500, /*500 kb*/
+            max_request_bytes: 1_024 * 1_024 * 2,
+            max_parts: 16,
+            max_field_bytes: 1_024,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FormError {
+    NotMultipart,
+    BodyAlreadyRead,
+    FileTooLarge {
+        filename: Option<String>,
+        limit: u64,
+    },
+    FieldTooLarge {
+        name: String,
+        limit: u64,
+    },
+    RequestTooLarge {
+        limit: u64,
+    },
+    TooManyParts {
+        limit: usize,
+    },
+    Malformed(io::Error),
+}
+
+impl fmt::Display for FormError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormError::NotMultipart => write!(f, "expected a multipart/form-data body"),
+            FormError::BodyAlreadyRead => write!(f, "request body was already consumed"),
+            FormError::FileTooLarge { filename, limit } => write!(
+                f,
+                "file {} is over the {limit} bytes limit",
+                filename.as_deref().unwrap_or("(unnamed)")
+            ),
+            FormError::FieldTooLarge { name, limit } => {
+                write!(f, "field `{name}` is over the {limit} bytes limit")
+            }
+            FormError::RequestTooLarge { limit } => {
+                write!(f, "request body is over the {limit} bytes limit")
+            }
+            FormError::TooManyParts { limit } => {
+                write!(f, "too many parts, at most {limit} are allowed")
+            }
+            FormError::Malformed(err) => write!(f, "malformed multipart body: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for FormError {}
+
+impl FormError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            FormError::FileTooLarge { .. }
+            | FormError::FieldTooLarge { .. }
+            | FormError::RequestTooLarge { .. }
+            | FormError::TooManyParts { .. } => 413,
+            FormError::NotMultipart => 415,
+            FormError::BodyAlreadyRead | FormError::Malformed(_) => 400,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FilePart {
+    pub filename: Option<String>,
+    pub data: Vec<u8>,
+}
+
+#[derive(Debug, Default)]
+pub struct Form {
+    pub files: Vec<FilePart>,
+    fields: HashMap<String, String>,
+}
+
+impl Form {
+    pub fn field(&self, name: &str) -> Option<String> {
+        self.fields.get(name).cloned()
+    }
+}
+
+/// counts every body byte, chunked or not, and fails once past `limit`.
+struct LimitedBody<R> {
+    inner: R,
+    read: Rc<Cell<u64>>,
+    limit: u64,
+}
+
+impl<R: Read> Read for LimitedBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.set(self.read.get() + n as u64);
+        if self.read.get() > self.limit {
+            return Err(io::Error::other("request body over the limit"));
+        }
+        Ok(n)
+    }
+}
+
+fn boundary(req: &rouille::Request) -> Option<String> {
+    let content_type = req.header("Content-Type")?;
+    if !content_type
+        .to_ascii_lowercase()
+        .starts_with("multipart/form-data")
+    {
+        return None;
+    }
+    let start = content_type.find("boundary=")? + "boundary=".len();
+    let value = content_type[start..].split(';').next()?.trim();
+    Some(value.trim_matches('"').to_string())
+}
+
+/// reads `limit + 1` bytes at most, so going over is noticed without buffering the rest.
+fn read_capped(data: &mut impl Read, limit: u64) -> io::Result<Option<Vec<u8>>> {
+    let mut buf = vec![];
+    data.take(limit + 1).read_to_end(&mut buf)?;
+    Ok((buf.len() as u64 <= limit).then_some(buf))
+}
+
+/// streams the multipart body of `req`, aborting as soon as a limit is crossed.
+/// parts named `files` (or carrying a filename) are files, the rest are fields.
+pub fn read(req: &rouille::Request, limits: &Limits) -> Result<Form, FormError> {
+    //? cheap early exit, the streaming counter below is what actually enforces it
+    if let Some(length) = req.header("Content-Length") {
+        match length.trim().parse::<u64>() {
+            Ok(size) if size > limits.max_request_bytes => {
+                println!(">> {size} bytes!");
+                return Err(FormError::RequestTooLarge {
+                    limit: limits.max_request_bytes,
+                });
+            }
+            Ok(_) => {}
+            Err(_) => {
+                return Err(FormError::Malformed(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "bad Content-Length",
+                )))
+            }
+        }
+    }
+
+    let boundary = boundary(req).ok_or(FormError::NotMultipart)?;
+    let body = req.data().ok_or(FormError::BodyAlreadyRead)?;
+    read_body(body, boundary, limits)
+}
+
+/// [`read`] past the headers, `body` may be chunked and never say how long it is.
+fn read_body(body: impl Read, boundary: String, limits: &Limits) -> Result<Form, FormError> {
+    let read = Rc::new(Cell::new(0));
+    let mut multipart = Multipart::with_body(
+        LimitedBody {
+            inner: body,
+            read: read.clone(),
+            limit: limits.max_request_bytes,
+        },
+        boundary,
+    );
+    let over_limit = |err: io::Error| {
+        if read.get() > limits.max_request_bytes {
+            FormError::RequestTooLarge {
+                limit: limits.max_request_bytes,
+            }
+        } else {
+            FormError::Malformed(err)
+        }
+    };
+
+    let mut form = Form::default();
+    let mut parts = 0;
+    while let Some(mut entry) = multipart.read_entry().map_err(over_limit)? {
+        parts += 1;
+        if parts > limits.max_parts {
+            return Err(FormError::TooManyParts {
+                limit: limits.max_parts,
+            });
+        }
+
+        let name = entry.headers.name.to_string();
+        let filename = entry.headers.filename.clone();
+        if name == "files" || filename.is_some() {
+            let data = read_capped(&mut entry.data, limits.max_file_bytes)
+                .map_err(over_limit)?
+                .ok_or(FormError::FileTooLarge {
+                    filename: filename.clone(),
+                    limit: limits.max_file_bytes,
+                })?;
+            form.files.push(FilePart { filename, data });
+        } else {
+            let data = read_capped(&mut entry.data, limits.max_field_bytes)
+                .map_err(over_limit)?
+                .ok_or(FormError::FieldTooLarge {
+                    name: name.clone(),
+                    limit: limits.max_field_bytes,
+                })?;
+            form.fields
+                .insert(name, String::from_utf8_lossy(&data).into_owned());
+        }
+    }
+    Ok(form)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BOUNDARY: &str = "XbX";
+
+    fn limits() -> Limits {
+        Limits {
+            max_file_bytes: 1_000,
+            max_request_bytes: 2_000,
+            max_parts: 3,
+            max_field_bytes: 10,
+        }
+    }
+
+    /// `(name, filename, data)` parts.
+    fn multipart(parts: &[(&str, Option<&str>, &[u8])]) -> Vec<u8> {
+        let mut body = vec![];
+        for (name, filename, data) in parts {
+            let filename = filename
+                .map(|filename| format!("; filename=\"{filename}\""))
+                .unwrap_or_default();
+            body.extend(
+                format!(
+                "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"{name}\"{filename}\r\n\r\n"
+            )
+                .bytes(),
+            );
+            body.extend(*data);
+            body.extend(b"\r\n");
+        }
+        body.extend(format!("--{BOUNDARY}--\r\n").bytes());
+        body
+    }
+
+    fn read_form(body: Vec<u8>, content_length: bool) -> Result<Form, FormError> {
+        let mut headers = vec![(
+            "Content-Type".to_string(),
+            format!("multipart/form-data; boundary={BOUNDARY}"),
+        )];
+        if content_length {
+            headers.push(("Content-Length".to_string(), body.len().to_string()));
+        }
+        let req = rouille::Request::fake_http("POST", "/upload", headers, body);
+        read(&req, &limits())
+    }
+
+    #[test]
+    fn reads_files_and_fields() {
+        let body = multipart(&[("files", Some("a.png"), &[1; 1_000]), ("w", None, b"64")]);
+        let form = read_form(body, true).unwrap();
+        assert_eq!(form.files.len(), 1);
+        assert_eq!(form.files[0].filename.as_deref(), Some("a.png"));
+        assert_eq!(form.files[0].data.len(), 1_000);
+        assert_eq!(form.field("w").as_deref(), Some("64"));
+    }
+
+    #[test]
+    fn aborts_at_each_limit() {
+        let body = multipart(&[("files", Some("big.png"), &[1; 1_001])]);
+        assert!(matches!(
+            read_form(body, true),
+            Err(FormError::FileTooLarge { filename: Some(name), limit: 1_000 }) if name == "big.png"
+        ));
+
+        let body = multipart(&[("w", None, b"12345678901")]);
+        assert!(matches!(
+            read_form(body, true),
+            Err(FormError::FieldTooLarge { name, .. }) if name == "w"
+        ));
+
+        //? every file is within its limit, together they are not
+        let three = multipart(&[
+            ("files", Some("a.png"), &[1; 900]),
+            ("files", Some("b.png"), &[1; 900]),
+            ("files", Some("c.png"), &[1; 900]),
+        ]);
+        assert!(matches!(
+            read_form(three.clone(), true),
+            Err(FormError::RequestTooLarge { limit: 2_000 })
+        ));
+        assert!(matches!(
+            read_form(three, false),
+            Err(FormError::RequestTooLarge { limit: 2_000 })
+        ));
+
+        let body = multipart(&[
+            ("a", None, b"1"),
+            ("b", None, b"2"),
+            ("c", None, b"3"),
+            ("d", None, b"4"),
+        ]);
+        assert!(matches!(
+            read_form(body, true),
+            Err(FormError::TooManyParts { limit: 3 })
+        ));
+    }
+
+    /// counts what the form reader pulled from the client.
+    struct Counted<R> {
+        inner: R,
+        read: Rc<Cell<u64>>,
+    }
+
+    impl<R: Read> Read for Counted<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.inner.read(buf)?;
+            self.read.set(self.read.get() + n as u64);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn an_endless_body_is_cut_off() {
+        let limits = Limits {
+            max_file_bytes: u64::MAX,
+            ..limits()
+        };
+        let head = format!(
+            "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"files\"; filename=\"a.png\"\r\n\r\n"
+        );
+        let read = Rc::new(Cell::new(0));
+        let body = Counted {
+            inner: io::Cursor::new(head).chain(io::repeat(1)),
+            read: read.clone(),
+        };
+        assert!(matches!(
+            read_body(body, BOUNDARY.to_string(), &limits),
+            Err(FormError::RequestTooLarge { limit: 2_000 })
+        ));
+        //? no further than the multipart reader's buffer past the limit
+        assert!(read.get() < 2_000 + 64 * 1_024, "read {}", read.get());
+    }
+}
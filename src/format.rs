@@ -0,0 +1,168 @@
+use std::{fmt, io::Cursor};
+
+use image::{DynamicImage, ImageOutputFormat};
+
+pub const DEFAULT_QUALITY: u8 = 80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Png,
+    Jpeg,
+    WebP,
+    Gif,
+    Bmp,
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    Unknown(String),
+    BadQuality(String),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Unknown(format) => write!(
+                f,
+                "unknown format {format:?}, expected one of: png, jpeg, webp, gif, bmp"
+            ),
+            FormatError::BadQuality(q) => {
+                write!(f, "`q` must be a whole number between 1 and 100, got {q:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+impl OutputFormat {
+    pub fn parse(value: &str) -> Result<Self, FormatError> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "png" => Ok(OutputFormat::Png),
+            "jpg" | "jpeg" => Ok(OutputFormat::Jpeg),
+            "webp" => Ok(OutputFormat::WebP),
+            "gif" => Ok(OutputFormat::Gif),
+            "bmp" => Ok(OutputFormat::Bmp),
+            _ => Err(FormatError::Unknown(value.to_string())),
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Gif => "gif",
+            OutputFormat::Bmp => "bmp",
+        }
+    }
+
+    pub fn mime(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Gif => "image/gif",
+            OutputFormat::Bmp => "image/bmp",
+        }
+    }
+
+    /// only JPEG is lossy here, the WebP encoder we ship is lossless.
+    pub fn uses_quality(&self) -> bool {
+        matches!(self, OutputFormat::Jpeg)
+    }
+
+    pub fn encode(&self, img: &DynamicImage, quality: u8) -> image::ImageResult<Vec<u8>> {
+        let mut buf = Cursor::new(Vec::new());
+        match self {
+            //? JPEG has no alpha channel, let the encoder see plain RGB
+            OutputFormat::Jpeg => DynamicImage::ImageRgb8(img.to_rgb8())
+                .write_to(&mut buf, ImageOutputFormat::Jpeg(quality))?,
+            OutputFormat::Png => img.write_to(&mut buf, ImageOutputFormat::Png)?,
+            OutputFormat::WebP => DynamicImage::ImageRgba8(img.to_rgba8())
+                .write_to(&mut buf, ImageOutputFormat::WebP)?,
+            OutputFormat::Gif => img.write_to(&mut buf, ImageOutputFormat::Gif)?,
+            OutputFormat::Bmp => DynamicImage::ImageRgba8(img.to_rgba8())
+                .write_to(&mut buf, ImageOutputFormat::Bmp)?,
+        }
+        Ok(buf.into_inner())
+    }
+}
+
+/// formats we offer when the client did not ask for one, best first.
+const NEGOTIABLE: [OutputFormat; 5] = [
+    OutputFormat::WebP,
+    OutputFormat::Png,
+    OutputFormat::Jpeg,
+    OutputFormat::Gif,
+    OutputFormat::Bmp,
+];
+
+/// picks a format from an `Accept` header, honouring `q=` weights.
+/// old clients that only send `*/*` (or nothing) keep getting PNG,
+/// WebP is only handed out when it is listed explicitly.
+pub fn negotiate(accept: Option<&str>) -> OutputFormat {
+    let Some(accept) = accept else {
+        return OutputFormat::Png;
+    };
+
+    let mut best: Option<(OutputFormat, f32)> = None;
+    for range in accept.split(',') {
+        let mut parts = range.split(';');
+        let mime = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+        let weight = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if weight <= 0.0 {
+            continue;
+        }
+        let candidate = match mime.as_str() {
+            "image/*" | "*/*" => Some(OutputFormat::Png),
+            _ => NEGOTIABLE.into_iter().find(|f| f.mime() == mime),
+        };
+        let Some(candidate) = candidate else {
+            continue;
+        };
+        let rank = |f: OutputFormat| NEGOTIABLE.iter().position(|n| *n == f);
+        best = match best {
+            Some((current, w))
+                if w > weight || (w == weight && rank(current) <= rank(candidate)) =>
+            {
+                Some((current, w))
+            }
+            _ => Some((candidate, weight)),
+        };
+    }
+    best.map(|(format, _)| format).unwrap_or(OutputFormat::Png)
+}
+
+pub fn parse_quality(value: &str) -> Result<u8, FormatError> {
+    match value.trim().parse::<u8>() {
+        Ok(q) if (1..=100).contains(&q) => Ok(q),
+        _ => Err(FormatError::BadQuality(value.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn negotiates_accept_header() {
+        assert_eq!(negotiate(None), OutputFormat::Png);
+        assert_eq!(negotiate(Some("*/*")), OutputFormat::Png);
+        assert_eq!(
+            negotiate(Some("image/avif,image/webp,image/apng,image/*,*/*;q=0.8")),
+            OutputFormat::WebP
+        );
+        assert_eq!(
+            negotiate(Some("image/webp;q=0.5, image/jpeg")),
+            OutputFormat::Jpeg
+        );
+        assert_eq!(
+            negotiate(Some("image/webp;q=0, text/html")),
+            OutputFormat::Png
+        );
+    }
+}
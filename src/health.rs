@@ -0,0 +1,76 @@
+use std::{fs, io, path::Path};
+
+use serde::Serialize;
+
+use crate::{jobs, similar, storage::Storage, store, tus};
+
+/// written and removed again by every readiness check, never listed as an image.
+const PROBE: &str = ".ready";
+
+/// one thing `/readyz` checked.
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+
+fn check(name: &str, result: io::Result<()>) -> Check {
+    Check {
+        name: name.to_string(),
+        ok: result.is_ok(),
+        error: result.err().map(|err| err.to_string()),
+    }
+}
+
+fn probe_storage(storage: &dyn Storage, dir: &str) -> io::Result<()> {
+    let key = format!("{dir}/{PROBE}");
+    storage.put(&key, b"ok")?;
+    storage.delete(&key)?;
+    Ok(())
+}
+
+/// jobs, partial uploads and the index are always on the local disk, whatever the storage.
+fn probe_local(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let path = dir.join(PROBE);
+    fs::write(&path, b"ok")?;
+    fs::remove_file(&path)
+}
+
+/// whether every place the server writes to takes a write right now.
+pub fn readiness(storage: &dyn Storage, data_dir: &Path) -> Readiness {
+    let index_dir = Path::new(similar::INDEX_FILE)
+        .parent()
+        .unwrap_or(Path::new(""));
+    let checks = vec![
+        check(
+            store::ORIGINALS_DIR,
+            probe_storage(storage, store::ORIGINALS_DIR),
+        ),
+        check(
+            store::VARIANTS_DIR,
+            probe_storage(storage, store::VARIANTS_DIR),
+        ),
+        check(jobs::JOBS_DIR, probe_local(&data_dir.join(jobs::JOBS_DIR))),
+        check(
+            tus::UPLOADS_DIR,
+            probe_local(&data_dir.join(tus::UPLOADS_DIR)),
+        ),
+        check(
+            &index_dir.to_string_lossy(),
+            probe_local(&data_dir.join(index_dir)),
+        ),
+    ];
+    Readiness {
+        ready: checks.iter().all(|check| check.ok),
+        checks,
+    }
+}
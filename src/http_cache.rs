@@ -0,0 +1,226 @@
+use std::{
+    borrow::Cow,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::store;
+
+/// variants of a content-addressed id never change, a year is the conventional "forever".
+pub const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+/// presets can be redefined in `presets.toml`, so clients revalidate now and then.
+pub const REVALIDATE: &str = "public, max-age=3600";
+/// [`IMMUTABLE`] for a response only its caller may see, shared caches keep out.
+pub const PRIVATE_IMMUTABLE: &str = "private, max-age=31536000, immutable";
+/// [`REVALIDATE`] for a response only its caller may see.
+pub const PRIVATE_REVALIDATE: &str = "private, max-age=3600";
+
+/// what a response is validated against.
+#[derive(Debug, Clone)]
+pub struct Validators {
+    /// strong, quoted: `"…"`.
+    pub etag: String,
+    pub last_modified: Option<SystemTime>,
+}
+
+/// the SHA-256 of the exact bytes served, so it is a strong validator.
+pub fn etag(bytes: &[u8]) -> String {
+    format!("\"{}\"", &store::content_id(bytes)[..32])
+}
+
+impl Validators {
+    /// `etag` as made by [`etag`], `last_modified` in seconds since the epoch.
+    pub fn new(etag: String, last_modified: Option<u64>) -> Self {
+        Self {
+            etag,
+            last_modified: last_modified.map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    /// `If-None-Match` wins over `If-Modified-Since` when both are sent.
+    fn not_modified(&self, if_none_match: Option<&str>, if_modified_since: Option<&str>) -> bool {
+        if let Some(header) = if_none_match {
+            return etag_matches(header, &self.etag);
+        }
+        match (
+            if_modified_since.map(httpdate::parse_http_date),
+            self.last_modified,
+        ) {
+            (Some(Ok(since)), Some(modified)) => modified <= since,
+            _ => false,
+        }
+    }
+
+    /// `If-Range` only lets the range through when it names what we would send.
+    fn range_still_valid(&self, if_range: Option<&str>) -> bool {
+        match if_range.map(str::trim) {
+            None => true,
+            Some(value) if value.starts_with('"') => value == self.etag,
+            Some(value) => match (httpdate::parse_http_date(value), self.last_modified) {
+                (Ok(date), Some(modified)) => modified <= date,
+                _ => false,
+            },
+        }
+    }
+}
+
+/// weak comparison, as RFC 9110 asks for `If-None-Match`.
+fn etag_matches(header: &str, etag: &str) -> bool {
+    let etag = etag.trim_start_matches("W/");
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|candidate| candidate == "*" || candidate.trim_start_matches("W/") == etag)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Range {
+    /// inclusive byte offsets.
+    Satisfiable(usize, usize),
+    Unsatisfiable,
+    /// malformed or multi-range, answered with the whole body.
+    Ignored,
+}
+
+fn parse_range(header: &str, len: usize) -> Range {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Range::Ignored;
+    };
+    if spec.contains(',') {
+        return Range::Ignored;
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Range::Ignored;
+    };
+    let (start, end) = match (start.trim(), end.trim()) {
+        //? `bytes=-500`: the last 500 bytes
+        ("", suffix) => match suffix.parse::<usize>() {
+            Ok(0) => return Range::Unsatisfiable,
+            Ok(n) => (len.saturating_sub(n), len.saturating_sub(1)),
+            Err(_) => return Range::Ignored,
+        },
+        (start, "") => match start.parse::<usize>() {
+            Ok(start) => (start, len.saturating_sub(1)),
+            Err(_) => return Range::Ignored,
+        },
+        (start, end) => match (start.parse::<usize>(), end.parse::<usize>()) {
+            (Ok(start), Ok(end)) if start <= end => (start, end.min(len.saturating_sub(1))),
+            _ => return Range::Ignored,
+        },
+    };
+    if len == 0 || start >= len {
+        return Range::Unsatisfiable;
+    }
+    Range::Satisfiable(start, end)
+}
+
+fn validator_headers(
+    validators: &Validators,
+    cache_control: &'static str,
+) -> Vec<(Cow<'static, str>, Cow<'static, str>)> {
+    let mut headers = vec![
+        ("ETag".into(), validators.etag.clone().into()),
+        ("Cache-Control".into(), cache_control.into()),
+    ];
+    if let Some(modified) = validators.last_modified {
+        headers.push((
+            "Last-Modified".into(),
+            httpdate::fmt_http_date(modified).into(),
+        ));
+    }
+    headers
+}
+
+/// the 304 for a client whose copy is current, before the body is even read.
+pub fn not_modified(
+    req: &rouille::Request,
+    validators: &Validators,
+    cache_control: &'static str,
+) -> Option<rouille::Response> {
+    if !validators.not_modified(req.header("If-None-Match"), req.header("If-Modified-Since")) {
+        return None;
+    }
+    let mut response = rouille::Response::empty_204().with_status_code(304);
+    response
+        .headers
+        .extend(validator_headers(validators, cache_control));
+    Some(response)
+}
+
+/// answers `bytes` with its validators, a 304 when the client copy is current
+/// and a 206 when a single byte range is asked for.
+pub fn respond(
+    req: &rouille::Request,
+    mime: &'static str,
+    bytes: Vec<u8>,
+    validators: &Validators,
+    cache_control: &'static str,
+) -> rouille::Response {
+    if let Some(response) = not_modified(req, validators, cache_control) {
+        return response;
+    }
+
+    let mut headers = validator_headers(validators, cache_control);
+    headers.push(("Accept-Ranges".into(), "bytes".into()));
+    let len = bytes.len();
+    let range = match req.header("Range") {
+        Some(range) if validators.range_still_valid(req.header("If-Range")) => {
+            parse_range(range, len)
+        }
+        _ => Range::Ignored,
+    };
+    let mut response = match range {
+        Range::Ignored => rouille::Response::from_data(mime, bytes),
+        Range::Satisfiable(start, end) => {
+            headers.push((
+                "Content-Range".into(),
+                format!("bytes {start}-{end}/{len}").into(),
+            ));
+            rouille::Response::from_data(mime, bytes[start..=end].to_vec()).with_status_code(206)
+        }
+        Range::Unsatisfiable => {
+            headers.push(("Content-Range".into(), format!("bytes */{len}").into()));
+            rouille::Response::text("range not satisfiable").with_status_code(416)
+        }
+    };
+    response.headers.extend(headers);
+    response
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_single_byte_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Range::Satisfiable(0, 99));
+        assert_eq!(
+            parse_range("bytes=900-", 1000),
+            Range::Satisfiable(900, 999)
+        );
+        assert_eq!(
+            parse_range("bytes=-100", 1000),
+            Range::Satisfiable(900, 999)
+        );
+        assert_eq!(
+            parse_range("bytes=500-5000", 1000),
+            Range::Satisfiable(500, 999)
+        );
+        assert_eq!(parse_range("bytes=1000-", 1000), Range::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Range::Ignored);
+        assert_eq!(parse_range("items=0-1", 1000), Range::Ignored);
+        assert_eq!(parse_range("bytes=9-1", 1000), Range::Ignored);
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let validators = Validators::new(etag(b"pixels"), Some(1_700_000_000));
+        let etag = validators.etag.clone();
+        assert!(validators.not_modified(Some(&etag), None));
+        assert!(validators.not_modified(Some(&format!("\"x\", W/{etag}")), None));
+        assert!(!validators.not_modified(Some("\"x\""), Some("Tue, 14 Nov 2023 22:13:20 GMT")));
+        assert!(validators.not_modified(None, Some("Tue, 14 Nov 2023 22:13:20 GMT")));
+        assert!(!validators.not_modified(None, Some("Tue, 14 Nov 2023 22:13:19 GMT")));
+        assert!(validators.range_still_valid(Some(&etag)));
+        assert!(!validators.range_still_valid(Some("\"stale\"")));
+    }
+}
@@ -0,0 +1,444 @@
+use std::{
+    fmt, fs, io,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::record;
+use crate::similar;
+use crate::storage::Storage;
+use crate::store;
+use crate::throttle::Admission;
+use crate::variant::{self, Variant, VariantError};
+
+pub const JOBS_DIR: &str = "jobs";
+/// jobs waiting for a worker, past this uploads are stored without one instead of piling up.
+pub const QUEUE_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pending,
+    Done,
+    Failed,
+}
+
+/// one output to render, `preset` is `None` for the thumbnail asked for in the form.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Task {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    pub variant: Variant,
+}
+
+impl Task {
+    pub fn url(&self, id: &str) -> String {
+        match &self.preset {
+            Some(name) => format!("/img/{id}/{name}"),
+            None => self.variant.url(id),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredVariant {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    pub key: String,
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub bytes: usize,
+    pub url: String,
+}
+
+/// the resize work for one upload, kept as `jobs/{id}.json` from the moment it is queued,
+/// so whatever is still pending when the server stops is picked up again on the next start.
+/// jobs always sit on the local disk, whichever storage holds the images.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub image_id: String,
+    pub status: Status,
+    pub created_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<u64>,
+    pub tasks: Vec<Task>,
+    pub outputs: Vec<StoredVariant>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// what an upload answers with, poll `url` until the job is done.
+#[derive(Debug, Serialize)]
+pub struct JobRef {
+    pub id: String,
+    pub status: Status,
+    pub url: String,
+}
+
+#[derive(Debug)]
+pub enum JobError {
+    QueueFull,
+    Io(io::Error),
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            JobError::QueueFull => {
+                write!(f, "too many images are waiting to be resized, retry later")
+            }
+            JobError::Io(err) => write!(f, "cannot persist job: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for JobError {}
+
+impl JobError {
+    /// stable, machine friendly name for the json api.
+    pub fn code(&self) -> &'static str {
+        match self {
+            JobError::QueueFull => "queue_full",
+            JobError::Io(_) => "storage_error",
+        }
+    }
+}
+
+fn new_id(image_id: &str) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let seed = format!(
+        "{image_id}-{nanos}-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    store::content_id(seed.as_bytes())[..32].to_string()
+}
+
+pub fn is_job_id(id: &str) -> bool {
+    id.len() == 32 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.json"))
+}
+
+/// `dir` is where the queue keeps its jobs, see [`JOBS_DIR`].
+pub fn load(dir: &Path, id: &str) -> Option<Job> {
+    let bytes = fs::read(path(dir, id)).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+fn save(dir: &Path, job: &Job) -> io::Result<()> {
+    let bytes = serde_json::to_vec_pretty(job)?;
+    store::write_atomically(&path(dir, &job.id), &bytes)
+}
+
+/// removes done and failed jobs that finished more than `max_age` seconds ago,
+/// returns how many went. pending ones are never touched.
+pub fn prune_finished(dir: &Path, max_age: u64) -> io::Result<usize> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let now = record::now();
+    let mut pruned = 0;
+    for entry in entries {
+        let path = entry?.path();
+        let Some(job) = fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<Job>(&bytes).ok())
+        else {
+            continue;
+        };
+        if job
+            .finished_at
+            .is_some_and(|finished| now.saturating_sub(finished) > max_age)
+        {
+            fs::remove_file(&path)?;
+            pruned += 1;
+        }
+    }
+    Ok(pruned)
+}
+
+/// dimensions of a variant we just rendered, without decoding the pixels.
+fn dimensions(bytes: &[u8]) -> image::ImageResult<(u32, u32)> {
+    image::io::Reader::new(Cursor::new(bytes))
+        .with_guessed_format()?
+        .into_dimensions()
+}
+
+fn render(
+    storage: &dyn Storage,
+    image_id: &str,
+    task: &Task,
+) -> Result<StoredVariant, VariantError> {
+    let rendered = variant::get_or_render(storage, image_id, &task.variant, Admission::Wait)?;
+    let (width, height) = dimensions(&rendered)?;
+    Ok(StoredVariant {
+        preset: task.preset.clone(),
+        key: task.variant.key(),
+        content_type: task.variant.format.mime().to_string(),
+        width,
+        height,
+        bytes: rendered.len(),
+        url: task.url(image_id),
+    })
+}
+
+fn run(storage: &dyn Storage, index: &similar::Index, dir: &Path, id: &str) {
+    let Some(mut job) = load(dir, id) else {
+        println!(">> job {id} vanished before it ran");
+        return;
+    };
+    if job.status != Status::Pending {
+        return;
+    }
+    let outputs: Result<Vec<_>, _> = job
+        .tasks
+        .iter()
+        .map(|task| render(storage, &job.image_id, task))
+        .collect();
+    match outputs {
+        Ok(outputs) => {
+            job.status = Status::Done;
+            job.outputs = outputs;
+        }
+        Err(err) => {
+            println!(">> job {id} failed: {err}");
+            job.status = Status::Failed;
+            job.error = Some(err.to_string());
+        }
+    }
+    //? hashing and placeholders need a full decode, so they run here and not on the upload request
+    if let Err(err) = index.ensure(storage, &job.image_id, Admission::Wait) {
+        println!(">> job {id}: cannot hash {}: {err}", job.image_id);
+    }
+    job.finished_at = Some(record::now());
+    if let Err(err) = save(dir, &job) {
+        println!(">> job {id}: cannot save result: {err}");
+    }
+}
+
+/// bounded queue in front of a fixed pool of resize workers.
+#[derive(Clone)]
+pub struct Queue {
+    sender: SyncSender<String>,
+    dir: PathBuf,
+    /// sent and not yet picked up by a worker.
+    queued: Arc<AtomicUsize>,
+}
+
+impl Queue {
+    /// the jobs are kept in `dir`, see [`JOBS_DIR`].
+    pub fn start(
+        workers: usize,
+        dir: PathBuf,
+        storage: Arc<dyn Storage>,
+        index: Arc<similar::Index>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<String>(QUEUE_CAPACITY);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+        for n in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            let queued = queued.clone();
+            let storage = storage.clone();
+            let index = index.clone();
+            let dir = dir.clone();
+            thread::Builder::new()
+                .name(format!("resize-{n}"))
+                .spawn(move || loop {
+                    //? the lock is only held while waiting, never while rendering
+                    let next = receiver.lock().unwrap().recv();
+                    match next {
+                        Ok(id) => {
+                            queued.fetch_sub(1, Ordering::Relaxed);
+                            run(storage.as_ref(), &index, &dir, &id)
+                        }
+                        Err(_) => return,
+                    }
+                })
+                .expect("spawn resize worker");
+        }
+        Self {
+            sender,
+            dir,
+            queued,
+        }
+    }
+
+    /// a queue no worker reads from, `receiver` sees what was sent.
+    #[cfg(test)]
+    pub fn paused(dir: PathBuf, capacity: usize) -> (Self, mpsc::Receiver<String>) {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let queue = Self {
+            sender,
+            dir,
+            queued: Arc::new(AtomicUsize::new(0)),
+        };
+        (queue, receiver)
+    }
+
+    pub fn job(&self, id: &str) -> Option<Job> {
+        load(&self.dir, id)
+    }
+
+    /// jobs waiting for a worker.
+    pub fn depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// persists the job and hands it to the workers.
+    pub fn submit(&self, image_id: &str, tasks: Vec<Task>) -> Result<JobRef, JobError> {
+        let job = Job {
+            id: new_id(image_id),
+            image_id: image_id.to_string(),
+            status: Status::Pending,
+            created_at: record::now(),
+            finished_at: None,
+            tasks,
+            outputs: vec![],
+            error: None,
+        };
+        save(&self.dir, &job).map_err(JobError::Io)?;
+        //? counted before the send, a worker may pick it up before `try_send` returns
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        match self.sender.try_send(job.id.clone()) {
+            Ok(()) => Ok(JobRef {
+                url: format!("/api/jobs/{}", job.id),
+                id: job.id,
+                status: Status::Pending,
+            }),
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => {
+                self.queued.fetch_sub(1, Ordering::Relaxed);
+                let _ = fs::remove_file(path(&self.dir, &job.id));
+                Err(JobError::QueueFull)
+            }
+        }
+    }
+
+    /// re-queues the jobs a previous run left pending, oldest first.
+    pub fn resume(&self) -> io::Result<usize> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let mut pending = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(job) = fs::read(&path)
+                .ok()
+                .and_then(|bytes| serde_json::from_slice::<Job>(&bytes).ok())
+            else {
+                continue;
+            };
+            if job.status == Status::Pending {
+                pending.push((job.created_at, job.id));
+            }
+        }
+        pending.sort();
+
+        let count = pending.len();
+        let sender = self.sender.clone();
+        let queued = self.queued.clone();
+        //? blocking sends, the backlog may be bigger than the queue
+        thread::spawn(move || {
+            for (_, id) in pending {
+                queued.fetch_add(1, Ordering::Relaxed);
+                if sender.send(id).is_err() {
+                    return;
+                }
+            }
+        });
+        Ok(count)
+    }
+}
+
+/// resize workers to start, leaves a core for the request threads.
+pub fn default_workers() -> usize {
+    thread::available_parallelism()
+        .map(|n| n.get().saturating_sub(1))
+        .unwrap_or(1)
+        .clamp(1, 4)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn pending_jobs_survive_a_restart() {
+        let scratch = std::env::temp_dir().join(format!("jobs-resume-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&scratch);
+        let dir = scratch.join(JOBS_DIR);
+        let storage = Arc::new(MemoryStorage::default());
+        let mut png = Cursor::new(vec![]);
+        image::DynamicImage::new_rgb8(16, 16)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let image_id = store::content_id(png.get_ref());
+        storage
+            .put(&store::original_key(&image_id), png.get_ref())
+            .unwrap();
+        let variant = Variant::from_params(
+            |name| matches!(name, "w" | "h").then(|| "8".to_string()),
+            Some("png"),
+            None,
+        )
+        .unwrap();
+        let task = Task {
+            preset: None,
+            variant,
+        };
+
+        let (queue, receiver) = Queue::paused(dir.clone(), 1);
+        let first = queue.submit(&image_id, vec![task.clone()]).unwrap();
+        assert!(matches!(
+            queue.submit(&image_id, vec![task]),
+            Err(JobError::QueueFull)
+        ));
+        //? only the queued job was kept
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(receiver.try_recv().unwrap(), first.id);
+        assert_eq!(load(&dir, &first.id).unwrap().status, Status::Pending);
+        //? the server stops before a worker got to it
+        drop((queue, receiver));
+
+        let index =
+            similar::Index::open(storage.as_ref(), scratch.join(similar::INDEX_FILE)).unwrap();
+        let queue = Queue::start(1, dir.clone(), storage, Arc::new(index));
+        assert_eq!(queue.resume().unwrap(), 1);
+        for _ in 0..1_000 {
+            if queue.job(&first.id).unwrap().status != Status::Pending {
+                break;
+            }
+            thread::sleep(std::time::Duration::from_millis(10));
+        }
+        let job = queue.job(&first.id).unwrap();
+        assert_eq!(job.status, Status::Done, "{:?}", job.error);
+        assert_eq!((job.outputs[0].width, job.outputs[0].height), (8, 8));
+        assert!(job.finished_at.is_some());
+        let _ = fs::remove_dir_all(scratch);
+    }
+}
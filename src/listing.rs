@@ -0,0 +1,367 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    io::{self, Cursor, Read},
+    time::UNIX_EPOCH,
+};
+
+use serde::Serialize;
+
+use crate::placeholder::Placeholder;
+use crate::record::{self, Record};
+use crate::storage::{ObjectMeta, Storage};
+use crate::store;
+use crate::validate;
+
+pub const DEFAULT_LIMIT: usize = 20;
+pub const MAX_LIMIT: usize = 100;
+/// enough of the original to find its dimensions, even behind a big EXIF block.
+const HEADER_BYTES: u64 = 256 * 1_024;
+
+/// one entry of `GET /api/images`, cheap to build: only the image header is read.
+#[derive(Debug, Serialize)]
+pub struct ListedImage {
+    pub id: String,
+    pub uploaded_at: u64,
+    pub format: Option<&'static str>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub bytes: u64,
+    pub url: String,
+    pub metadata_url: String,
+    /// what to show until `url` loads, absent until the upload's job has run.
+    pub placeholder: Option<Placeholder>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Page {
+    pub images: Vec<ListedImage>,
+    /// pass it back as `cursor` for the next page, absent on the last one.
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ListError {
+    BadCursor(String),
+    BadLimit(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for ListError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListError::BadCursor(cursor) => write!(f, "invalid cursor {cursor:?}"),
+            ListError::BadLimit(limit) => write!(
+                f,
+                "`limit` must be a whole number between 1 and {MAX_LIMIT}, got {limit:?}"
+            ),
+            ListError::Io(err) => write!(f, "storage error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ListError {}
+
+impl ListError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ListError::BadCursor(_) | ListError::BadLimit(_) => 400,
+            ListError::Io(_) => 500,
+        }
+    }
+}
+
+pub fn parse_limit(value: Option<&str>) -> Result<usize, ListError> {
+    match value.map(str::trim).filter(|v| !v.is_empty()) {
+        None => Ok(DEFAULT_LIMIT),
+        Some(value) => match value.parse::<usize>() {
+            Ok(limit) if (1..=MAX_LIMIT).contains(&limit) => Ok(limit),
+            _ => Err(ListError::BadLimit(value.to_string())),
+        },
+    }
+}
+
+/// the listing is kept as empty objects under here, one per image in `all/` and one
+/// per image and owner in `owners/`, named `{uploaded_at}.{id}` like the cursor, so a
+/// page is read off the keys without loading any record.
+pub const INDEX_DIR: &str = "listing";
+
+fn all_prefix() -> String {
+    format!("{INDEX_DIR}/all/")
+}
+
+/// owners are tokens or user names, they go into keys hashed.
+fn owner_prefix(owner: &str) -> String {
+    format!(
+        "{INDEX_DIR}/owners/{}/",
+        store::content_id(owner.as_bytes())
+    )
+}
+
+fn entry_keys(id: &str, record: &Record) -> Vec<String> {
+    let name = cursor(record.uploaded_at, id);
+    std::iter::once(all_prefix())
+        .chain(record.owners.iter().map(|owner| owner_prefix(owner)))
+        .map(|prefix| format!("{prefix}{name}"))
+        .collect()
+}
+
+/// brings the entries of `id` in line with its record going from `before` to `after`,
+/// `None` for a record that is created or deleted.
+pub fn reindex(
+    storage: &dyn Storage,
+    id: &str,
+    before: Option<&Record>,
+    after: Option<&Record>,
+) -> io::Result<()> {
+    let old = before.map_or_else(Vec::new, |record| entry_keys(id, record));
+    let new = after.map_or_else(Vec::new, |record| entry_keys(id, record));
+    for key in new.iter().filter(|key| !old.contains(key)) {
+        storage.put(key, b"")?;
+    }
+    for key in old.iter().filter(|key| !new.contains(key)) {
+        storage.delete(key)?;
+    }
+    Ok(())
+}
+
+/// adds the originals the listing does not know, stored before it was kept.
+/// returns how many, run once at startup.
+pub fn backfill(storage: &dyn Storage) -> io::Result<usize> {
+    let listed: HashSet<String> = storage
+        .list(&all_prefix())?
+        .into_iter()
+        .filter_map(|object| entry(&object.key[all_prefix().len()..]))
+        .map(|(_, id)| id)
+        .collect();
+    let mut added = 0;
+    for object in storage.list(&format!("{}/", store::ORIGINALS_DIR))? {
+        let Some(id) = object.key.rsplit('/').next() else {
+            continue;
+        };
+        if !store::is_content_id(id) || object.key != store::original_key(id) || listed.contains(id)
+        {
+            continue;
+        }
+        match record::load(storage, id) {
+            Some(record) => reindex(storage, id, None, Some(&record))?,
+            //? no record, no owners, and the file time is all we know of the upload
+            None => storage.put(
+                &format!("{}{}", all_prefix(), cursor(written(&object), id)),
+                b"",
+            )?,
+        }
+        added += 1;
+    }
+    Ok(added)
+}
+
+/// `{uploaded_at}.{id}` of the last entry handed out.
+fn cursor(uploaded_at: u64, id: &str) -> String {
+    format!("{uploaded_at}.{id}")
+}
+
+/// the `(uploaded_at, id)` an index entry is named after.
+fn entry(name: &str) -> Option<(u64, String)> {
+    parse_cursor(name).ok()
+}
+
+fn parse_cursor(value: &str) -> Result<(u64, String), ListError> {
+    let bad = || ListError::BadCursor(value.to_string());
+    let (uploaded_at, id) = value.split_once('.').ok_or_else(bad)?;
+    let uploaded_at = uploaded_at.parse().map_err(|_| bad())?;
+    if !store::is_content_id(id) {
+        return Err(bad());
+    }
+    Ok((uploaded_at, id.to_string()))
+}
+
+/// newest first, ties broken by id so pages never overlap or skip.
+fn order(a: &(u64, String), b: &(u64, String)) -> std::cmp::Ordering {
+    b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1))
+}
+
+fn written(object: &ObjectMeta) -> u64 {
+    object
+        .modified
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn uploaded_at(storage: &dyn Storage, id: &str, original: &ObjectMeta) -> u64 {
+    //? originals stored before records existed fall back to the file time
+    record::load(storage, id).map_or_else(|| written(original), |r| r.uploaded_at)
+}
+
+fn describe(storage: &dyn Storage, id: String, uploaded_at: u64) -> io::Result<ListedImage> {
+    let key = store::original_key(&id);
+    let bytes = storage
+        .stat(&key)?
+        .map(|object| object.size)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, key.clone()))?;
+    let mut header = vec![];
+    storage
+        .get(&key)?
+        .take(HEADER_BYTES)
+        .read_to_end(&mut header)?;
+    let reader = image::io::Reader::new(Cursor::new(header)).with_guessed_format()?;
+    let format = reader.format();
+    let dimensions = reader.into_dimensions().ok();
+    Ok(ListedImage {
+        format: format.map(validate::format_name),
+        width: dimensions.map(|(w, _)| w),
+        height: dimensions.map(|(_, h)| h),
+        bytes,
+        url: format!("/img/{id}"),
+        metadata_url: format!("/api/images/{id}"),
+        placeholder: record::load(storage, &id).and_then(|r| r.placeholder),
+        uploaded_at,
+        id,
+    })
+}
+
+/// `(uploaded_at, id)` of every stored original, unsorted.
+/// reads every record, fine for the sweep but not per request.
+pub fn uploads(storage: &dyn Storage) -> io::Result<Vec<(u64, String)>> {
+    let mut keys = vec![];
+    for object in storage.list(&format!("{}/", store::ORIGINALS_DIR))? {
+        //? skips the `.json` records
+        let Some(id) = object.key.rsplit('/').next() else {
+            continue;
+        };
+        if store::is_content_id(id) && object.key == store::original_key(id) {
+            keys.push((uploaded_at(storage, id, &object), id.to_string()));
+        }
+    }
+    Ok(keys)
+}
+
+/// one page of stored originals, sorted by upload time, newest first.
+/// with `owner` only the images that owner uploaded, from that owner's own entries.
+pub fn list(
+    storage: &dyn Storage,
+    after: Option<&str>,
+    limit: usize,
+    owner: Option<&str>,
+) -> Result<Page, ListError> {
+    let after = after
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(parse_cursor)
+        .transpose()?;
+
+    let prefix = owner.map_or_else(all_prefix, owner_prefix);
+    let mut keys: Vec<_> = storage
+        .list(&prefix)
+        .map_err(ListError::Io)?
+        .into_iter()
+        .filter_map(|object| entry(&object.key[prefix.len()..]))
+        .collect();
+    keys.sort_by(order);
+
+    let start = match &after {
+        Some(after) => keys.partition_point(|key| order(key, after).is_le()),
+        None => 0,
+    };
+    let page: Vec<_> = keys.into_iter().skip(start).take(limit + 1).collect();
+    let next_cursor = (page.len() > limit)
+        .then(|| page.get(limit - 1))
+        .flatten()
+        .map(|(uploaded_at, id)| cursor(*uploaded_at, id));
+
+    let mut images = vec![];
+    for (uploaded_at, id) in page.into_iter().take(limit) {
+        match describe(storage, id, uploaded_at) {
+            Ok(image) => images.push(image),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(ListError::Io(err)),
+        }
+    }
+    Ok(Page {
+        images,
+        next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cursors_and_ordering() {
+        let id = "a".repeat(64);
+        assert_eq!(parse_cursor(&cursor(42, &id)).unwrap(), (42, id.clone()));
+        assert!(parse_cursor("42").is_err());
+        assert!(parse_cursor("x.y").is_err());
+
+        let mut keys = vec![
+            (1, "b".to_string()),
+            (3, "a".to_string()),
+            (1, "a".to_string()),
+        ];
+        keys.sort_by(order);
+        assert_eq!(
+            keys,
+            [
+                (3, "a".to_string()),
+                (1, "a".to_string()),
+                (1, "b".to_string())
+            ]
+        );
+        let after = (1, "a".to_string());
+        assert_eq!(keys.partition_point(|key| order(key, &after).is_le()), 2);
+
+        assert_eq!(parse_limit(None).unwrap(), DEFAULT_LIMIT);
+        assert!(parse_limit(Some("0")).is_err());
+        assert!(parse_limit(Some("101")).is_err());
+    }
+
+    #[test]
+    fn pages_by_upload_time() {
+        let storage = crate::storage::MemoryStorage::default();
+        let mut owned = vec![];
+        for seed in 0..5u8 {
+            let id = store::content_id(&[seed]);
+            storage.put(&store::original_key(&id), &[seed]).unwrap();
+            let owners = if seed % 2 == 0 { "[\"alice\"]" } else { "[]" };
+            //? the later the write, the earlier the upload
+            let record = format!("{{\"uploaded_at\": {}, \"owners\": {owners}}}", 10 - seed);
+            record::update(&storage, &id, |stored| {
+                *stored = Some(serde_json::from_str(&record)?);
+                Ok(())
+            })
+            .unwrap();
+            if seed % 2 == 0 {
+                owned.push(id);
+            }
+        }
+        //? stored before the listing was kept, it only has its file time
+        let legacy = store::content_id(b"legacy");
+        storage
+            .put(&store::original_key(&legacy), b"legacy")
+            .unwrap();
+        assert_eq!(backfill(&storage).unwrap(), 1);
+        assert_eq!(backfill(&storage).unwrap(), 0);
+
+        let pages = |owner: Option<&str>| {
+            let mut seen = vec![];
+            let mut cursor = None;
+            loop {
+                let page = list(&storage, cursor.as_deref(), 2, owner).unwrap();
+                seen.extend(page.images.into_iter().map(|image| image.id));
+                match page.next_cursor {
+                    Some(next) => cursor = Some(next),
+                    None => return seen,
+                }
+            }
+        };
+        assert_eq!(pages(Some("alice")), owned);
+        let mut all: Vec<_> = (0..5u8).map(|seed| store::content_id(&[seed])).collect();
+        all.insert(0, legacy);
+        assert_eq!(pages(None), all);
+        assert_eq!(pages(Some("bob")), Vec::<String>::new());
+
+        store::delete(&storage, &owned[1]).unwrap();
+        assert_eq!(pages(Some("alice")), [owned[0].as_str(), owned[2].as_str()]);
+    }
+}
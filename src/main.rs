@@ -1,91 +1,1401 @@
-use std::io::{self, Write};
+use std::{
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 #[macro_use]
 extern crate rouille;
 
+mod animation;
+mod api;
+mod auth;
+mod config;
+mod form;
+mod format;
+mod health;
+mod http_cache;
+mod jobs;
+mod listing;
+mod metadata;
+mod metrics;
+mod ops;
+mod overlay;
+mod placeholder;
+mod presets;
+mod record;
+mod resize;
+mod retention;
+mod signing;
+mod similar;
+mod storage;
+mod store;
+mod throttle;
+mod tus;
+mod upload;
+mod validate;
+mod variant;
+
+use auth::Auth;
+use config::Config;
+use format::OutputFormat;
+use presets::Presets;
+use resize::ResizeOptions;
+use storage::Storage;
+use throttle::Admission;
+use variant::{Variant, VariantError};
+
 fn main() {
-    println!("Now listening on localhost:8000");
+    let args = match config::Args::parse(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!(">> {err}");
+            std::process::exit(2);
+        }
+    };
+    if args.help {
+        print!("{}", config::usage());
+        return;
+    }
+    let check = args.check;
+    let config = match Config::load(args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!(">> {err}");
+            std::process::exit(1);
+        }
+    };
+    println!(">> {config:?}");
+    if check {
+        return;
+    }
 
-    rouille::start_server("localhost:8000", move |req| {
-        rouille::log(req, io::stdout(), || {
+    //? before the presets, they may ask for the watermark
+    if let Some(settings) = &config.watermark {
+        match overlay::Watermark::load(settings) {
+            Ok(watermark) => overlay::set_watermark(watermark),
+            Err(err) => {
+                eprintln!(">> {err}");
+                std::process::exit(1);
+            }
+        }
+    }
+    let presets = match Presets::load(&config.presets) {
+        Ok(presets) => presets,
+        Err(err) => {
+            eprintln!(">> {err}");
+            std::process::exit(1);
+        }
+    };
+    println!(
+        ">> presets: {:?}",
+        presets.iter().map(|p| &p.name).collect::<Vec<_>>()
+    );
+    let auth = match Auth::load(&config.tokens, &config.auth) {
+        Ok(auth) => auth,
+        Err(err) => {
+            eprintln!(">> {err}");
+            std::process::exit(1);
+        }
+    };
+    if auth.private {
+        println!(">> private mode");
+    }
+    if auth.signed_urls {
+        println!(">> renders need signed urls");
+    }
+    let storage = storage::open(&config.storage, &config.data_dir);
+    match listing::backfill(storage.as_ref()) {
+        Ok(0) => {}
+        Ok(added) => println!(">> listed {added} images stored before the listing was kept"),
+        Err(err) => {
+            eprintln!(">> cannot build the listing: {err}");
+            std::process::exit(1);
+        }
+    }
+    let retention = Arc::new(retention::Retention::new(
+        config.retention,
+        storage.clone(),
+        config.data_dir.clone(),
+    ));
+    retention::spawn_sweeper(retention.clone());
+
+    let limits = config.limits;
+    throttle::set_max_decodes(limits.max_decodes);
+    let uploads = throttle::RateLimiter::new(limits.uploads_per_minute, limits.upload_burst);
+
+    let index_file = config.data_dir.join(similar::INDEX_FILE);
+    let index = match similar::Index::open(storage.as_ref(), index_file.clone()) {
+        Ok(index) => Arc::new(index),
+        Err(err) => {
+            eprintln!(">> cannot open {}: {err}", index_file.display());
+            std::process::exit(1);
+        }
+    };
+    let queue = jobs::Queue::start(
+        config.workers,
+        config.data_dir.join(jobs::JOBS_DIR),
+        storage.clone(),
+        index.clone(),
+    );
+    match queue.resume() {
+        Ok(0) => {}
+        Ok(count) => println!(">> resuming {count} queued jobs"),
+        Err(err) => println!(">> cannot resume queued jobs: {err}"),
+    }
+    let bind = config.bind.clone();
+    let server = rouille::Server::new(&bind, move |req| {
+        let storage = storage.as_ref();
+        let started = Instant::now();
+        let response = rouille::log(req, io::stdout(), || {
             router!(req,
+                (GET) (/healthz) => {
+                    rouille::Response::text("ok")
+                },
+                (GET) (/readyz) => {
+                    readyz_ctrl(storage, &config)
+                },
+                (GET) (/metrics) => {
+                    metrics_ctrl(req, storage, &auth, &queue)
+                },
                 (GET) (/) => {
-                    rouille::Response::html(PAGE)
+                    index_ctrl(req, storage, &auth)
                 },
                 (POST) (/upload) => {
-                    upload_ctrl(req)
+                    upload_ctrl(req, storage, &config, &presets, &queue, &uploads, &auth)
+                },
+                (OPTIONS) (/uploads) => {
+                    tus_options_ctrl(&config)
+                },
+                (POST) (/uploads) => {
+                    tus_create_ctrl(req, &config, &uploads, &auth)
+                },
+                (HEAD) (/uploads/{id: String}) => {
+                    tus_head_ctrl(req, &config, &auth, &id)
+                },
+                (PATCH) (/uploads/{id: String}) => {
+                    tus_patch_ctrl(req, storage, &config, &presets, &queue, &auth, &id)
+                },
+                (DELETE) (/uploads/{id: String}) => {
+                    tus_delete_ctrl(req, &config, &auth, &id)
+                },
+                (POST) (/api/images) => {
+                    api_upload_ctrl(req, storage, &config, &presets, &queue, &uploads, &auth)
+                },
+                (GET) (/api/jobs/{id: String}) => {
+                    api_job_ctrl(&queue, &id)
+                },
+                (GET) (/api/images) => {
+                    api_list_ctrl(req, storage, &auth)
+                },
+                (GET) (/api/images/{id: String}) => {
+                    api_image_ctrl(req, storage, &auth, &id)
+                },
+                (GET) (/api/images/{id: String}/signed-url) => {
+                    api_signed_url_ctrl(req, storage, &auth, &id)
+                },
+                (GET) (/api/images/{id: String}/similar) => {
+                    api_similar_ctrl(req, storage, &auth, &index, &id)
+                },
+                (DELETE) (/api/images/{id: String}) => {
+                    api_delete_ctrl(req, storage, &auth, &index, &id)
+                },
+                (POST) (/admin/sweep) => {
+                    admin_sweep_ctrl(req, &auth, &retention)
+                },
+                (GET) (/img/{id: String}/{preset: String}) => {
+                    preset_ctrl(req, storage, &auth, &id, &preset, &presets)
+                },
+                (POST) (/img/{name: String}) => {
+                    img_post_ctrl(req, storage, &auth, &name)
                 },
                 (GET) (/img/{name: String}) => {
                     println!("looking for: {name}");
-                    if let Some(request) = req.remove_prefix("/img") {
-                        return rouille::match_assets(&request, "out");
-                    }
-                    rouille::Response::html("404 error. Try again 😏.")
-                        .with_status_code(404)
+                    img_ctrl(req, storage, &config, &auth, &name)
                 },
                 _ => rouille::Response::empty_404()
             )
-        })
+        });
+        metrics::record_request(
+            req.method(),
+            &req.url(),
+            response.status_code,
+            started.elapsed(),
+        );
+        response
     });
+    let server = match server {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!(">> cannot listen on {bind}: {err}");
+            std::process::exit(1);
+        }
+    };
+    println!("Now listening on {}", server.server_addr());
+    server.run();
 }
 
-fn upload_ctrl(req: &rouille::Request) -> rouille::Response {
-    const MAX_FILE_SIZE: usize = 1_024 * 500 /*500 kb*/;
-    // println!(">> {:?}", req.headers());
-    // println!(">> {:?}", req.header("Content-length"));
-    if let Some(string_size) = req.header("Content-length") {
-        match string_size.parse::<usize>() {
-            Ok(size) => {
-                if size > MAX_FILE_SIZE {
-                    println!(">> {size} bytes!");
-                    return rouille::Response::empty_400();
-                }
+fn img_ctrl(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    config: &Config,
+    auth: &Auth,
+    name: &str,
+) -> rouille::Response {
+    let (id, extension) = variant::split_name(name);
+    let content_id = store::is_content_id(id).then_some(id);
+    let access = match check_read(req, storage, auth, content_id, content_id.is_some()) {
+        Ok(access) => access,
+        Err((status, message)) => {
+            return challenge(rouille::Response::text(message).with_status_code(status))
+        }
+    };
+    if content_id.is_none() {
+        //? files from before content addressing still sit flat in `out/`
+        if let Some(request) = req.remove_prefix("/img") {
+            let variants = config.data_dir.join(store::VARIANTS_DIR);
+            return rouille::match_assets(&request, &variants);
+        }
+        return rouille::Response::html("404 error. Try again 😏.").with_status_code(404);
+    }
+
+    let variant = match Variant::from_params(
+        |param| req.get_param(param),
+        extension,
+        req.header("Accept"),
+    ) {
+        Ok(variant) => variant,
+        Err(err) => return rouille::Response::text(err.to_string()).with_status_code(400),
+    };
+    variant_response(req, storage, id, variant, access)
+}
+
+/// same as `GET /img/{name}` with the parameters in a JSON body,
+/// `{"ops": ["crop:10,10,200,200", "rotate:90"], "w": 100, "h": 100}`.
+/// the query string fills whatever the body leaves out.
+/// a signature only covers the url, so with signed urls or in private mode
+/// this needs the owner's or the admin token.
+fn img_post_ctrl(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    auth: &Auth,
+    name: &str,
+) -> rouille::Response {
+    let (id, extension) = variant::split_name(name);
+    if !store::is_content_id(id) {
+        return rouille::Response::html("404 error. Try again 😏.").with_status_code(404);
+    }
+    let access = match check_owner(req, storage, auth, id) {
+        Ok(access) => access,
+        Err((status, message)) => {
+            return challenge(rouille::Response::text(message).with_status_code(status))
+        }
+    };
+    let params = match read_json_params(req) {
+        Ok(params) => params,
+        Err((status, message)) => return rouille::Response::text(message).with_status_code(status),
+    };
+    let variant = match Variant::from_params(
+        |param| params.get(param).cloned().or_else(|| req.get_param(param)),
+        extension,
+        req.header("Accept"),
+    ) {
+        Ok(variant) => variant,
+        Err(err) => return rouille::Response::text(err.to_string()).with_status_code(400),
+    };
+    variant_response(req, storage, id, variant, access)
+}
+
+/// a flat JSON object of query parameters, `ops` may also be a list of steps.
+fn read_json_params(
+    req: &rouille::Request,
+) -> Result<std::collections::HashMap<String, String>, (u16, String)> {
+    use std::io::Read;
+
+    const MAX_BODY: u64 = 16 * 1_024;
+    let mut body = vec![];
+    req.data()
+        .ok_or((500, "request body already read".to_string()))?
+        .take(MAX_BODY + 1)
+        .read_to_end(&mut body)
+        .map_err(|err| (400, err.to_string()))?;
+    if body.len() as u64 > MAX_BODY {
+        return Err((413, format!("body is over {MAX_BODY} bytes")));
+    }
+    let object: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&body)
+        .map_err(|err| (400, format!("body must be a JSON object: {err}")))?;
+    object
+        .into_iter()
+        .map(|(key, value)| {
+            let value = match value {
+                serde_json::Value::String(value) => value,
+                serde_json::Value::Number(n) => n.to_string(),
+                serde_json::Value::Array(steps) if key == "ops" => steps
+                    .iter()
+                    .map(|step| {
+                        step.as_str()
+                            .ok_or((400, "`ops` steps must be strings".to_string()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join("|"),
+                _ => return Err((400, format!("`{key}` must be a string or a number"))),
+            };
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn variant_response(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    id: &str,
+    mut variant: Variant,
+    access: Access,
+) -> rouille::Response {
+    let record = record::load(storage, id);
+    //? only GIF keeps an animation, so that is what a browser gets unless it asked otherwise
+    if variant.negotiated
+        && variant.frame.is_none()
+        && record.as_ref().is_some_and(|record| record.frames > 1)
+    {
+        variant.format = OutputFormat::Gif;
+    }
+    let cache_control = match access {
+        Access::Public => http_cache::IMMUTABLE,
+        Access::Private => http_cache::PRIVATE_IMMUTABLE,
+    };
+    let uploaded_at = record.map(|record| record.uploaded_at);
+    match cached_response(req, storage, id, &variant, uploaded_at, cache_control) {
+        Ok(response) => {
+            if variant.negotiated {
+                response.with_additional_header("Vary", "Accept")
+            } else {
+                response
+            }
+        }
+        Err(err) => {
+            println!(">> variant {id}/{}: {err}", variant.key());
+            let response =
+                rouille::Response::text(err.to_string()).with_status_code(err.status_code());
+            if matches!(err, VariantError::Busy(_)) {
+                retry_after(response, throttle::BUSY_RETRY_AFTER)
+            } else {
+                response
             }
-            Err(_) => return rouille::Response::empty_400(),
         }
     }
-    let data = try_or_400!(post_input!(req, {
-        files: Vec<rouille::input::post::BufferedFile>,
-    }));
+}
 
-    println!("Received data: {:?}", data);
+/// serves `variant` of `id`, a client with a current copy gets its 304 from the
+/// stored ETag without the variant being read.
+fn cached_response(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    id: &str,
+    variant: &Variant,
+    last_modified: Option<u64>,
+    cache_control: &'static str,
+) -> Result<rouille::Response, VariantError> {
+    let stored = variant::stored_etag(storage, id, variant);
+    if let Some(etag) = &stored {
+        let validators = http_cache::Validators::new(etag.clone(), last_modified);
+        if let Some(response) = http_cache::not_modified(req, &validators, cache_control) {
+            return Ok(response);
+        }
+    }
+    let bytes = variant::get_or_render(storage, id, variant, Admission::Reject)?;
+    let etag = match stored {
+        Some(etag) => etag,
+        None => variant::save_etag(storage, &store::variant_key(id, &variant.key()), &bytes),
+    };
+    let validators = http_cache::Validators::new(etag, last_modified);
+    Ok(http_cache::respond(
+        req,
+        variant.format.mime(),
+        bytes,
+        &validators,
+        cache_control,
+    ))
+}
 
-    let mut imgs = vec![];
+fn preset_ctrl(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    auth: &Auth,
+    id: &str,
+    name: &str,
+    presets: &Presets,
+) -> rouille::Response {
+    let Some(preset) = presets.get(name) else {
+        return rouille::Response::text(format!("no preset named `{name}`")).with_status_code(404);
+    };
+    if !store::is_content_id(id) {
+        return rouille::Response::html("404 error. Try again 😏.").with_status_code(404);
+    }
+    let access = match check_read(req, storage, auth, Some(id), false) {
+        Ok(access) => access,
+        Err((status, message)) => {
+            return challenge(rouille::Response::text(message).with_status_code(status))
+        }
+    };
+    let cache_control = match access {
+        Access::Public => http_cache::REVALIDATE,
+        Access::Private => http_cache::PRIVATE_REVALIDATE,
+    };
+    //? no Last-Modified: a redefined preset renders new bytes for an old upload
+    match cached_response(req, storage, id, &preset.variant, None, cache_control) {
+        Ok(response) => response,
+        Err(err) => {
+            println!(">> preset {id}/{name}: {err}");
+            let response =
+                rouille::Response::text(err.to_string()).with_status_code(err.status_code());
+            if matches!(err, VariantError::Busy(_)) {
+                retry_after(response, throttle::BUSY_RETRY_AFTER)
+            } else {
+                response
+            }
+        }
+    }
+}
+
+/// what `/upload` and `/api/images` both read from the multipart form.
+struct UploadForm {
+    files: Vec<form::FilePart>,
+    thumbnail: Variant,
+    /// drop EXIF/GPS from the stored original as well.
+    strip: bool,
+    /// for browsers, which cannot send `Authorization` from a form.
+    token: Option<String>,
+}
+
+/// on failure returns the status and a message for the client.
+fn read_upload(req: &rouille::Request, config: &Config) -> Result<UploadForm, (u16, String)> {
+    let data = form::read(req, &config.uploads).map_err(|err| {
+        println!(">> upload rejected: {err}");
+        (err.status_code(), err.to_string())
+    })?;
+
+    for file in &data.files {
+        metrics::record_upload(file.data.len() as u64);
+    }
+    println!(
+        "Received files: {:?}",
+        data.files
+            .iter()
+            .map(|f| (&f.filename, f.data.len()))
+            .collect::<Vec<_>>()
+    );
+
+    //? form fields win, the query string (`/upload?w=320&h=200`) fills the gaps
+    let form_param = |name: &str| data.field(name);
+    let param = |name: &str| {
+        form_param(name)
+            .filter(|v| !v.trim().is_empty())
+            .or_else(|| req.get_param(name))
+            .filter(|v| !v.trim().is_empty())
+    };
+    let (thumbnail, strip) = upload_options(param, config)?;
+    //? never from the query string, it would end up in logs
+    let token = form_param("token");
+
+    Ok(UploadForm {
+        files: data.files,
+        thumbnail,
+        strip,
+        token,
+    })
+}
+
+/// the requested thumbnail and `strip`, from the form of a multipart upload
+/// or the metadata of a resumable one.
+fn upload_options<F>(param: F, config: &Config) -> Result<(Variant, bool), (u16, String)>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let (width, height) = config.thumbnail;
+    let defaults = ResizeOptions {
+        width,
+        height: Some(height),
+        ..ResizeOptions::default()
+    };
+    let options =
+        ResizeOptions::from_params_or(&param, defaults).map_err(|err| (400, err.to_string()))?;
+    let output_format = match param("format").map(|f| OutputFormat::parse(&f)) {
+        None => OutputFormat::Png,
+        Some(Ok(format)) => format,
+        Some(Err(err)) => return Err((400, err.to_string())),
+    };
+    let quality = match param("q").map(|q| format::parse_quality(&q)) {
+        None => format::DEFAULT_QUALITY,
+        Some(Ok(q)) => q,
+        Some(Err(err)) => return Err((400, err.to_string())),
+    };
+    let ops = match param("ops").map(|ops| ops::Ops::parse(&ops)) {
+        None => ops::Ops::default(),
+        Some(Ok(ops)) => ops,
+        Some(Err(err)) => return Err((400, err.to_string())),
+    };
+    let strip = matches!(
+        param("strip").as_deref().map(str::trim),
+        Some("1" | "true" | "on" | "yes")
+    );
+    let thumbnail = Variant {
+        resize: Some(options),
+        ops,
+        watermark: false,
+        caption: None,
+        frame: None,
+        format: output_format,
+        quality,
+        negotiated: false,
+    };
+    Ok((thumbnail, strip))
+}
+
+/// `Retry-After` in whole seconds, on a 429 or a 503.
+fn retry_after(response: rouille::Response, wait: Duration) -> rouille::Response {
+    response.with_additional_header("Retry-After", throttle::retry_after_secs(wait).to_string())
+}
 
-    for (num, hack) in data.files.into_iter().enumerate() {
+/// one token per upload request, whatever the number of files in it.
+fn check_upload_rate(
+    req: &rouille::Request,
+    uploads: &throttle::RateLimiter,
+) -> Result<(), (Duration, String)> {
+    let client = req.remote_addr().ip();
+    uploads.check(client).map_err(|wait| {
+        println!(">> upload from {client} rate limited");
+        (
+            wait,
+            format!(
+                "too many uploads, try again in {}s",
+                throttle::retry_after_secs(wait)
+            ),
+        )
+    })
+}
+
+fn upload_ctrl(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    config: &Config,
+    presets: &Presets,
+    queue: &jobs::Queue,
+    uploads: &throttle::RateLimiter,
+    auth: &Auth,
+) -> rouille::Response {
+    if let Err((wait, message)) = check_upload_rate(req, uploads) {
+        return retry_after(rouille::Response::text(message).with_status_code(429), wait);
+    }
+    let form = match read_upload(req, config) {
+        Ok(form) => form,
+        Err((status, message)) => return rouille::Response::text(message).with_status_code(status),
+    };
+    let owner = match upload_owner(req, auth, form.token.as_deref()) {
+        Ok(owner) => owner,
+        Err((status, message)) => {
+            return challenge(rouille::Response::text(message).with_status_code(status))
+        }
+    };
+
+    let tasks = upload::tasks(&form.thumbnail, presets);
+    let mut imgs = vec![];
+    for hack in form.files.into_iter() {
+        //? the form always posts three file inputs, unused ones arrive empty
         if hack.data.is_empty() {
             continue;
         }
-        //? println!(">> {}",std::time::SystemTime::now().duration_since(std::time::SystemTime::UNIX_EPOCH).unwrap().as_millis());
-        let id = std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-        let filepath = format!("public/{num}-{id}.png");
+        match upload::process(
+            storage,
+            &hack.data,
+            &tasks,
+            form.strip,
+            owner.as_deref(),
+            queue,
+        ) {
+            Ok(upload) => imgs.push(upload),
+            Err(err) => {
+                println!(">> upload {:?}: {err}", hack.filename);
+                return rouille::Response::text(err.to_string())
+                    .with_status_code(err.status_code());
+            }
+        }
+    }
 
-        let mut file = std::fs::File::create(&filepath).expect("file creation");
-        file.write_all(&hack.data).expect("write file");
-        let resized = image::open(&filepath).unwrap();
+    let Some(upload) = imgs.pop() else {
+        return rouille::Response::text("no file selected 🤔").with_status_code(400);
+    };
+    let link = |url: &str| {
+        let url = signed_link(auth, url).replace('&', "&amp;");
+        format!("<a href=\"{url}\">{url}</a>")
+    };
+    //? the urls are known up front, `/img` renders on demand if the job is not done yet
+    let (thumbnail, presets) = tasks.split_first().expect("the thumbnail is always a task");
+    let mut html = format!("Success 🎉! try: {}.", link(&thumbnail.url(&upload.id)));
+    if !presets.is_empty() {
+        html.push_str("<ul>");
+        for task in presets {
+            let name = task.preset.as_deref().unwrap_or_default();
+            html.push_str(&format!("<li>{name}: {}</li>", link(&task.url(&upload.id))));
+        }
+        html.push_str("</ul>");
+    }
+    match (&upload.job, &upload.job_error) {
+        (Some(job), _) => {
+            let job_url = job.url.replace('&', "&amp;");
+            html.push_str(&format!(
+                "<p>resize job: <a href=\"{job_url}\">{job_url}</a></p>"
+            ));
+        }
+        (None, Some(failure)) => html.push_str(&format!(
+            "<p>no resize job: {}, the links render on first view.</p>",
+            failure.message
+        )),
+        (None, None) => {}
+    }
+    rouille::Response::html(html)
+}
 
-        let id = std::time::SystemTime::now()
-            .duration_since(std::time::SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-
-        let name = format!("{num}-{id}.png");
-        let filepath = format!("out/{name}");
-        imgs.push(name.clone());
-        resized
-            .resize_exact(100, 100, image::imageops::FilterType::Nearest)
-            //   .save_with_format(filepath, image::ImageFormat::WebP)
-            .save(filepath)
-            .unwrap();
+/// the upload form with a gallery of what is stored, paged like `/api/images`.
+fn index_ctrl(req: &rouille::Request, storage: &dyn Storage, auth: &Auth) -> rouille::Response {
+    //? browsers carry no token, a listing would only be broken links, or leak
+    if auth.private {
+        let note = "<h2>gallery</h2><p>this server is private, use the api with a token.</p>";
+        return rouille::Response::html(PAGE.replace("<!-- gallery -->", note));
     }
+    let cursor = req.get_param("cursor");
+    let page = match listing::list(storage, cursor.as_deref(), listing::DEFAULT_LIMIT, None) {
+        Ok(page) => page,
+        Err(err) => {
+            println!(">> gallery: {err}");
+            return rouille::Response::text(err.to_string()).with_status_code(err.status_code());
+        }
+    };
 
-    let filename = imgs.pop().unwrap();
-    rouille::Response::html(format!(
-        "Success 🎉! try: <a href=\"img/{filename}\">{filename}</a>."
-    ))
+    let mut gallery = String::from("<h2>gallery</h2>");
+    if page.images.is_empty() {
+        gallery.push_str("<p>nothing uploaded yet.</p>");
+    }
+    gallery.push_str(r#"<div style="display:flex;flex-wrap:wrap;gap:8px">"#);
+    for image in &page.images {
+        //? the blurry preview shows through until the thumbnail paints over it
+        let style = match &image.placeholder {
+            Some(placeholder) => format!(
+                r#" style="background:{} url({}) center/cover""#,
+                placeholder.dominant_color().unwrap_or("#eee"),
+                placeholder.lqip,
+            ),
+            None => String::new(),
+        };
+        //? no extension: browsers get WebP through `Accept`
+        let thumbnail = signed_link(auth, &format!("{}?w=160&h=160&fit=cover", image.url));
+        gallery.push_str(&format!(
+            r#"<a href="{url}" title="{id}"><img src="{thumbnail}" width="160" height="160" loading="lazy" alt=""{style} /></a>"#,
+            url = signed_link(auth, &image.url).replace('&', "&amp;"),
+            thumbnail = thumbnail.replace('&', "&amp;"),
+            id = image.id,
+        ));
+    }
+    gallery.push_str("</div>");
+    if let Some(next) = &page.next_cursor {
+        gallery.push_str(&format!(r#"<p><a href="/?cursor={next}">older →</a></p>"#));
+    }
+    rouille::Response::html(PAGE.replace("<!-- gallery -->", &gallery))
+}
+
+fn api_list_ctrl(req: &rouille::Request, storage: &dyn Storage, auth: &Auth) -> rouille::Response {
+    //? private: an owner only sees their own images, the admin sees everything
+    let owner = if auth.private && !has_admin_token(req, auth) {
+        match caller(req, auth) {
+            Some(owner) => Some(owner),
+            None => return challenge(api::error(401, "unauthorized", PRIVATE)),
+        }
+    } else {
+        None
+    };
+    let page = listing::parse_limit(req.get_param("limit").as_deref())
+        .and_then(|limit| listing::list(storage, req.get_param("cursor").as_deref(), limit, owner));
+    match page {
+        Ok(page) => rouille::Response::json(&page),
+        Err(err) => {
+            let status = err.status_code();
+            api::error(status, api::code_for(status), err)
+        }
+    }
+}
+
+fn api_delete_ctrl(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    auth: &Auth,
+    index: &similar::Index,
+    id: &str,
+) -> rouille::Response {
+    if !store::is_content_id(id) {
+        return api::error(404, "not_found", "no image with that id");
+    }
+    let record = record::load(storage, id);
+    let owners = record.as_ref().map_or(&[][..], |record| &record.owners[..]);
+    //? owned images are only deleted by an owner, anonymous ones stay open unless private
+    if (!owners.is_empty() || auth.private) && !has_admin_token(req, auth) {
+        let Some(owner) = caller(req, auth) else {
+            return challenge(api::error(
+                401,
+                "unauthorized",
+                "deleting needs the owner's token",
+            ));
+        };
+        if !owners.iter().any(|known| known == owner) {
+            return api::error(404, "not_found", "no image with that id");
+        }
+        //? the same bytes uploaded by someone else stay theirs
+        let shared = record::update(storage, id, |record| {
+            let Some(record) = record.as_mut().filter(|record| record.owners.len() > 1) else {
+                return Ok(false);
+            };
+            record.owners.retain(|known| known != owner);
+            Ok(true)
+        });
+        match shared {
+            Ok(true) => return rouille::Response::empty_204(),
+            Ok(false) => {}
+            Err(err) => return api::error(500, "storage_error", err),
+        }
+    }
+    let phash = record.and_then(|record| record.phash);
+    match store::delete(storage, id) {
+        Ok(true) => {
+            println!(">> deleted {id}");
+            if let Some(phash) = phash {
+                if let Err(err) = index.remove(id, phash) {
+                    println!(
+                        ">> delete {id}: cannot update {}: {err}",
+                        similar::INDEX_FILE
+                    );
+                }
+            }
+            rouille::Response::empty_204()
+        }
+        Ok(false) => api::error(404, "not_found", "no image with that id"),
+        Err(err) => {
+            println!(">> delete {id}: {err}");
+            api::error(500, "storage_error", err)
+        }
+    }
+}
+
+/// other uploads that look the same, for spotting re-uploads at another size or quality.
+fn api_similar_ctrl(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    auth: &Auth,
+    index: &similar::Index,
+    id: &str,
+) -> rouille::Response {
+    if !store::is_content_id(id) {
+        return api::error(404, "not_found", "no image with that id");
+    }
+    if let Err((status, message)) = check_read(req, storage, auth, Some(id), false) {
+        return challenge(api::error(status, api::code_for(status), message));
+    }
+    let similar = similar::parse_max_distance(req.get_param("max_distance").as_deref())
+        .and_then(|max_distance| index.similar(storage, id, max_distance));
+    match similar {
+        Ok(mut similar) => {
+            //? in private mode only the caller's own look-alikes are listed
+            if auth.private && !has_admin_token(req, auth) {
+                let owner = caller(req, auth);
+                similar.images.retain(|image| {
+                    owner.is_some_and(|owner| {
+                        record::load(storage, &image.id).is_some_and(|r| r.is_owned_by(owner))
+                    })
+                });
+            }
+            rouille::Response::json(&similar)
+        }
+        Err(similar::SimilarError::Busy(err)) => {
+            retry_after(api::error(503, "busy", err), throttle::BUSY_RETRY_AFTER)
+        }
+        Err(err) => {
+            if err.status_code() >= 500 {
+                println!(">> similar {id}: {err}");
+            }
+            api::error(err.status_code(), err.code(), err)
+        }
+    }
+}
+
+/// `Authorization: Bearer $ADMIN_TOKEN`, or any local client when no token is set.
+fn is_admin(req: &rouille::Request, auth: &Auth) -> bool {
+    if auth.admin_token.is_some() {
+        has_admin_token(req, auth)
+    } else {
+        req.remote_addr().ip().is_loopback()
+    }
+}
+
+/// `Authorization: Bearer $ADMIN_TOKEN` only, being a local client is not enough.
+fn has_admin_token(req: &rouille::Request, auth: &Auth) -> bool {
+    auth::bearer(req.header("Authorization")).is_some_and(|given| auth.is_admin_token(given))
+}
+
+const PRIVATE: &str =
+    "this server is private: send `Authorization: Bearer <token>` or use a signed url";
+
+const UNSIGNED: &str = "renders need a signed url, or the owner's `Authorization: Bearer <token>`";
+
+const BODY_UNSIGNED: &str =
+    "a signature does not cover the body, send the owner's `Authorization: Bearer <token>`";
+
+/// who a read was let through for, and so who may cache the response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    /// anyone could have made the same request.
+    Public,
+    /// a token or a signature let it through, shared caches must not keep it.
+    Private,
+}
+
+/// the owner of the `Authorization: Bearer` token.
+fn caller<'a>(req: &rouille::Request, auth: &'a Auth) -> Option<&'a str> {
+    auth::bearer(req.header("Authorization")).and_then(|token| auth.owner(token))
+}
+
+/// any read in private mode, and a `transform` (a render from the url's parameters)
+/// with signed urls on, needs an owner of `id`, the admin, or a signed url.
+/// `id` is `None` for the flat files from before content addressing, any owner can read those.
+/// what let the read through decides whether shared caches may keep the response.
+fn check_read(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    auth: &Auth,
+    id: Option<&str>,
+    transform: bool,
+) -> Result<Access, (u16, String)> {
+    if !(auth.private || transform && auth.signed_urls) {
+        return Ok(Access::Public);
+    }
+    if has_admin_token(req, auth) {
+        return Ok(Access::Private);
+    }
+    if let Some(owner) = caller(req, auth) {
+        let owned = id.is_none_or(|id| {
+            record::load(storage, id).is_some_and(|record| record.is_owned_by(owner))
+        });
+        if owned {
+            return Ok(Access::Private);
+        }
+        //? someone else's private image does not exist as far as this owner knows
+        if auth.private {
+            return Err((404, "no image with that id".to_string()));
+        }
+    }
+    match auth
+        .signer
+        .as_ref()
+        .map(|signer| signer.verify(req.raw_url(), record::now()))
+    {
+        Some(Ok(())) => Ok(Access::Private),
+        None | Some(Err(signing::SignatureError::Missing)) => {
+            let message = if auth.private { PRIVATE } else { UNSIGNED };
+            Err((401, message.to_string()))
+        }
+        Some(Err(err)) => Err((403, err.to_string())),
+    }
+}
+
+/// like [`check_read`] for a render a signed url cannot vouch for: only a token will do.
+fn check_owner(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    auth: &Auth,
+    id: &str,
+) -> Result<Access, (u16, String)> {
+    if !(auth.private || auth.signed_urls) {
+        return Ok(Access::Public);
+    }
+    if has_admin_token(req, auth) {
+        return Ok(Access::Private);
+    }
+    match caller(req, auth) {
+        Some(owner)
+            if record::load(storage, id).is_some_and(|record| record.is_owned_by(owner)) =>
+        {
+            Ok(Access::Private)
+        }
+        Some(_) if auth.private => Err((404, "no image with that id".to_string())),
+        _ => Err((401, BODY_UNSIGNED.to_string())),
+    }
+}
+
+/// 401s say how to authenticate.
+fn challenge(response: rouille::Response) -> rouille::Response {
+    if response.status_code == 401 {
+        response.with_additional_header("WWW-Authenticate", "Bearer")
+    } else {
+        response
+    }
+}
+
+/// with tokens configured an upload needs one, from `Authorization: Bearer` or the
+/// form's `token` field, and its owner is recorded. without, only local clients
+/// can upload and their images have no owner.
+fn upload_owner(
+    req: &rouille::Request,
+    auth: &Auth,
+    form_token: Option<&str>,
+) -> Result<Option<String>, (u16, String)> {
+    if !auth.has_tokens() {
+        if !req.remote_addr().ip().is_loopback() {
+            return Err((
+                401,
+                "uploads need a token and none are configured".to_string(),
+            ));
+        }
+        return Ok(None);
+    }
+    let token = auth::bearer(req.header("Authorization"))
+        .or(form_token.map(str::trim))
+        .filter(|token| !token.is_empty());
+    match token.map(|token| auth.owner(token)) {
+        Some(Some(owner)) => Ok(Some(owner.to_string())),
+        Some(None) => Err((401, "unknown token".to_string())),
+        None => Err((
+            401,
+            "uploads need a token: `Authorization: Bearer <token>`".to_string(),
+        )),
+    }
+}
+
+/// the links handed to a browser are signed when they need to be, it cannot send a token.
+fn signed_link(auth: &Auth, url: &str) -> String {
+    match &auth.signer {
+        Some(signer) if auth.private || auth.signed_urls => {
+            signer.sign(url, record::now() + signing::DEFAULT_TTL.as_secs())
+        }
+        _ => url.to_string(),
+    }
+}
+
+/// a link to a render of `id` for whoever holds it, until it expires:
+/// `GET /api/images/{id}/signed-url?w=320&h=200&format=webp&ttl=600`.
+/// the parameters are those of `/img`, the link carries them in canonical form.
+/// with `format` the link pins it in the extension, without it the link has none
+/// and each client gets what its `Accept` asks for.
+fn api_signed_url_ctrl(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    auth: &Auth,
+    id: &str,
+) -> rouille::Response {
+    let Some(signer) = &auth.signer else {
+        return api::error(404, "not_found", "signed urls are not configured");
+    };
+    if !store::is_content_id(id) || !storage.exists(&store::original_key(id)).unwrap_or(false) {
+        return api::error(404, "not_found", "no image with that id");
+    }
+    //? a link is a capability: only an owner, or the admin, can hand one out
+    let owned = caller(req, auth).is_some_and(|owner| {
+        record::load(storage, id).is_some_and(|record| record.is_owned_by(owner))
+    });
+    if !owned && !is_admin(req, auth) {
+        return challenge(api::error(
+            401,
+            "unauthorized",
+            "signing needs the owner's or the admin token",
+        ));
+    }
+    let ttl = match signing::parse_ttl(req.get_param("ttl").as_deref()) {
+        Ok(ttl) => ttl,
+        Err(err) => return api::error(400, "bad_request", err),
+    };
+    let variant = match Variant::from_params(|param| req.get_param(param), None, None) {
+        Ok(variant) => variant,
+        Err(err) => return api::error(400, "bad_request", err),
+    };
+    let expires_at = record::now() + ttl.as_secs();
+    rouille::Response::json(&signing::SignedUrl {
+        url: signer.sign(&variant.url(id), expires_at),
+        expires_at,
+    })
+}
+
+/// ready once every place the server writes to takes a write.
+fn readyz_ctrl(storage: &dyn Storage, config: &Config) -> rouille::Response {
+    let readiness = health::readiness(storage, &config.data_dir);
+    for check in readiness.checks.iter().filter(|check| !check.ok) {
+        println!(
+            ">> not ready: {}: {}",
+            check.name,
+            check.error.as_deref().unwrap_or("")
+        );
+    }
+    let status = if readiness.ready { 200 } else { 503 };
+    rouille::Response::json(&readiness)
+        .with_status_code(status)
+        .with_no_cache()
+}
+
+/// admin only like `/admin/sweep`, a scraper sends `Authorization: Bearer $ADMIN_TOKEN`.
+fn metrics_ctrl(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    auth: &Auth,
+    queue: &jobs::Queue,
+) -> rouille::Response {
+    if !is_admin(req, auth) {
+        return api::error(403, "forbidden", "admin token required");
+    }
+    let usage = match metrics::storage_usage(storage) {
+        Ok(usage) => Some(usage),
+        Err(err) => {
+            println!(">> metrics: cannot list the storage: {err}");
+            None
+        }
+    };
+    let text = metrics::render(&metrics::Gauges {
+        queue_depth: queue.depth(),
+        storage: usage,
+    });
+    rouille::Response::from_data("text/plain; version=0.0.4; charset=utf-8", text).with_no_cache()
+}
+
+fn admin_sweep_ctrl(
+    req: &rouille::Request,
+    auth: &Auth,
+    retention: &retention::Retention,
+) -> rouille::Response {
+    if !is_admin(req, auth) {
+        return api::error(403, "forbidden", "admin token required");
+    }
+    match retention.sweep() {
+        Ok(report) => {
+            println!(
+                ">> manual sweep: {} bytes freed, {} bytes stored",
+                report.bytes_freed, report.bytes_stored
+            );
+            rouille::Response::json(&report)
+        }
+        Err(err) => {
+            println!(">> manual sweep failed: {err}");
+            api::error(500, "storage_error", err)
+        }
+    }
+}
+
+fn api_job_ctrl(queue: &jobs::Queue, id: &str) -> rouille::Response {
+    match jobs::is_job_id(id).then(|| queue.job(id)).flatten() {
+        Some(job) => rouille::Response::json(&job),
+        None => api::error(404, "not_found", "no job with that id"),
+    }
+}
+
+fn api_image_ctrl(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    auth: &Auth,
+    id: &str,
+) -> rouille::Response {
+    if !store::is_content_id(id) {
+        return api::error(404, "not_found", "no image with that id");
+    }
+    if let Err((status, message)) = check_read(req, storage, auth, Some(id), false) {
+        return challenge(api::error(status, api::code_for(status), message));
+    }
+    let bytes = match storage.read(&store::original_key(id)) {
+        Ok(bytes) => bytes,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return api::error(404, "not_found", "no image with that id")
+        }
+        Err(err) => return api::error(500, "storage_error", err),
+    };
+    match metadata::ImageInfo::read(id, &bytes, record::load(storage, id).as_ref()) {
+        Ok(info) => rouille::Response::json(&info),
+        Err(err) => api::error(500, "unreadable_image", err),
+    }
+}
+
+fn api_upload_ctrl(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    config: &Config,
+    presets: &Presets,
+    queue: &jobs::Queue,
+    uploads: &throttle::RateLimiter,
+    auth: &Auth,
+) -> rouille::Response {
+    if let Err((wait, message)) = check_upload_rate(req, uploads) {
+        return retry_after(api::error(429, "rate_limited", message), wait);
+    }
+    let form = match read_upload(req, config) {
+        Ok(form) => form,
+        Err((status, message)) => return api::error(status, api::code_for(status), message),
+    };
+    let owner = match upload_owner(req, auth, form.token.as_deref()) {
+        Ok(owner) => owner,
+        Err((status, message)) => {
+            return challenge(api::error(status, api::code_for(status), message))
+        }
+    };
+    if form.files.is_empty() {
+        return api::error(400, "no_files", "send one or more `files` parts");
+    }
+
+    let tasks = upload::tasks(&form.thumbnail, presets);
+    let files = form
+        .files
+        .into_iter()
+        .enumerate()
+        .map(|(index, file)| {
+            let result = upload::process(
+                storage,
+                &file.data,
+                &tasks,
+                form.strip,
+                owner.as_deref(),
+                queue,
+            );
+            if let Err(err) = &result {
+                println!(">> upload {:?}: {err}", file.filename);
+            }
+            api::FileResult::new(index, file.filename, result)
+        })
+        .collect();
+    rouille::Response::json(&api::UploadResponse { files })
+}
+
+/// every tus response names the protocol version it speaks.
+fn tus_response(response: rouille::Response) -> rouille::Response {
+    response.with_additional_header("Tus-Resumable", tus::TUS_VERSION)
+}
+
+fn tus_error(err: tus::TusError) -> rouille::Response {
+    let response = rouille::Response::text(err.to_string()).with_status_code(err.status_code());
+    let response = match err {
+        tus::TusError::BadVersion(_) => {
+            response.with_additional_header("Tus-Version", tus::TUS_VERSION)
+        }
+        _ => response,
+    };
+    tus_response(response)
+}
+
+/// `OPTIONS /uploads`, what a tus client may ask first.
+fn tus_options_ctrl(config: &Config) -> rouille::Response {
+    tus_response(
+        rouille::Response::empty_204()
+            .with_additional_header("Tus-Version", tus::TUS_VERSION)
+            .with_additional_header("Tus-Extension", tus::TUS_EXTENSIONS)
+            .with_additional_header("Tus-Max-Size", config.max_resumable_bytes.to_string()),
+    )
+}
+
+/// `POST /uploads` with `Upload-Length`, and `Upload-Metadata` carrying `filename` and
+/// the thumbnail parameters of a multipart upload (`w`, `h`, `fit`, `format`, ...).
+fn tus_create_ctrl(
+    req: &rouille::Request,
+    config: &Config,
+    uploads: &throttle::RateLimiter,
+    auth: &Auth,
+) -> rouille::Response {
+    if let Err(err) = tus::check_version(req.header("Tus-Resumable")) {
+        return tus_error(err);
+    }
+    if let Err((wait, message)) = check_upload_rate(req, uploads) {
+        return tus_response(retry_after(
+            rouille::Response::text(message).with_status_code(429),
+            wait,
+        ));
+    }
+    let owner = match upload_owner(req, auth, None) {
+        Ok(owner) => owner,
+        Err((status, message)) => {
+            return tus_response(challenge(
+                rouille::Response::text(message).with_status_code(status),
+            ))
+        }
+    };
+    let length = match tus::parse_length(req.header("Upload-Length"), config.max_resumable_bytes) {
+        Ok(length) => length,
+        Err(err) => return tus_error(err),
+    };
+    let metadata = match tus::parse_metadata(req.header("Upload-Metadata")) {
+        Ok(metadata) => metadata,
+        Err(err) => return tus_error(err),
+    };
+    //? a bad `w` is better said now than after the last chunk
+    if let Err((status, message)) = upload_options(
+        |name| metadata.get(name).filter(|v| !v.trim().is_empty()).cloned(),
+        config,
+    ) {
+        return tus_response(rouille::Response::text(message).with_status_code(status));
+    }
+    match tus::create(&tus_dir(config), length, metadata, owner) {
+        Ok(id) => {
+            println!(">> resumable upload {id}: {length} bytes");
+            tus_response(
+                rouille::Response::empty_204()
+                    .with_status_code(201)
+                    .with_additional_header("Location", format!("/uploads/{id}")),
+            )
+        }
+        Err(err) => tus_error(err),
+    }
+}
+
+/// where the partial uploads are kept.
+fn tus_dir(config: &Config) -> std::path::PathBuf {
+    config.data_dir.join(tus::UPLOADS_DIR)
+}
+
+/// the upload `id` with its offset, when the caller is the one who created it.
+fn tus_upload(
+    req: &rouille::Request,
+    config: &Config,
+    auth: &Auth,
+    id: &str,
+) -> Result<(tus::Info, u64), rouille::Response> {
+    tus::check_version(req.header("Tus-Resumable")).map_err(tus_error)?;
+    let owner = upload_owner(req, auth, None).map_err(|(status, message)| {
+        tus_response(challenge(
+            rouille::Response::text(message).with_status_code(status),
+        ))
+    })?;
+    let (info, offset) = tus::get(&tus_dir(config), id).map_err(tus_error)?;
+    //? someone else's upload does not exist, as far as the caller can tell
+    if info.owner != owner {
+        return Err(tus_error(tus::TusError::NotFound));
+    }
+    Ok((info, offset))
+}
+
+/// `HEAD /uploads/{id}`, where to resume from.
+fn tus_head_ctrl(
+    req: &rouille::Request,
+    config: &Config,
+    auth: &Auth,
+    id: &str,
+) -> rouille::Response {
+    let (info, offset) = match tus_upload(req, config, auth, id) {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    let mut response = rouille::Response::empty_204()
+        .with_status_code(200)
+        .with_additional_header("Upload-Offset", offset.to_string())
+        .with_additional_header("Upload-Length", info.length.to_string())
+        .with_no_cache();
+    if !info.metadata.is_empty() {
+        response = response
+            .with_additional_header("Upload-Metadata", tus::encode_metadata(&info.metadata));
+    }
+    tus_response(response)
+}
+
+/// `PATCH /uploads/{id}` with `Upload-Offset` and the next bytes.
+/// after the last one the image goes through the same pipeline as a multipart upload,
+/// the answer then names it in `Image-Id`.
+fn tus_patch_ctrl(
+    req: &rouille::Request,
+    storage: &dyn Storage,
+    config: &Config,
+    presets: &Presets,
+    queue: &jobs::Queue,
+    auth: &Auth,
+    id: &str,
+) -> rouille::Response {
+    let dir = tus_dir(config);
+    let (info, _) = match tus_upload(req, config, auth, id) {
+        Ok(upload) => upload,
+        Err(response) => return response,
+    };
+    if req.header("Content-Type").map(str::trim) != Some("application/offset+octet-stream") {
+        return tus_error(tus::TusError::BadContentType);
+    }
+    let offset = match tus::parse_offset(req.header("Upload-Offset")) {
+        Ok(offset) => offset,
+        Err(err) => return tus_error(err),
+    };
+    let content_length = req
+        .header("Content-Length")
+        .and_then(|length| length.trim().parse().ok());
+    let Some(mut body) = req.data() else {
+        return tus_response(
+            rouille::Response::text("request body was already consumed").with_status_code(500),
+        );
+    };
+    let offset = match tus::append(&dir, id, offset, content_length, &mut body) {
+        Ok(offset) => offset,
+        Err(err) => {
+            println!(">> resumable upload {id}: {err}");
+            return tus_error(err);
+        }
+    };
+    let progress =
+        rouille::Response::empty_204().with_additional_header("Upload-Offset", offset.to_string());
+    if offset < info.length {
+        return tus_response(progress);
+    }
+
+    //? a failed final PATCH can be retried with an empty body, the bytes are still there
+    let bytes = match tus::read(&dir, id) {
+        Ok(bytes) => bytes,
+        Err(err) => return tus_error(err),
+    };
+    metrics::record_upload(bytes.len() as u64);
+    let thumbnail = upload_options(
+        |name| {
+            info.metadata
+                .get(name)
+                .filter(|v| !v.trim().is_empty())
+                .cloned()
+        },
+        config,
+    );
+    let result = thumbnail.and_then(|(thumbnail, strip)| {
+        let tasks = upload::tasks(&thumbnail, presets);
+        upload::process(storage, &bytes, &tasks, strip, info.owner.as_deref(), queue)
+            .map_err(|err| (err.status_code(), err.to_string()))
+    });
+    println!(
+        ">> resumable upload {id} complete: {:?}, {} bytes",
+        info.metadata.get("filename"),
+        bytes.len()
+    );
+    match result {
+        Ok(image) => {
+            if let Err(err) = tus::remove(&dir, id) {
+                println!(">> resumable upload {id}: cannot remove: {err}");
+            }
+            tus_response(progress.with_additional_header("Image-Id", image.id))
+        }
+        Err((status, message)) => {
+            println!(">> resumable upload {id}: {message}");
+            //? a rejected image stays rejected, a storage or queue failure may pass later
+            if status < 500 {
+                if let Err(err) = tus::remove(&dir, id) {
+                    println!(">> resumable upload {id}: cannot remove: {err}");
+                }
+            }
+            tus_response(rouille::Response::text(message).with_status_code(status))
+        }
+    }
+}
+
+/// `DELETE /uploads/{id}`, termination.
+fn tus_delete_ctrl(
+    req: &rouille::Request,
+    config: &Config,
+    auth: &Auth,
+    id: &str,
+) -> rouille::Response {
+    if let Err(response) = tus_upload(req, config, auth, id) {
+        return response;
+    }
+    match tus::remove(&tus_dir(config), id) {
+        Ok(_) => tus_response(rouille::Response::empty_204()),
+        Err(err) => tus_error(err),
+    }
 }
 
 static PAGE: &str = r#"
@@ -103,10 +1413,576 @@ static PAGE: &str = r#"
             <input type="file" name="files" id="file_one" />
             <input type="file" name="files" id="file_two" />
             <input type="file" name="files" id="file_three" />
+            <div class="grid">
+                <label>Width <input type="number" name="w" min="1" max="4096" placeholder="100" /></label>
+                <label>Height <input type="number" name="h" min="1" max="4096" placeholder="100" /></label>
+                <label>Fit
+                    <select name="fit">
+                        <option value="cover">cover (crop)</option>
+                        <option value="contain">contain (letterbox)</option>
+                        <option value="exact">exact (stretch)</option>
+                        <option value="width">scale by width</option>
+                    </select>
+                </label>
+                <label>Filter
+                    <select name="filter">
+                        <option value="triangle">Triangle</option>
+                        <option value="catmullrom">CatmullRom</option>
+                        <option value="lanczos3">Lanczos3</option>
+                        <option value="nearest">Nearest</option>
+                    </select>
+                </label>
+            </div>
+            <div class="grid">
+                <label>Format
+                    <select name="format">
+                        <option value="png">PNG</option>
+                        <option value="webp">WebP (lossless)</option>
+                        <option value="jpeg">JPEG</option>
+                        <option value="gif">GIF</option>
+                        <option value="bmp">BMP</option>
+                    </select>
+                </label>
+                <label>JPEG quality <input type="number" name="q" min="1" max="100" placeholder="80" /></label>
+            </div>
+            <label><input type="checkbox" name="strip" value="1" /> Strip EXIF/GPS metadata from the stored original</label>
+            <label>API token <input type="password" name="token" autocomplete="off" placeholder="only if the server asks for one" /></label>
             <br />
             <p><button>Upload</button></p>
         </form>
+        <!-- gallery -->
       </main>
     </body>
 </html>
 "#;
+
+#[cfg(test)]
+mod test {
+    use std::{fs, io::Read};
+
+    use super::*;
+
+    const ALICE: &str = "alice-0123456789abcdef";
+    const SECRET: &str = "0123456789abcdef0123456789abcdef";
+
+    /// the state `main` hands the controllers, over `MemoryStorage`.
+    struct Server {
+        storage: Arc<storage::MemoryStorage>,
+        config: Config,
+        auth: Auth,
+        queue: jobs::Queue,
+    }
+
+    impl Drop for Server {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.config.data_dir);
+        }
+    }
+
+    /// the local files go to a directory of their own per `test`, alice has a token.
+    fn server(test: &str, settings: auth::Settings) -> Server {
+        let data_dir = std::env::temp_dir().join(format!("ctrl-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        fs::create_dir_all(&data_dir).unwrap();
+        let tokens = data_dir.join(auth::TOKENS_FILE);
+        fs::write(
+            &tokens,
+            format!("[[tokens]]\nowner = \"alice\"\ntoken = \"{ALICE}\"\n"),
+        )
+        .unwrap();
+        let storage = Arc::new(storage::MemoryStorage::default());
+        let index =
+            similar::Index::open(storage.as_ref(), data_dir.join(similar::INDEX_FILE)).unwrap();
+        let queue = jobs::Queue::start(
+            1,
+            data_dir.join(jobs::JOBS_DIR),
+            storage.clone(),
+            Arc::new(index),
+        );
+        Server {
+            auth: Auth::load(&tokens, &settings).unwrap(),
+            config: Config {
+                data_dir,
+                ..Config::default()
+            },
+            storage,
+            queue,
+        }
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let img =
+            image::RgbImage::from_fn(width, height, |x, y| image::Rgb([x as u8, y as u8, 128]));
+        let mut bytes = io::Cursor::new(vec![]);
+        image::DynamicImage::ImageRgb8(img)
+            .write_to(&mut bytes, image::ImageOutputFormat::Png)
+            .unwrap();
+        bytes.into_inner()
+    }
+
+    fn request(method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]) -> rouille::Request {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        rouille::Request::fake_http(method, url, headers, body.to_vec())
+    }
+
+    /// stores `bytes` for `owner` and waits for its job, the worker would hold a decode slot.
+    fn upload(server: &Server, bytes: &[u8], owner: Option<&str>) -> String {
+        let upload = upload::process(
+            server.storage.as_ref(),
+            bytes,
+            &[],
+            false,
+            owner,
+            &server.queue,
+        )
+        .unwrap();
+        while server
+            .queue
+            .job(&upload.job.as_ref().unwrap().id)
+            .is_none_or(|job| job.status == jobs::Status::Pending)
+        {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        upload.id
+    }
+
+    /// the decode slots are shared with every other test running.
+    fn unless_busy(respond: impl Fn() -> rouille::Response) -> rouille::Response {
+        for _ in 0..100 {
+            let response = respond();
+            if response.status_code != 503 {
+                return response;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        respond()
+    }
+
+    fn signed_urls() -> auth::Settings {
+        auth::Settings {
+            signed_urls: Some(true),
+            url_signing_secret: Some(config::Secret::new(SECRET)),
+            ..auth::Settings::default()
+        }
+    }
+
+    fn server_private(test: &str) -> Server {
+        server(
+            test,
+            auth::Settings {
+                private: Some(true),
+                ..auth::Settings::default()
+            },
+        )
+    }
+
+    /// a `POST` of `files` parts, the way a browser or `curl -F` sends them.
+    fn multipart_upload(url: &str, files: &[(&str, &[u8])]) -> rouille::Request {
+        let mut body = vec![];
+        for (filename, data) in files {
+            body.extend(
+                format!(
+                    "--XbX\r\nContent-Disposition: form-data; name=\"files\"; filename=\"{filename}\"\r\n\r\n"
+                )
+                .bytes(),
+            );
+            body.extend(*data);
+            body.extend(b"\r\n");
+        }
+        body.extend(b"--XbX--\r\n");
+        let bearer = format!("Bearer {ALICE}");
+        let headers = [
+            ("Content-Type", "multipart/form-data; boundary=XbX"),
+            ("Authorization", bearer.as_str()),
+        ];
+        request("POST", url, &headers, &body)
+    }
+
+    fn header<'a>(response: &'a rouille::Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_ref())
+    }
+
+    fn body(response: rouille::Response) -> Vec<u8> {
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn a_signature_does_not_cover_a_post_body() {
+        let server = server("post-body", signed_urls());
+        let (storage, auth) = (server.storage.as_ref(), &server.auth);
+        let id = upload(&server, &png(32, 32), Some("alice"));
+        let name = format!("{id}.png");
+        let signed = signed_link(auth, &format!("/img/{name}?w=16&h=16"));
+
+        let response = unless_busy(|| {
+            let get = request("GET", &signed, &[], b"");
+            img_ctrl(&get, storage, &server.config, auth, &name)
+        });
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            header(&response, "Cache-Control"),
+            Some(http_cache::PRIVATE_IMMUTABLE)
+        );
+        let thumbnail = image::load_from_memory(&body(response)).unwrap();
+        assert_eq!((thumbnail.width(), thumbnail.height()), (16, 16));
+
+        //? the same link, asking for something else in the body
+        let json = br#"{"w": 64, "h": 64}"#;
+        let post = request("POST", &signed, &[], json);
+        assert_eq!(img_post_ctrl(&post, storage, auth, &name).status_code, 401);
+        let unsigned = request("POST", &format!("/img/{name}"), &[], json);
+        assert_eq!(
+            img_post_ctrl(&unsigned, storage, auth, &name).status_code,
+            401
+        );
+
+        let bearer = format!("Bearer {ALICE}");
+        let response = unless_busy(|| {
+            let post = request("POST", &signed, &[("Authorization", &bearer)], json);
+            img_post_ctrl(&post, storage, auth, &name)
+        });
+        assert_eq!(response.status_code, 200);
+        let render = image::load_from_memory(&body(response)).unwrap();
+        assert_eq!((render.width(), render.height()), (64, 64));
+    }
+
+    #[test]
+    fn a_minted_link_without_format_keeps_negotiating() {
+        let server = server("signed-url", signed_urls());
+        let (storage, auth) = (server.storage.as_ref(), &server.auth);
+        let id = upload(&server, &png(32, 32), Some("alice"));
+        let bearer = format!("Bearer {ALICE}");
+        let mint = |query: &str| {
+            let url = format!("/api/images/{id}/signed-url?{query}");
+            let req = request("GET", &url, &[("Authorization", &bearer)], b"");
+            let response = api_signed_url_ctrl(&req, storage, auth, &id);
+            let status = response.status_code;
+            let bytes = body(response);
+            assert_eq!(status, 200, "{}", String::from_utf8_lossy(&bytes));
+            let minted: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+            minted["url"].as_str().unwrap().to_string()
+        };
+
+        assert!(mint("w=16&h=16&format=webp").starts_with(&format!("/img/{id}.webp?w=16&h=16&")));
+        let link = mint("w=16&h=16");
+        assert!(link.starts_with(&format!("/img/{id}?w=16&h=16&")));
+        for (accept, mime) in [("image/webp", "image/webp"), ("image/jpeg", "image/jpeg")] {
+            let response = unless_busy(|| {
+                let get = request("GET", &link, &[("Accept", accept)], b"");
+                img_ctrl(&get, storage, &server.config, auth, &id)
+            });
+            assert_eq!(response.status_code, 200);
+            assert_eq!(header(&response, "Content-Type"), Some(mime));
+            assert_eq!(header(&response, "Vary"), Some("Accept"));
+        }
+    }
+
+    #[test]
+    fn only_open_reads_are_cached_publicly() {
+        let server = server("cache-control", auth::Settings::default());
+        let (storage, auth) = (server.storage.as_ref(), &server.auth);
+        let id = upload(&server, &png(32, 32), Some("alice"));
+        let name = format!("{id}.png");
+        let response = unless_busy(|| {
+            let get = request("GET", &format!("/img/{name}?w=16&h=16"), &[], b"");
+            img_ctrl(&get, storage, &server.config, auth, &name)
+        });
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            header(&response, "Cache-Control"),
+            Some(http_cache::IMMUTABLE)
+        );
+
+        let server = server_private("cache-control-private");
+        let (storage, auth) = (server.storage.as_ref(), &server.auth);
+        let id = upload(&server, &png(32, 32), Some("alice"));
+        let presets = Presets::parse("[presets.small]\nwidth = 8\nheight = 8").unwrap();
+        let bearer = format!("Bearer {ALICE}");
+        let response = unless_busy(|| {
+            let get = request("GET", "/", &[("Authorization", &bearer)], b"");
+            preset_ctrl(&get, storage, auth, &id, "small", &presets)
+        });
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            header(&response, "Cache-Control"),
+            Some(http_cache::PRIVATE_REVALIDATE)
+        );
+    }
+
+    #[test]
+    fn revalidates_from_the_stored_etag() {
+        let server = server("etag", auth::Settings::default());
+        let (storage, auth) = (server.storage.as_ref(), &server.auth);
+        let id = upload(&server, &png(32, 32), None);
+        let presets = Presets::parse("[presets.small]\nwidth = 8\nheight = 8").unwrap();
+        let get = |headers: &[(&str, &str)]| {
+            unless_busy(|| {
+                let get = request("GET", "/", headers, b"");
+                preset_ctrl(&get, storage, auth, &id, "small", &presets)
+            })
+        };
+
+        let response = get(&[]);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(header(&response, "Last-Modified"), None);
+        let etag = header(&response, "ETag").unwrap().to_string();
+        let cached = store::variant_key(&id, &presets.get("small").unwrap().variant.key());
+        assert_eq!(
+            storage.read(&store::etag_key(&cached)).unwrap(),
+            etag.as_bytes()
+        );
+
+        //? a 304 without the variant proves it was not read
+        storage.delete(&cached).unwrap();
+        let response = get(&[("If-None-Match", &etag)]);
+        assert_eq!(response.status_code, 304);
+        assert_eq!(header(&response, "ETag"), Some(etag.as_str()));
+        let response = get(&[("If-None-Match", "\"stale\"")]);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(header(&response, "ETag"), Some(etag.as_str()));
+    }
+
+    #[test]
+    fn only_an_owner_deletes() {
+        let server = server("delete", auth::Settings::default());
+        let (storage, auth) = (server.storage.as_ref(), &server.auth);
+        let index = similar::Index::open(storage, server.config.data_dir.join("delete-index.json"))
+            .unwrap();
+        let id = upload(&server, &png(32, 32), Some("alice"));
+        let url = format!("/api/images/{id}");
+        let delete = |headers: &[(&str, &str)]| {
+            let req = request("DELETE", &url, headers, b"");
+            api_delete_ctrl(&req, storage, auth, &index, &id).status_code
+        };
+
+        assert_eq!(delete(&[]), 401);
+        assert!(storage.exists(&store::original_key(&id)).unwrap());
+        let bearer = format!("Bearer {ALICE}");
+        assert_eq!(delete(&[("Authorization", &bearer)]), 204);
+        assert!(!storage.exists(&store::original_key(&id)).unwrap());
+        assert!(!storage.exists(&record::key(&id)).unwrap());
+        assert!(storage
+            .list(&store::variants_prefix(&id))
+            .unwrap()
+            .is_empty());
+        assert_eq!(delete(&[("Authorization", &bearer)]), 404);
+    }
+
+    #[test]
+    fn a_resumable_upload_ends_in_the_pipeline() {
+        let server = server("tus", auth::Settings::default());
+        let (storage, auth, config) = (server.storage.as_ref(), &server.auth, &server.config);
+        let uploads = throttle::RateLimiter::new(600, 100);
+        let bearer = format!("Bearer {ALICE}");
+        let tus = |method: &str, url: &str, headers: &[(&str, &str)], body: &[u8]| {
+            let mut all = vec![
+                ("Tus-Resumable", tus::TUS_VERSION),
+                ("Authorization", &bearer),
+            ];
+            all.extend(headers);
+            request(method, url, &all, body)
+        };
+        let create = |length: usize| {
+            let length = length.to_string();
+            let req = tus("POST", "/uploads", &[("Upload-Length", &length)], b"");
+            let response = tus_create_ctrl(&req, config, &uploads, auth);
+            assert_eq!(response.status_code, 201);
+            let location = header(&response, "Location").unwrap();
+            location.strip_prefix("/uploads/").unwrap().to_string()
+        };
+        let patch = |id: &str, offset: usize, headers: &[(&str, &str)], body: &[u8]| {
+            let offset = offset.to_string();
+            let mut all = vec![
+                ("Content-Type", "application/offset+octet-stream"),
+                ("Upload-Offset", offset.as_str()),
+            ];
+            all.extend(headers);
+            let req = tus("PATCH", &format!("/uploads/{id}"), &all, body);
+            let presets = Presets::default();
+            tus_patch_ctrl(&req, storage, config, &presets, &server.queue, auth, id)
+        };
+        let head = |id: &str| {
+            let req = tus("HEAD", &format!("/uploads/{id}"), &[], b"");
+            tus_head_ctrl(&req, config, auth, id)
+        };
+
+        let bytes = png(24, 24);
+        let half = bytes.len() / 2;
+        let id = create(bytes.len());
+        let response = patch(&id, 0, &[], &bytes[..half]);
+        assert_eq!(response.status_code, 204);
+        assert_eq!(
+            header(&response, "Upload-Offset"),
+            Some(half.to_string().as_str())
+        );
+        let response = head(&id);
+        assert_eq!(response.status_code, 200);
+        assert_eq!(
+            header(&response, "Upload-Offset"),
+            Some(half.to_string().as_str())
+        );
+
+        assert_eq!(patch(&id, 0, &[], &bytes[..half]).status_code, 409);
+        //? one byte too many, announced and not
+        let mut over = bytes[half..].to_vec();
+        over.push(0);
+        let length = over.len().to_string();
+        assert_eq!(
+            patch(&id, half, &[("Content-Length", &length)], &over).status_code,
+            413
+        );
+        assert_eq!(patch(&id, half, &[], &over).status_code, 413);
+        assert_eq!(
+            header(&head(&id), "Upload-Offset"),
+            Some(half.to_string().as_str())
+        );
+
+        let response = patch(&id, half, &[], &bytes[half..]);
+        assert_eq!(response.status_code, 204);
+        let image_id = store::content_id(&bytes);
+        assert_eq!(header(&response, "Image-Id"), Some(image_id.as_str()));
+        assert_eq!(
+            storage.read(&store::original_key(&image_id)).unwrap(),
+            bytes
+        );
+        assert!(record::load(storage, &image_id)
+            .unwrap()
+            .is_owned_by("alice"));
+        assert_eq!(head(&id).status_code, 404);
+
+        //? what the validation rejects is dropped as well
+        let junk = b"not an image at all";
+        let id = create(junk.len());
+        let response = patch(&id, 0, &[], junk);
+        assert!((400..500).contains(&response.status_code));
+        assert_eq!(head(&id).status_code, 404);
+
+        let id = create(bytes.len());
+        assert_eq!(patch(&id, 0, &[], &bytes[..half]).status_code, 204);
+        let req = tus("DELETE", &format!("/uploads/{id}"), &[], b"");
+        assert_eq!(tus_delete_ctrl(&req, config, auth, &id).status_code, 204);
+        assert_eq!(head(&id).status_code, 404);
+    }
+
+    #[test]
+    fn the_same_bytes_are_stored_once() {
+        let server = server("dedup", auth::Settings::default());
+        let storage = server.storage.as_ref();
+        let bytes = png(20, 20);
+        let id = upload(&server, &bytes, Some("alice"));
+        let originals = storage.list(&format!("{}/", store::ORIGINALS_DIR)).unwrap();
+
+        let again =
+            upload::process(storage, &bytes, &[], false, Some("bob"), &server.queue).unwrap();
+        assert_eq!(again.id, id);
+        assert!(again.duplicate);
+        assert_eq!(again.original.url, format!("/img/{id}"));
+        assert_eq!(
+            storage
+                .list(&format!("{}/", store::ORIGINALS_DIR))
+                .unwrap()
+                .len(),
+            originals.len()
+        );
+        let record = record::load(storage, &id).unwrap();
+        assert_eq!(record.owners, ["alice", "bob"]);
+
+        //? who else uploaded it is nobody's business
+        let req = request("GET", &format!("/api/images/{id}"), &[], b"");
+        let info = body(api_image_ctrl(&req, storage, &server.auth, &id));
+        let info: serde_json::Value = serde_json::from_slice(&info).unwrap();
+        assert_eq!(info["id"], id.as_str());
+        assert!(info.get("owners").is_none());
+    }
+
+    #[test]
+    fn each_file_gets_its_own_result() {
+        let server = server("api-upload", auth::Settings::default());
+        let (storage, auth, config) = (server.storage.as_ref(), &server.auth, &server.config);
+        let (presets, uploads) = (Presets::default(), throttle::RateLimiter::new(600, 100));
+        let post = |req: &rouille::Request| {
+            let response = api_upload_ctrl(
+                req,
+                storage,
+                config,
+                &presets,
+                &server.queue,
+                &uploads,
+                auth,
+            );
+            let status = response.status_code;
+            let json: serde_json::Value = serde_json::from_slice(&body(response)).unwrap();
+            (status, json)
+        };
+
+        let good = png(16, 16);
+        let req = multipart_upload(
+            "/api/images",
+            &[("a.png", &good), ("", b""), ("b.txt", b"not an image")],
+        );
+        let (status, json) = post(&req);
+        assert_eq!(status, 200);
+        let files = json["files"].as_array().unwrap();
+        assert_eq!(files.len(), 3);
+        assert_eq!(files[0]["filename"], "a.png");
+        assert_eq!(files[0]["image"]["id"], store::content_id(&good));
+        assert_eq!(files[0]["image"]["original"]["width"], 16);
+        assert!(files[0].get("error").is_none());
+        assert_eq!(files[1]["error"]["code"], "empty_file");
+        assert_eq!(files[2]["index"], 2);
+        assert!(files[2]["error"]["code"].is_string());
+        assert!(files[2].get("image").is_none());
+
+        //? what a form with no file picked sends, this used to panic
+        let req = multipart_upload("/api/images", &[("", b""), ("", b"")]);
+        let (status, json) = post(&req);
+        assert_eq!(status, 200);
+        for file in json["files"].as_array().unwrap() {
+            assert_eq!(file["error"]["code"], "empty_file");
+        }
+        let req = multipart_upload("/upload", &[("", b""), ("", b"")]);
+        let response = upload_ctrl(
+            &req,
+            storage,
+            config,
+            &presets,
+            &server.queue,
+            &uploads,
+            auth,
+        );
+        assert_eq!(response.status_code, 400);
+    }
+
+    #[test]
+    fn a_full_queue_keeps_the_upload() {
+        let server = server("queue-full", auth::Settings::default());
+        let storage = server.storage.as_ref();
+        let (queue, _receiver) =
+            jobs::Queue::paused(server.config.data_dir.join(jobs::JOBS_DIR), 0);
+        let bytes = png(12, 12);
+
+        let stored = upload::process(storage, &bytes, &[], false, Some("alice"), &queue).unwrap();
+        assert_eq!(stored.id, store::content_id(&bytes));
+        assert!(stored.job.is_none());
+        let failure = stored.job_error.as_ref().unwrap();
+        assert_eq!((failure.code, failure.retryable), ("queue_full", true));
+        assert!(storage.exists(&store::original_key(&stored.id)).unwrap());
+        assert!(record::load(storage, &stored.id)
+            .unwrap()
+            .is_owned_by("alice"));
+
+        //? retrying is uploading the same bytes again
+        let id = upload(&server, &bytes, Some("alice"));
+        assert_eq!(id, stored.id);
+    }
+}
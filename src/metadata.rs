@@ -0,0 +1,240 @@
+use std::io::Cursor;
+
+use exif::{In, Tag};
+use image::{
+    codecs::{
+        bmp::BmpDecoder, gif::GifDecoder, jpeg::JpegDecoder, png::PngDecoder, webp::WebPDecoder,
+    },
+    ColorType, DynamicImage, ImageDecoder, ImageFormat,
+};
+use serde::Serialize;
+
+use crate::placeholder::Placeholder;
+use crate::record::Record;
+use crate::validate;
+
+/// the EXIF fields we expose, everything else stays private.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ExifSummary {
+    pub camera_make: Option<String>,
+    pub camera_model: Option<String>,
+    pub taken_at: Option<String>,
+    pub orientation: Option<u16>,
+    pub has_gps: bool,
+}
+
+fn text(exif: &exif::Exif, tag: Tag) -> Option<String> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+    let value = match &field.value {
+        exif::Value::Ascii(parts) => parts
+            .iter()
+            .map(|part| String::from_utf8_lossy(part).trim().to_string())
+            .collect::<Vec<_>>()
+            .join(" "),
+        _ => field.display_value().to_string(),
+    };
+    (!value.is_empty()).then_some(value)
+}
+
+/// `None` when the container has no EXIF block (or we cannot read it).
+pub fn read_exif(bytes: &[u8]) -> Option<ExifSummary> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut Cursor::new(bytes))
+        .ok()?;
+    let has_gps = exif.fields().any(|f| f.tag.context() == exif::Context::Gps);
+    Some(ExifSummary {
+        camera_make: text(&exif, Tag::Make),
+        camera_model: text(&exif, Tag::Model),
+        taken_at: text(&exif, Tag::DateTimeOriginal).or_else(|| text(&exif, Tag::DateTime)),
+        orientation: exif
+            .get_field(Tag::Orientation, In::PRIMARY)
+            .and_then(|f| f.value.get_uint(0))
+            .map(|o| o as u16),
+        has_gps,
+    })
+}
+
+pub fn orientation(bytes: &[u8]) -> u16 {
+    read_exif(bytes)
+        .and_then(|summary| summary.orientation)
+        .filter(|o| (1..=8).contains(o))
+        .unwrap_or(1)
+}
+
+/// turns the pixels the way the camera meant them to be seen.
+pub fn apply_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// removes EXIF/XMP/text metadata without touching the pixel data.
+/// formats we do not know how to walk come back unchanged.
+pub fn strip(bytes: &[u8], format: ImageFormat) -> Vec<u8> {
+    let stripped = match format {
+        ImageFormat::Jpeg => strip_jpeg(bytes),
+        ImageFormat::Png => strip_png(bytes),
+        ImageFormat::WebP => strip_webp(bytes),
+        _ => None,
+    };
+    stripped.unwrap_or_else(|| bytes.to_vec())
+}
+
+/// drops APP1 (EXIF, XMP), APP13 (IPTC) and comments, keeps ICC and the rest.
+fn strip_jpeg(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = bytes.get(..2)?.to_vec();
+    let mut pos = 2;
+    loop {
+        if *bytes.get(pos)? != 0xFF {
+            return None;
+        }
+        let marker = *bytes.get(pos + 1)?;
+        if marker == 0xFF {
+            pos += 1;
+            continue;
+        }
+        //? start of scan: the entropy coded data runs to the end, copy it as is
+        if marker == 0xDA || marker == 0xD9 {
+            out.extend_from_slice(&bytes[pos..]);
+            return Some(out);
+        }
+        let len = u16::from_be_bytes([*bytes.get(pos + 2)?, *bytes.get(pos + 3)?]) as usize;
+        let end = pos + 2 + len;
+        let segment = bytes.get(pos..end)?;
+        if !matches!(marker, 0xE1 | 0xED | 0xFE) {
+            out.extend_from_slice(segment);
+        }
+        pos = end;
+    }
+}
+
+fn strip_png(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut out = bytes.get(..8)?.to_vec();
+    let mut pos = 8;
+    while pos < bytes.len() {
+        let len = u32::from_be_bytes(bytes.get(pos..pos + 4)?.try_into().ok()?) as usize;
+        let end = pos + 12 + len;
+        let chunk = bytes.get(pos..end)?;
+        if !matches!(
+            &chunk[4..8],
+            b"eXIf" | b"tEXt" | b"zTXt" | b"iTXt" | b"tIME"
+        ) {
+            out.extend_from_slice(chunk);
+        }
+        pos = end;
+    }
+    Some(out)
+}
+
+fn strip_webp(bytes: &[u8]) -> Option<Vec<u8>> {
+    let mut body = b"WEBP".to_vec();
+    let mut pos = 12;
+    while pos < bytes.len() {
+        let len = u32::from_le_bytes(bytes.get(pos + 4..pos + 8)?.try_into().ok()?) as usize;
+        let end = (pos + 8 + len + (len & 1)).min(bytes.len());
+        let chunk = bytes.get(pos..end)?;
+        match &chunk[0..4] {
+            b"EXIF" | b"XMP " => {}
+            b"VP8X" => {
+                let mut chunk = chunk.to_vec();
+                //? clear the "has EXIF" and "has XMP" flags
+                *chunk.get_mut(8)? &= !(0x08 | 0x04);
+                body.extend_from_slice(&chunk);
+            }
+            _ => body.extend_from_slice(chunk),
+        }
+        pos = end;
+    }
+    let mut out = b"RIFF".to_vec();
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&body);
+    Some(out)
+}
+
+fn color_type(bytes: &[u8], format: ImageFormat) -> image::ImageResult<ColorType> {
+    let cursor = Cursor::new(bytes);
+    Ok(match format {
+        ImageFormat::Png => PngDecoder::new(cursor)?.color_type(),
+        ImageFormat::Jpeg => JpegDecoder::new(cursor)?.color_type(),
+        ImageFormat::Gif => GifDecoder::new(cursor)?.color_type(),
+        ImageFormat::WebP => WebPDecoder::new(cursor)?.color_type(),
+        ImageFormat::Bmp => BmpDecoder::new(cursor)?.color_type(),
+        _ => image::load_from_memory_with_format(bytes, format)?.color(),
+    })
+}
+
+/// what `GET /api/images/{id}` answers.
+#[derive(Debug, Serialize)]
+pub struct ImageInfo {
+    pub id: String,
+    pub format: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub color_type: String,
+    /// bits per channel.
+    pub bit_depth: u16,
+    pub bytes: usize,
+    pub uploaded_at: Option<u64>,
+    pub metadata_stripped: bool,
+    pub exif: Option<ExifSummary>,
+    /// absent until the upload's job has run.
+    pub placeholder: Option<Placeholder>,
+}
+
+impl ImageInfo {
+    pub fn read(id: &str, bytes: &[u8], record: Option<&Record>) -> image::ImageResult<Self> {
+        let reader = image::io::Reader::new(Cursor::new(bytes)).with_guessed_format()?;
+        let format = reader.format();
+        let (width, height) = reader.into_dimensions()?;
+        //? `into_dimensions` succeeded, so the format is known
+        let format = format.unwrap_or(ImageFormat::Png);
+        let color = color_type(bytes, format)?;
+        Ok(Self {
+            id: id.to_string(),
+            format: validate::format_name(format),
+            width,
+            height,
+            color_type: format!("{color:?}"),
+            bit_depth: color.bits_per_pixel() / color.channel_count() as u16,
+            bytes: bytes.len(),
+            uploaded_at: record.map(|r| r.uploaded_at),
+            metadata_stripped: record.is_some_and(|r| r.metadata_stripped),
+            exif: read_exif(bytes),
+            placeholder: record.and_then(|r| r.placeholder.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn strips_jpeg_app1_but_keeps_pixels() {
+        let img = DynamicImage::new_rgb8(8, 8);
+        let mut jpeg = vec![];
+        img.write_to(
+            &mut Cursor::new(&mut jpeg),
+            image::ImageOutputFormat::Jpeg(90),
+        )
+        .unwrap();
+        //? splice a fake EXIF APP1 right after SOI
+        let exif = b"Exif\0\0II*\0\x08\0\0\0\0\0";
+        let mut with_exif = jpeg[..2].to_vec();
+        with_exif.extend_from_slice(&[0xFF, 0xE1]);
+        with_exif.extend_from_slice(&((exif.len() + 2) as u16).to_be_bytes());
+        with_exif.extend_from_slice(exif);
+        with_exif.extend_from_slice(&jpeg[2..]);
+
+        let stripped = strip(&with_exif, ImageFormat::Jpeg);
+        assert_eq!(stripped, jpeg);
+        assert!(image::load_from_memory(&stripped).is_ok());
+    }
+}
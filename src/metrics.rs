@@ -0,0 +1,379 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    sync::{Mutex, MutexGuard, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::{storage::Storage, store};
+
+/// seconds, from a cached variant to a slow on-demand render.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+/// seconds, a thumbnail resize is milliseconds, a huge decode can take a while.
+const IMAGE_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0];
+/// listing the whole store on every scrape would be too much for S3.
+const USAGE_TTL: Duration = Duration::from_secs(60);
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+struct Histogram {
+    bounds: &'static [f64],
+    /// one per bound, not cumulative, `+Inf` is `count`.
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            bounds,
+            counts: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(at) = self.bounds.iter().position(|bound| secs <= *bound) {
+            self.counts[at] += 1;
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+
+    /// `labels` are already formatted, `route="/",status="200"`.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let sep = if labels.is_empty() { "" } else { "," };
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(&self.counts) {
+            cumulative += count;
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{{labels}{sep}le=\"{bound}\"}} {cumulative}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "{name}_bucket{{{labels}{sep}le=\"+Inf\"}} {}",
+            self.count
+        );
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{labels}}}")
+        };
+        let _ = writeln!(out, "{name}_sum{labels} {}", self.sum);
+        let _ = writeln!(out, "{name}_count{labels} {}", self.count);
+    }
+}
+
+/// what image work is timed.
+#[derive(Debug, Clone, Copy)]
+pub enum Stage {
+    Decode,
+    Resize,
+}
+
+struct Registry {
+    /// (method, route, status).
+    requests: BTreeMap<(&'static str, &'static str, u16), u64>,
+    /// (route, status).
+    latency: BTreeMap<(&'static str, u16), Histogram>,
+    uploads: u64,
+    upload_bytes: u64,
+    decode: Histogram,
+    resize: Histogram,
+}
+
+/// objects and bytes under one of the storage prefixes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Usage {
+    pub dir: &'static str,
+    pub objects: u64,
+    pub bytes: u64,
+}
+
+struct Metrics {
+    registry: Mutex<Registry>,
+    usage: Mutex<Option<(Instant, Vec<Usage>)>>,
+}
+
+fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics {
+        registry: Mutex::new(Registry {
+            requests: BTreeMap::new(),
+            latency: BTreeMap::new(),
+            uploads: 0,
+            upload_bytes: 0,
+            decode: Histogram::new(&IMAGE_BUCKETS),
+            resize: Histogram::new(&IMAGE_BUCKETS),
+        }),
+        usage: Mutex::new(None),
+    })
+}
+
+fn registry() -> MutexGuard<'static, Registry> {
+    metrics()
+        .registry
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// the route pattern a path was served by, ids would make a series per image.
+pub fn route(path: &str) -> &'static str {
+    let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+    match segments.as_slice() {
+        [""] => "/",
+        ["upload"] => "/upload",
+        ["uploads"] => "/uploads",
+        ["uploads", _] => "/uploads/{id}",
+        ["api", "images"] => "/api/images",
+        ["api", "images", _] => "/api/images/{id}",
+        ["api", "images", _, "signed-url"] => "/api/images/{id}/signed-url",
+        ["api", "images", _, "similar"] => "/api/images/{id}/similar",
+        ["api", "jobs", _] => "/api/jobs/{id}",
+        ["admin", "sweep"] => "/admin/sweep",
+        ["img", _] => "/img/{name}",
+        ["img", _, _] => "/img/{id}/{preset}",
+        ["metrics"] => "/metrics",
+        ["healthz"] => "/healthz",
+        ["readyz"] => "/readyz",
+        _ => "unmatched",
+    }
+}
+
+/// anything a client makes up counts as `other`.
+fn method(method: &str) -> &'static str {
+    match method {
+        "GET" => "GET",
+        "HEAD" => "HEAD",
+        "POST" => "POST",
+        "PUT" => "PUT",
+        "PATCH" => "PATCH",
+        "DELETE" => "DELETE",
+        "OPTIONS" => "OPTIONS",
+        _ => "other",
+    }
+}
+
+pub fn record_request(req_method: &str, path: &str, status: u16, elapsed: Duration) {
+    let route = route(path);
+    let mut registry = registry();
+    *registry
+        .requests
+        .entry((method(req_method), route, status))
+        .or_default() += 1;
+    registry
+        .latency
+        .entry((route, status))
+        .or_insert_with(|| Histogram::new(&LATENCY_BUCKETS))
+        .observe(elapsed);
+}
+
+/// one uploaded file, counted once it is fully received.
+pub fn record_upload(bytes: u64) {
+    let mut registry = registry();
+    registry.uploads += 1;
+    registry.upload_bytes += bytes;
+}
+
+/// runs `work` and adds its duration to the `stage` histogram.
+pub fn timed<T>(stage: Stage, work: impl FnOnce() -> T) -> T {
+    let started = Instant::now();
+    let result = work();
+    let elapsed = started.elapsed();
+    let mut registry = registry();
+    match stage {
+        Stage::Decode => registry.decode.observe(elapsed),
+        Stage::Resize => registry.resize.observe(elapsed),
+    }
+    result
+}
+
+/// recounted at most once per [`USAGE_TTL`].
+pub fn storage_usage(storage: &dyn Storage) -> io::Result<Vec<Usage>> {
+    let mut cached = metrics()
+        .usage
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some((counted, usage)) = cached.as_ref() {
+        if counted.elapsed() < USAGE_TTL {
+            return Ok(usage.clone());
+        }
+    }
+    let mut usage = vec![];
+    for dir in [store::ORIGINALS_DIR, store::VARIANTS_DIR] {
+        let objects = storage.list(&format!("{dir}/"))?;
+        usage.push(Usage {
+            dir,
+            objects: objects.len() as u64,
+            bytes: objects.iter().map(|object| object.size).sum(),
+        });
+    }
+    *cached = Some((Instant::now(), usage.clone()));
+    Ok(usage)
+}
+
+/// sampled at scrape time rather than tracked.
+pub struct Gauges {
+    pub queue_depth: usize,
+    /// `None` when the storage could not be listed.
+    pub storage: Option<Vec<Usage>>,
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// the Prometheus text exposition format, version 0.0.4.
+pub fn render(gauges: &Gauges) -> String {
+    let registry = registry();
+    let mut out = String::new();
+
+    header(
+        &mut out,
+        "http_requests_total",
+        "counter",
+        "Requests served, by method, route and status.",
+    );
+    for ((method, route, status), count) in &registry.requests {
+        let _ = writeln!(
+            out,
+            "http_requests_total{{method=\"{method}\",route=\"{route}\",status=\"{status}\"}} {count}"
+        );
+    }
+    header(
+        &mut out,
+        "http_request_duration_seconds",
+        "histogram",
+        "Time to build a response, by route and status.",
+    );
+    for ((route, status), histogram) in &registry.latency {
+        histogram.render(
+            &mut out,
+            "http_request_duration_seconds",
+            &format!("route=\"{route}\",status=\"{status}\""),
+        );
+    }
+
+    header(
+        &mut out,
+        "uploads_total",
+        "counter",
+        "Files received by the upload endpoints.",
+    );
+    let _ = writeln!(out, "uploads_total {}", registry.uploads);
+    header(
+        &mut out,
+        "upload_bytes_total",
+        "counter",
+        "Bytes of the files received by the upload endpoints.",
+    );
+    let _ = writeln!(out, "upload_bytes_total {}", registry.upload_bytes);
+
+    header(
+        &mut out,
+        "image_decode_seconds",
+        "histogram",
+        "Time to decode an original, every frame of an animation at once.",
+    );
+    registry.decode.render(&mut out, "image_decode_seconds", "");
+    header(
+        &mut out,
+        "image_resize_seconds",
+        "histogram",
+        "Time to resize one image or frame.",
+    );
+    registry.resize.render(&mut out, "image_resize_seconds", "");
+
+    header(
+        &mut out,
+        "jobs_queued",
+        "gauge",
+        "Jobs waiting for a resize worker.",
+    );
+    let _ = writeln!(out, "jobs_queued {}", gauges.queue_depth);
+
+    if let Some(storage) = &gauges.storage {
+        header(
+            &mut out,
+            "storage_objects",
+            "gauge",
+            "Stored objects, by top-level directory.",
+        );
+        for usage in storage {
+            let _ = writeln!(
+                out,
+                "storage_objects{{dir=\"{}\"}} {}",
+                usage.dir, usage.objects
+            );
+        }
+        header(
+            &mut out,
+            "storage_bytes",
+            "gauge",
+            "Stored bytes, by top-level directory.",
+        );
+        for usage in storage {
+            let _ = writeln!(
+                out,
+                "storage_bytes{{dir=\"{}\"}} {}",
+                usage.dir, usage.bytes
+            );
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn renders_the_text_format() {
+        assert_eq!(route("/"), "/");
+        assert_eq!(route("/img/abc.webp"), "/img/{name}");
+        assert_eq!(route("/img/abc/thumb"), "/img/{id}/{preset}");
+        assert_eq!(route("/api/images/abc/similar"), "/api/images/{id}/similar");
+        assert_eq!(route("/wp-login.php"), "unmatched");
+        assert_eq!(method("BREW"), "other");
+
+        let mut histogram = Histogram::new(&[0.1, 1.0]);
+        histogram.observe(Duration::from_millis(50));
+        histogram.observe(Duration::from_millis(500));
+        histogram.observe(Duration::from_secs(3));
+        let mut out = String::new();
+        histogram.render(&mut out, "t", "route=\"/\"");
+        assert_eq!(
+            out,
+            "t_bucket{route=\"/\",le=\"0.1\"} 1\n\
+             t_bucket{route=\"/\",le=\"1\"} 2\n\
+             t_bucket{route=\"/\",le=\"+Inf\"} 3\n\
+             t_sum{route=\"/\"} 3.55\n\
+             t_count{route=\"/\"} 3\n"
+        );
+
+        record_request("GET", "/img/abc.png", 200, Duration::from_millis(3));
+        record_upload(1_234);
+        let text = render(&Gauges {
+            queue_depth: 2,
+            storage: Some(vec![Usage {
+                dir: "public",
+                objects: 1,
+                bytes: 10,
+            }]),
+        });
+        assert!(text
+            .contains("http_requests_total{method=\"GET\",route=\"/img/{name}\",status=\"200\"}"));
+        assert!(text.contains("# TYPE http_request_duration_seconds histogram\n"));
+        assert!(text.contains("jobs_queued 2\n"));
+        assert!(text.contains("storage_bytes{dir=\"public\"} 10\n"));
+        assert!(text.contains("upload_bytes_total 1234\n"));
+    }
+}
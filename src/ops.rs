@@ -0,0 +1,300 @@
+use std::fmt;
+
+use image::{DynamicImage, GenericImageView};
+
+use crate::store;
+
+/// longer pipelines are a 400, every step is another pass over the pixels.
+pub const MAX_OPS: usize = 16;
+const MAX_BLUR: f32 = 50.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    Horizontal,
+    Vertical,
+}
+
+/// one step of `ops=crop:10,10,200,200|rotate:90|grayscale|blur:1.5`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Crop {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    },
+    /// clockwise, 90, 180 or 270.
+    Rotate(u16),
+    Flip(Axis),
+    /// gaussian sigma.
+    Blur(f32),
+    Grayscale,
+    /// added to every channel, -255..=255.
+    Brightness(i32),
+    /// percent, -100..=100.
+    Contrast(f32),
+}
+
+#[derive(Debug)]
+pub enum OpsError {
+    UnknownOp(String),
+    BadArgument { op: &'static str, value: String },
+    TooMany(usize),
+    CropOutside { op: Op, width: u32, height: u32 },
+}
+
+impl fmt::Display for OpsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OpsError::UnknownOp(op) => write!(
+                f,
+                "unknown op {op:?}, expected one of: crop, rotate, flip, blur, grayscale, brightness, contrast"
+            ),
+            OpsError::BadArgument { op, value } => {
+                write!(f, "{op} expects {}, got {value:?}", expected(op))
+            }
+            OpsError::TooMany(count) => {
+                write!(f, "at most {MAX_OPS} ops per image, got {count}")
+            }
+            OpsError::CropOutside { op, width, height } => {
+                write!(f, "{op} falls outside the {width}x{height} image")
+            }
+        }
+    }
+}
+
+impl std::error::Error for OpsError {}
+
+fn expected(op: &str) -> &'static str {
+    match op {
+        "crop" => "x,y,width,height with a width and height of at least 1",
+        "rotate" => "90, 180 or 270",
+        "flip" => "h or v",
+        "blur" => "a sigma above 0 and up to 50",
+        "brightness" => "a whole number between -255 and 255",
+        "contrast" => "a number between -100 and 100",
+        _ => "no argument",
+    }
+}
+
+impl fmt::Display for Op {
+    /// the canonical spelling, which is what the cache key hashes.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Op::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => write!(f, "crop:{x},{y},{width},{height}"),
+            Op::Rotate(degrees) => write!(f, "rotate:{degrees}"),
+            Op::Flip(Axis::Horizontal) => write!(f, "flip:h"),
+            Op::Flip(Axis::Vertical) => write!(f, "flip:v"),
+            Op::Blur(sigma) => write!(f, "blur:{sigma}"),
+            Op::Grayscale => write!(f, "grayscale"),
+            Op::Brightness(amount) => write!(f, "brightness:{amount}"),
+            Op::Contrast(percent) => write!(f, "contrast:{percent}"),
+        }
+    }
+}
+
+impl Op {
+    pub fn parse(step: &str) -> Result<Self, OpsError> {
+        let (name, args) = match step.split_once(':') {
+            Some((name, args)) => (name.trim(), Some(args.trim())),
+            None => (step.trim(), None),
+        };
+        let name = name.to_ascii_lowercase();
+        let op: &'static str = match name.as_str() {
+            "crop" => "crop",
+            "rotate" => "rotate",
+            "flip" => "flip",
+            "blur" => "blur",
+            "grayscale" | "greyscale" => "grayscale",
+            "brightness" => "brightness",
+            "contrast" => "contrast",
+            _ => return Err(OpsError::UnknownOp(name)),
+        };
+        let bad = || OpsError::BadArgument {
+            op,
+            value: args.unwrap_or_default().to_string(),
+        };
+        let number = |value: &str| value.trim().parse::<f32>().ok().filter(|v| v.is_finite());
+
+        match (op, args) {
+            ("grayscale", None) => Ok(Op::Grayscale),
+            ("crop", Some(args)) => {
+                let parts = args
+                    .split(',')
+                    .map(|part| part.trim().parse::<u32>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|_| bad())?;
+                match parts[..] {
+                    [x, y, width, height] if width > 0 && height > 0 => Ok(Op::Crop {
+                        x,
+                        y,
+                        width,
+                        height,
+                    }),
+                    _ => Err(bad()),
+                }
+            }
+            ("rotate", Some(args)) => match args {
+                "90" | "180" | "270" => Ok(Op::Rotate(args.parse().map_err(|_| bad())?)),
+                //? a quarter turn back is three forward
+                "-90" => Ok(Op::Rotate(270)),
+                _ => Err(bad()),
+            },
+            ("flip", Some(args)) => match args.to_ascii_lowercase().as_str() {
+                "h" | "horizontal" => Ok(Op::Flip(Axis::Horizontal)),
+                "v" | "vertical" => Ok(Op::Flip(Axis::Vertical)),
+                _ => Err(bad()),
+            },
+            ("blur", Some(args)) => match number(args) {
+                Some(sigma) if sigma > 0.0 && sigma <= MAX_BLUR => Ok(Op::Blur(sigma)),
+                _ => Err(bad()),
+            },
+            ("brightness", Some(args)) => match args.parse::<i32>() {
+                Ok(amount) if (-255..=255).contains(&amount) => Ok(Op::Brightness(amount)),
+                _ => Err(bad()),
+            },
+            ("contrast", Some(args)) => match number(args) {
+                //? `+ 0.0` turns -0 into 0, both would otherwise hash differently
+                Some(percent) if (-100.0..=100.0).contains(&percent) => {
+                    Ok(Op::Contrast(percent + 0.0))
+                }
+                _ => Err(bad()),
+            },
+            _ => Err(bad()),
+        }
+    }
+
+    fn apply(&self, img: DynamicImage) -> Result<DynamicImage, OpsError> {
+        Ok(match *self {
+            Op::Crop {
+                x,
+                y,
+                width,
+                height,
+            } => {
+                let (w, h) = img.dimensions();
+                if x as u64 + width as u64 > w as u64 || y as u64 + height as u64 > h as u64 {
+                    return Err(OpsError::CropOutside {
+                        op: *self,
+                        width: w,
+                        height: h,
+                    });
+                }
+                img.crop_imm(x, y, width, height)
+            }
+            Op::Rotate(90) => img.rotate90(),
+            Op::Rotate(180) => img.rotate180(),
+            Op::Rotate(_) => img.rotate270(),
+            Op::Flip(Axis::Horizontal) => img.fliph(),
+            Op::Flip(Axis::Vertical) => img.flipv(),
+            Op::Blur(sigma) => img.blur(sigma),
+            Op::Grayscale => img.grayscale(),
+            Op::Brightness(amount) => img.brighten(amount),
+            Op::Contrast(percent) => img.adjust_contrast(percent),
+        })
+    }
+}
+
+/// the steps run in order on the upright original, before any resize.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Ops(Vec<Op>);
+
+impl Ops {
+    /// `|` separated steps, blank steps (a trailing `|`) are skipped.
+    pub fn parse(value: &str) -> Result<Self, OpsError> {
+        let ops = value
+            .split('|')
+            .filter(|step| !step.trim().is_empty())
+            .map(Op::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if ops.len() > MAX_OPS {
+            return Err(OpsError::TooMany(ops.len()));
+        }
+        Ok(Self(ops))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// the pipeline spelled one way only, `Ops::parse` reads it back.
+    pub fn canonical(&self) -> String {
+        self.0
+            .iter()
+            .map(Op::to_string)
+            .collect::<Vec<_>>()
+            .join("|")
+    }
+
+    /// short hash of [`Ops::canonical`], names the cached variant.
+    pub fn key(&self) -> String {
+        store::content_id(self.canonical().as_bytes())[..12].to_string()
+    }
+
+    pub fn apply(&self, img: DynamicImage) -> Result<DynamicImage, OpsError> {
+        self.0.iter().try_fold(img, |img, op| op.apply(img))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_canonically() {
+        let ops = Ops::parse("crop:10, 10,200,200 | Rotate:-90|greyscale|blur:1.50|").unwrap();
+        assert_eq!(
+            ops.canonical(),
+            "crop:10,10,200,200|rotate:270|grayscale|blur:1.5"
+        );
+        assert_eq!(Ops::parse(&ops.canonical()).unwrap(), ops);
+        assert_eq!(
+            ops.key(),
+            Ops::parse("crop:10,10,200,200|rotate:270|grayscale|blur:1.5")
+                .unwrap()
+                .key()
+        );
+        assert_ne!(ops.key(), Ops::parse("blur:1.5").unwrap().key());
+        assert_eq!(Ops::parse("contrast:-0").unwrap().canonical(), "contrast:0");
+
+        assert!(matches!(
+            Ops::parse("sharpen:2"),
+            Err(OpsError::UnknownOp(_))
+        ));
+        for bad in [
+            "crop:1,2,3",
+            "crop:0,0,0,5",
+            "rotate:45",
+            "blur:0",
+            "blur:nan",
+            "flip:x",
+            "grayscale:1",
+            "brightness:300",
+        ] {
+            assert!(
+                matches!(Ops::parse(bad), Err(OpsError::BadArgument { .. })),
+                "{bad}"
+            );
+        }
+        assert!(matches!(
+            Ops::parse(&["grayscale"; MAX_OPS + 1].join("|")),
+            Err(OpsError::TooMany(_))
+        ));
+    }
+
+    #[test]
+    fn applies_in_order() {
+        let img = DynamicImage::new_rgb8(400, 100);
+        let ops = Ops::parse("crop:0,0,300,100|rotate:90|flip:h").unwrap();
+        assert_eq!(ops.apply(img.clone()).unwrap().dimensions(), (100, 300));
+        assert!(matches!(
+            Ops::parse("crop:350,0,100,100").unwrap().apply(img),
+            Err(OpsError::CropOutside { .. })
+        ));
+    }
+}
@@ -0,0 +1,345 @@
+use std::{fmt, fs, io, path::PathBuf, sync::OnceLock};
+
+use ab_glyph::{Font, FontRef, PxScale, ScaleFont};
+use image::{imageops, imageops::FilterType, DynamicImage, Rgba, RgbaImage};
+
+use crate::store;
+
+/// DejaVu Sans, see `assets/fonts/DejaVuSans-LICENSE.txt`.
+static FONT: &[u8] = include_bytes!("../assets/fonts/DejaVuSans.ttf");
+/// longer captions are a 400, they would not fit on a thumbnail anyway.
+pub const MAX_CAPTION: usize = 100;
+
+static WATERMARK: OnceLock<Watermark> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+}
+
+impl Position {
+    pub fn parse(value: &str) -> Option<Self> {
+        Some(match value.trim().to_ascii_lowercase().as_str() {
+            "top-left" => Position::TopLeft,
+            "top" => Position::Top,
+            "top-right" => Position::TopRight,
+            "left" => Position::Left,
+            "center" => Position::Center,
+            "right" => Position::Right,
+            "bottom-left" => Position::BottomLeft,
+            "bottom" => Position::Bottom,
+            "bottom-right" => Position::BottomRight,
+            _ => return None,
+        })
+    }
+
+    /// top left corner of a `mark` sized box inside `canvas`, `margin` away from the edges.
+    fn place(&self, canvas: (u32, u32), mark: (u32, u32), margin: u32) -> (i64, i64) {
+        let free = |outer: u32, inner: u32| outer as i64 - inner as i64;
+        let start = margin as i64;
+        let (w, h) = (free(canvas.0, mark.0), free(canvas.1, mark.1));
+        let x = match self {
+            Position::TopLeft | Position::Left | Position::BottomLeft => start,
+            Position::Top | Position::Center | Position::Bottom => w / 2,
+            _ => w - start,
+        };
+        let y = match self {
+            Position::TopLeft | Position::Top | Position::TopRight => start,
+            Position::Left | Position::Center | Position::Right => h / 2,
+            _ => h - start,
+        };
+        (x, y)
+    }
+}
+
+#[derive(Debug)]
+pub enum OverlayError {
+    Io {
+        path: PathBuf,
+        err: io::Error,
+    },
+    Image {
+        path: PathBuf,
+        err: image::ImageError,
+    },
+    NoWatermark,
+    CaptionTooLong(usize),
+}
+
+impl fmt::Display for OverlayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OverlayError::Io { path, err } => {
+                write!(f, "cannot read watermark {}: {err}", path.display())
+            }
+            OverlayError::Image { path, err } => {
+                write!(f, "watermark {} is not a usable PNG: {err}", path.display())
+            }
+            OverlayError::NoWatermark => write!(f, "no watermark is configured on this server"),
+            OverlayError::CaptionTooLong(len) => {
+                write!(
+                    f,
+                    "captions are at most {MAX_CAPTION} characters, got {len}"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for OverlayError {}
+
+/// where the watermark comes from and how it is laid out, see [`crate::config::Config`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatermarkSettings {
+    pub image: PathBuf,
+    pub position: Position,
+    pub margin: u32,
+    pub opacity: f32,
+    pub scale: f32,
+}
+
+impl WatermarkSettings {
+    /// `image` in the bottom right corner, 16 pixels in, half transparent, a quarter wide.
+    pub fn new(image: PathBuf) -> Self {
+        Self {
+            image,
+            position: Position::BottomRight,
+            margin: 16,
+            opacity: 0.5,
+            scale: 0.25,
+        }
+    }
+}
+
+/// a PNG stamped on variants that ask for it, never on the stored original.
+#[derive(Debug)]
+pub struct Watermark {
+    image: RgbaImage,
+    pub position: Position,
+    /// pixels between the watermark and the nearest edges.
+    pub margin: u32,
+    pub opacity: f32,
+    /// watermark width as a fraction of the output width.
+    pub scale: f32,
+    /// changes with the PNG and every setting, part of the cache key.
+    fingerprint: String,
+}
+
+impl Watermark {
+    pub fn new(
+        png: &[u8],
+        position: Position,
+        margin: u32,
+        opacity: f32,
+        scale: f32,
+    ) -> Result<Self, image::ImageError> {
+        let image = image::load_from_memory_with_format(png, image::ImageFormat::Png)?.to_rgba8();
+        let mut fingerprinted = png.to_vec();
+        fingerprinted
+            .extend_from_slice(format!("{position:?}/{margin}/{opacity}/{scale}").as_bytes());
+        Ok(Self {
+            image,
+            position,
+            margin,
+            opacity,
+            scale,
+            fingerprint: store::content_id(&fingerprinted)[..12].to_string(),
+        })
+    }
+
+    /// reads the PNG `settings` point at.
+    pub fn load(settings: &WatermarkSettings) -> Result<Self, OverlayError> {
+        let path = &settings.image;
+        let png = fs::read(path).map_err(|err| OverlayError::Io {
+            path: path.clone(),
+            err,
+        })?;
+        Self::new(
+            &png,
+            settings.position,
+            settings.margin,
+            settings.opacity,
+            settings.scale,
+        )
+        .map_err(|err| OverlayError::Image {
+            path: path.clone(),
+            err,
+        })
+    }
+
+    pub fn fingerprint(&self) -> &str {
+        &self.fingerprint
+    }
+
+    pub fn apply(&self, canvas: &mut RgbaImage) {
+        let (w, h) = canvas.dimensions();
+        let (mark_w, mark_h) = self.image.dimensions();
+        let width = ((w as f32 * self.scale).round() as u32).clamp(1, w);
+        let height =
+            ((mark_h as u64 * width as u64) / mark_w.max(1) as u64).clamp(1, h as u64) as u32;
+        let mut mark = imageops::resize(&self.image, width, height, FilterType::Triangle);
+        for pixel in mark.pixels_mut() {
+            pixel[3] = (pixel[3] as f32 * self.opacity).round() as u8;
+        }
+        let (x, y) = self.position.place((w, h), (width, height), self.margin);
+        imageops::overlay(canvas, &mark, x, y);
+    }
+}
+
+/// installs the server wide watermark, called once at startup.
+pub fn set_watermark(watermark: Watermark) {
+    let _ = WATERMARK.set(watermark);
+}
+
+pub fn watermark() -> Option<&'static Watermark> {
+    WATERMARK.get()
+}
+
+pub fn check_caption(caption: &str) -> Result<(), OverlayError> {
+    let len = caption.chars().count();
+    if len > MAX_CAPTION {
+        return Err(OverlayError::CaptionTooLong(len));
+    }
+    Ok(())
+}
+
+/// one line of white text on a dark band along the bottom edge,
+/// sized to the image and shrunk further when the text would not fit.
+pub fn draw_caption(canvas: &mut RgbaImage, caption: &str) {
+    let font = FontRef::try_from_slice(FONT).expect("the bundled font parses");
+    let (w, h) = canvas.dimensions();
+    let padding = (h as f32 / 40.0).max(2.0);
+
+    let width_at = |px: f32| {
+        let scaled = font.as_scaled(PxScale::from(px));
+        let mut width = 0.0;
+        let mut previous = None;
+        for c in caption.chars() {
+            let id = scaled.glyph_id(c);
+            if let Some(previous) = previous {
+                width += scaled.kern(previous, id);
+            }
+            width += scaled.h_advance(id);
+            previous = Some(id);
+        }
+        width
+    };
+    let mut px = (h as f32 / 12.0).clamp(8.0, 64.0);
+    let available = w as f32 - 2.0 * padding;
+    let natural = width_at(px);
+    if natural > available && natural > 0.0 {
+        px = (px * available / natural).max(4.0);
+    }
+    let scaled = font.as_scaled(PxScale::from(px));
+
+    let band = (scaled.height() + 2.0 * padding).ceil().min(h as f32) as u32;
+    let top = h - band;
+    for y in top..h {
+        for x in 0..w {
+            blend(canvas.get_pixel_mut(x, y), [0, 0, 0], 0.45);
+        }
+    }
+
+    let baseline = top as f32 + padding + scaled.ascent();
+    let mut x = padding;
+    let mut previous = None;
+    for c in caption.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(previous) = previous {
+            x += scaled.kern(previous, id);
+        }
+        let glyph = id.with_scale_and_position(px, ab_glyph::point(x, baseline));
+        x += scaled.h_advance(id);
+        previous = Some(id);
+        let Some(outline) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outline.px_bounds();
+        outline.draw(|gx, gy, coverage| {
+            let (px, py) = (
+                bounds.min.x as i64 + gx as i64,
+                bounds.min.y as i64 + gy as i64,
+            );
+            if (0..w as i64).contains(&px) && (0..h as i64).contains(&py) {
+                blend(
+                    canvas.get_pixel_mut(px as u32, py as u32),
+                    [255, 255, 255],
+                    coverage,
+                );
+            }
+        });
+    }
+}
+
+fn blend(pixel: &mut Rgba<u8>, color: [u8; 3], alpha: f32) {
+    let alpha = alpha.clamp(0.0, 1.0);
+    for (channel, value) in pixel.0.iter_mut().zip(color) {
+        *channel = (*channel as f32 * (1.0 - alpha) + value as f32 * alpha).round() as u8;
+    }
+    //? transparent pixels under the band and the text become visible
+    pixel[3] = (pixel[3] as f32 + (255.0 - pixel[3] as f32) * alpha).round() as u8;
+}
+
+/// watermark then caption, on the finished (cropped, resized) variant.
+pub fn apply(img: DynamicImage, watermark: bool, caption: Option<&str>) -> DynamicImage {
+    let mark = watermark.then(self::watermark).flatten();
+    if mark.is_none() && caption.is_none() {
+        return img;
+    }
+    let mut canvas = img.to_rgba8();
+    if let Some(mark) = mark {
+        mark.apply(&mut canvas);
+    }
+    if let Some(caption) = caption {
+        draw_caption(&mut canvas, caption);
+    }
+    DynamicImage::ImageRgba8(canvas)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn places_marks_inside_the_margin() {
+        let (canvas, mark) = ((200, 100), (50, 20));
+        assert_eq!(Position::TopLeft.place(canvas, mark, 10), (10, 10));
+        assert_eq!(Position::BottomRight.place(canvas, mark, 10), (140, 70));
+        assert_eq!(Position::Center.place(canvas, mark, 10), (75, 40));
+        assert_eq!(Position::parse("Bottom-Left"), Some(Position::BottomLeft));
+        assert_eq!(Position::parse("middle"), None);
+    }
+
+    #[test]
+    fn stamps_only_the_covered_corner() {
+        let mut png = vec![];
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(10, 10, Rgba([255, 0, 0, 255])))
+            .write_to(
+                &mut io::Cursor::new(&mut png),
+                image::ImageOutputFormat::Png,
+            )
+            .unwrap();
+        let mark = Watermark::new(&png, Position::BottomRight, 0, 1.0, 0.5).unwrap();
+        let mut canvas = RgbaImage::from_pixel(40, 20, Rgba([0, 0, 0, 255]));
+        mark.apply(&mut canvas);
+        assert_eq!(canvas.get_pixel(39, 19), &Rgba([255, 0, 0, 255]));
+        assert_eq!(canvas.get_pixel(0, 0), &Rgba([0, 0, 0, 255]));
+
+        let other = Watermark::new(&png, Position::BottomRight, 0, 0.9, 0.5).unwrap();
+        assert_ne!(mark.fingerprint(), other.fingerprint());
+
+        let mut captioned = RgbaImage::from_pixel(200, 60, Rgba([0, 0, 0, 255]));
+        draw_caption(&mut captioned, "hello");
+        assert!(captioned.pixels().any(|p| p[0] > 128));
+        assert_eq!(captioned.get_pixel(199, 0), &Rgba([0, 0, 0, 255]));
+        assert!(check_caption(&"x".repeat(MAX_CAPTION + 1)).is_err());
+    }
+}
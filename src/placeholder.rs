@@ -0,0 +1,181 @@
+use std::{cmp::Reverse, io::Cursor};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageFormat, Rgba};
+use serde::{Deserialize, Serialize};
+
+/// longest side of the inlined preview, the browser blurs it up to size.
+const LQIP_SIZE: u32 = 16;
+/// the hash only keeps a few cosine terms, more pixels than this change nothing.
+const BLURHASH_SIZE: u32 = 32;
+/// pixels sampled for the palette.
+const PALETTE_SIZE: u32 = 64;
+pub const PALETTE_COLORS: usize = 5;
+
+/// what a page shows while the real image loads, computed once per upload.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Placeholder {
+    /// <https://blurha.sh>, decoded client side.
+    pub blurhash: String,
+    /// a tiny PNG as a `data:` URL, usable straight as `src` or `background-image`.
+    pub lqip: String,
+    /// `#rrggbb`, the dominant color first.
+    pub palette: Vec<String>,
+}
+
+impl Placeholder {
+    /// `img` is the upright original.
+    pub fn compute(img: &DynamicImage) -> image::ImageResult<Self> {
+        Ok(Self {
+            blurhash: blurhash(img),
+            lqip: lqip(img)?,
+            palette: palette(img, PALETTE_COLORS)
+                .into_iter()
+                .map(|[r, g, b]| format!("#{r:02x}{g:02x}{b:02x}"))
+                .collect(),
+        })
+    }
+
+    pub fn dominant_color(&self) -> Option<&str> {
+        self.palette.first().map(String::as_str)
+    }
+}
+
+fn blurhash(img: &DynamicImage) -> String {
+    let small = img.thumbnail(BLURHASH_SIZE, BLURHASH_SIZE).to_rgba8();
+    let (width, height) = small.dimensions();
+    //? one more component along the long side keeps the blobs roughly square
+    let (x, y) = if width >= height { (4, 3) } else { (3, 4) };
+    blurhash::encode(x, y, width, height, small.as_raw())
+        .expect("component counts are within 1..=9")
+}
+
+fn lqip(img: &DynamicImage) -> image::ImageResult<String> {
+    let small = img.resize(LQIP_SIZE, LQIP_SIZE, FilterType::Triangle);
+    let small = if small.color().has_alpha() {
+        DynamicImage::ImageRgba8(small.to_rgba8())
+    } else {
+        DynamicImage::ImageRgb8(small.to_rgb8())
+    };
+    let mut png = vec![];
+    small.write_to(&mut Cursor::new(&mut png), ImageFormat::Png)?;
+    Ok(format!("data:image/png;base64,{}", STANDARD.encode(png)))
+}
+
+/// median cut: keep splitting the box with the widest channel at its median,
+/// then average each box. sorted by how many pixels each color stands for.
+fn palette(img: &DynamicImage, colors: usize) -> Vec<[u8; 3]> {
+    let small = img.thumbnail(PALETTE_SIZE, PALETTE_SIZE);
+    let pixels: Vec<[u8; 3]> = small
+        .pixels()
+        //? mostly transparent pixels are not part of the picture
+        .filter(|(_, _, Rgba([.., a]))| *a >= 128)
+        .map(|(_, _, Rgba([r, g, b, _]))| [r, g, b])
+        .collect();
+    if pixels.is_empty() {
+        return vec![];
+    }
+
+    let mut boxes = vec![pixels];
+    while boxes.len() < colors {
+        let widest = boxes
+            .iter()
+            .enumerate()
+            .map(|(at, pixels)| {
+                let (channel, range) = widest_channel(pixels);
+                (range, at, channel)
+            })
+            .max();
+        let Some((range, at, channel)) = widest else {
+            break;
+        };
+        //? every box is a single color already
+        if range == 0 {
+            break;
+        }
+        let mut lower = boxes.swap_remove(at);
+        lower.sort_unstable_by_key(|pixel| pixel[channel]);
+        let upper = lower.split_off(lower.len() / 2);
+        boxes.push(lower);
+        boxes.push(upper);
+    }
+    //? a color split across boxes counts once, with all of its pixels
+    let mut counted: Vec<(usize, [u8; 3])> = vec![];
+    for pixels in &boxes {
+        let color = average(pixels);
+        match counted.iter_mut().find(|(_, known)| *known == color) {
+            Some((count, _)) => *count += pixels.len(),
+            None => counted.push((pixels.len(), color)),
+        }
+    }
+    counted.sort_by_key(|&(count, _)| Reverse(count));
+    counted.into_iter().map(|(_, color)| color).collect()
+}
+
+fn widest_channel(pixels: &[[u8; 3]]) -> (usize, u8) {
+    (0..3)
+        .map(|channel| {
+            let values = pixels.iter().map(|pixel| pixel[channel]);
+            let range = values.clone().max().unwrap_or(0) - values.min().unwrap_or(0);
+            (channel, range)
+        })
+        .max_by_key(|&(_, range)| range)
+        .unwrap_or((0, 0))
+}
+
+fn average(pixels: &[[u8; 3]]) -> [u8; 3] {
+    let mut sum = [0u64; 3];
+    for pixel in pixels {
+        for (total, value) in sum.iter_mut().zip(pixel) {
+            *total += *value as u64;
+        }
+    }
+    let count = pixels.len().max(1) as u64;
+    sum.map(|total| (total / count) as u8)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn finds_the_dominant_colors() {
+        //? three quarters red, one quarter blue
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(200, 100, |x, _| {
+            if x < 150 {
+                Rgb([200, 10, 10])
+            } else {
+                Rgb([10, 10, 200])
+            }
+        }));
+        let placeholder = Placeholder::compute(&img).unwrap();
+        assert_eq!(placeholder.dominant_color(), Some("#c80a0a"));
+        assert!(placeholder.palette.contains(&"#0a0ac8".to_string()));
+        assert!(placeholder.palette.len() <= PALETTE_COLORS);
+        let unique: std::collections::HashSet<_> = placeholder.palette.iter().collect();
+        assert_eq!(unique.len(), placeholder.palette.len());
+
+        let solid = DynamicImage::ImageRgb8(RgbImage::from_pixel(10, 10, Rgb([1, 2, 3])));
+        assert_eq!(palette(&solid, PALETTE_COLORS), [[1, 2, 3]]);
+        assert!(palette(&DynamicImage::new_rgba8(4, 4), PALETTE_COLORS).is_empty());
+    }
+
+    #[test]
+    fn encodes_small_previews() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(300, 200, |x, y| {
+            Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        }));
+        let placeholder = Placeholder::compute(&img).unwrap();
+        //? size flag, max AC, DC, then 2 characters per AC component
+        assert_eq!(placeholder.blurhash.len(), 1 + 1 + 4 + 2 * (4 * 3 - 1));
+
+        let lqip = placeholder
+            .lqip
+            .strip_prefix("data:image/png;base64,")
+            .unwrap();
+        assert!(lqip.len() < 2_048);
+        let preview = image::load_from_memory(&STANDARD.decode(lqip).unwrap()).unwrap();
+        assert_eq!(preview.dimensions(), (16, 11));
+    }
+}
@@ -0,0 +1,204 @@
+use std::{collections::BTreeMap, fmt, fs, io, path::Path};
+
+use serde::Deserialize;
+
+use crate::format::{self, OutputFormat};
+use crate::ops::Ops;
+use crate::overlay::{self, OverlayError};
+use crate::resize::ResizeOptions;
+use crate::variant::{Variant, VariantError};
+
+pub const PRESETS_FILE: &str = "presets.toml";
+
+/// one `[presets.<name>]` table, values use the same vocabulary as the query string.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PresetSpec {
+    width: u32,
+    height: Option<u32>,
+    fit: Option<String>,
+    filter: Option<String>,
+    format: Option<String>,
+    quality: Option<u8>,
+    /// same pipeline as `ops=`, e.g. `"grayscale|blur:1.5"`.
+    ops: Option<String>,
+    /// stamp the server's watermark, see [`overlay::WatermarkSettings`].
+    watermark: Option<bool>,
+    caption: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct PresetFile {
+    #[serde(default)]
+    presets: BTreeMap<String, PresetSpec>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Preset {
+    pub name: String,
+    pub variant: Variant,
+}
+
+#[derive(Debug)]
+pub enum PresetError {
+    Io(io::Error),
+    Toml(toml::de::Error),
+    Invalid { name: String, err: VariantError },
+    BadName(String),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetError::Io(err) => write!(f, "cannot read presets: {err}"),
+            PresetError::Toml(err) => write!(f, "invalid presets file: {err}"),
+            PresetError::Invalid { name, err } => write!(f, "preset `{name}`: {err}"),
+            PresetError::BadName(name) => write!(
+                f,
+                "preset name {name:?} must be lowercase letters, digits, `-` or `_`"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+impl PresetSpec {
+    fn into_variant(self) -> Result<Variant, VariantError> {
+        let param = |name: &str| match name {
+            "w" => Some(self.width.to_string()),
+            "h" => self.height.map(|h| h.to_string()),
+            "fit" => self.fit.clone(),
+            "filter" => self.filter.clone(),
+            _ => None,
+        };
+        let resize = ResizeOptions::from_params(param)?;
+        let format = match &self.format {
+            Some(format) => OutputFormat::parse(format)?,
+            None => OutputFormat::Png,
+        };
+        let quality = match self.quality {
+            Some(q) => format::parse_quality(&q.to_string())?,
+            None => format::DEFAULT_QUALITY,
+        };
+        let ops = match &self.ops {
+            Some(ops) => Ops::parse(ops)?,
+            None => Ops::default(),
+        };
+        let watermark = self.watermark.unwrap_or(false);
+        if watermark && overlay::watermark().is_none() {
+            return Err(OverlayError::NoWatermark.into());
+        }
+        if let Some(caption) = &self.caption {
+            overlay::check_caption(caption)?;
+        }
+        Ok(Variant {
+            resize: Some(resize),
+            ops,
+            watermark,
+            caption: self.caption,
+            frame: None,
+            format,
+            quality,
+            negotiated: false,
+        })
+    }
+}
+
+/// the named thumbnail sizes every upload gets, addressable as `/img/{id}/{preset}`.
+#[derive(Debug, Clone, Default)]
+pub struct Presets(Vec<Preset>);
+
+impl Presets {
+    pub fn parse(toml_text: &str) -> Result<Self, PresetError> {
+        let file: PresetFile = toml::from_str(toml_text).map_err(PresetError::Toml)?;
+        let mut presets = vec![];
+        for (name, spec) in file.presets {
+            if name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_'))
+            {
+                return Err(PresetError::BadName(name));
+            }
+            let variant = spec.into_variant().map_err(|err| PresetError::Invalid {
+                name: name.clone(),
+                err,
+            })?;
+            presets.push(Preset { name, variant });
+        }
+        Ok(Self(presets))
+    }
+
+    /// a missing file falls back to the built-in gallery sizes.
+    pub fn load(path: &Path) -> Result<Self, PresetError> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                println!(">> no {}, using the default presets", path.display());
+                Self::parse(DEFAULT_PRESETS)
+            }
+            Err(err) => Err(PresetError::Io(err)),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Preset> {
+        self.0.iter().find(|preset| preset.name == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Preset> {
+        self.0.iter()
+    }
+}
+
+const DEFAULT_PRESETS: &str = r#"
+[presets.small]
+width = 64
+height = 64
+fit = "cover"
+
+[presets.medium]
+width = 256
+height = 256
+fit = "cover"
+
+[presets.large]
+width = 1024
+fit = "width"
+"#;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_and_rejects_presets() {
+        let presets = Presets::parse(DEFAULT_PRESETS).unwrap();
+        assert_eq!(presets.iter().count(), 3);
+        assert_eq!(
+            presets.get("large").unwrap().variant.resize.unwrap().height,
+            None
+        );
+
+        let webp =
+            Presets::parse("[presets.hero]\nwidth = 640\nheight = 360\nformat = \"webp\"").unwrap();
+        assert_eq!(webp.get("hero").unwrap().variant.format, OutputFormat::WebP);
+
+        assert!(matches!(
+            Presets::parse("[presets.bad]\nwidth = 64\nfit = \"cover\""),
+            Err(PresetError::Invalid { .. })
+        ));
+        assert!(matches!(
+            Presets::parse("[presets.bad]\nwidth = 64\nheight = 64\nops = \"sharpen\""),
+            Err(PresetError::Invalid { .. })
+        ));
+        assert!(matches!(
+            Presets::parse("[presets.x]\nwidth = 64\nheight = 64\nzoom = 2"),
+            Err(PresetError::Toml(_))
+        ));
+        assert!(matches!(
+            Presets::parse("[presets.\"../x\"]\nwidth = 64\nheight = 64"),
+            Err(PresetError::BadName(_))
+        ));
+    }
+}
@@ -0,0 +1,94 @@
+use std::{io, sync::Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::listing;
+use crate::placeholder::Placeholder;
+use crate::storage::Storage;
+use crate::store;
+
+/// what we know about an original besides its bytes,
+/// kept as `public/ab/cd/{id}.json` next to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Record {
+    /// unix seconds of the first upload.
+    pub uploaded_at: u64,
+    /// EXIF orientation (1..=8) read at upload time, survives stripping the EXIF itself.
+    #[serde(default = "upright")]
+    pub orientation: u16,
+    #[serde(default)]
+    pub metadata_stripped: bool,
+    /// more than 1 for animated uploads, which are served as GIF unless a format is asked for.
+    #[serde(default = "still")]
+    pub frames: u32,
+    /// perceptual hash, filled in by the resize job, see [`crate::similar::dhash`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phash: Option<u64>,
+    /// blurhash, preview and palette, filled in with the hash.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placeholder: Option<Placeholder>,
+    /// every owner (see [`crate::auth`]) that uploaded these bytes, empty for anonymous uploads.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub owners: Vec<String>,
+}
+
+impl Record {
+    pub fn is_owned_by(&self, owner: &str) -> bool {
+        self.owners.iter().any(|known| known == owner)
+    }
+}
+
+fn upright() -> u16 {
+    1
+}
+
+fn still() -> u32 {
+    1
+}
+
+pub fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+pub fn key(id: &str) -> String {
+    format!("{}.json", store::original_key(id))
+}
+
+pub fn load(storage: &dyn Storage, id: &str) -> Option<Record> {
+    let bytes = storage.read(&key(id)).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+pub fn save(storage: &dyn Storage, id: &str, record: &Record) -> io::Result<()> {
+    let bytes = serde_json::to_vec_pretty(record)?;
+    storage.put(&key(id), &bytes)
+}
+
+/// loads the record of `id`, lets `change` edit it and saves it if it changed,
+/// along with its entries in the listing, see [`listing::reindex`].
+/// every read-modify-write goes through here, one at a time, so a duplicate upload
+/// adding an owner and a job filling in the hash do not write over each other.
+pub fn update<T>(
+    storage: &dyn Storage,
+    id: &str,
+    change: impl FnOnce(&mut Option<Record>) -> io::Result<T>,
+) -> io::Result<T> {
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    let _guard = LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let before = load(storage, id);
+    let mut record = before.clone();
+    let out = change(&mut record)?;
+    if let Some(record) = &record {
+        let unchanged = before.as_ref().map(serde_json::to_vec).transpose()?
+            == Some(serde_json::to_vec(record)?);
+        if !unchanged {
+            save(storage, id, record)?;
+            listing::reindex(storage, id, before.as_ref(), Some(record))?;
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,338 @@
+use std::fmt;
+
+use image::{imageops::FilterType, DynamicImage, GenericImageView, RgbaImage};
+
+use crate::metrics::{self, Stage};
+
+/// biggest side we are willing to produce, anything above is a 400.
+pub const MAX_DIMENSION: u32 = 4_096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// stretch to `w`x`h`, ignoring the aspect ratio.
+    Exact,
+    /// fit inside `w`x`h` and pad the rest (letterbox).
+    Contain,
+    /// fill `w`x`h` and crop whatever overflows.
+    Cover,
+    /// scale to `w`, the height follows the aspect ratio.
+    Width,
+}
+
+impl Fit {
+    pub fn parse(value: &str) -> Result<Self, ResizeError> {
+        match value.to_ascii_lowercase().as_str() {
+            "exact" => Ok(Fit::Exact),
+            "contain" | "letterbox" => Ok(Fit::Contain),
+            "cover" | "crop" => Ok(Fit::Cover),
+            "width" | "scale-by-width" => Ok(Fit::Width),
+            _ => Err(ResizeError::UnknownFit(value.to_string())),
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fit::Exact => "exact",
+            Fit::Contain => "contain",
+            Fit::Cover => "cover",
+            Fit::Width => "width",
+        }
+    }
+}
+
+fn parse_filter(value: &str) -> Result<FilterType, ResizeError> {
+    match value.to_ascii_lowercase().as_str() {
+        "nearest" => Ok(FilterType::Nearest),
+        "triangle" => Ok(FilterType::Triangle),
+        "catmullrom" | "catmull-rom" => Ok(FilterType::CatmullRom),
+        "lanczos3" | "lanczos" => Ok(FilterType::Lanczos3),
+        _ => Err(ResizeError::UnknownFilter(value.to_string())),
+    }
+}
+
+fn filter_name(filter: FilterType) -> &'static str {
+    match filter {
+        FilterType::Nearest => "nearest",
+        FilterType::Triangle => "triangle",
+        FilterType::CatmullRom => "catmullrom",
+        FilterType::Gaussian => "gaussian",
+        FilterType::Lanczos3 => "lanczos3",
+    }
+}
+
+#[derive(Debug)]
+pub enum ResizeError {
+    NotANumber { field: &'static str, value: String },
+    OutOfRange { field: &'static str, value: u32 },
+    UnknownFit(String),
+    UnknownFilter(String),
+    MissingHeight(Fit),
+    UnexpectedHeight,
+}
+
+impl fmt::Display for ResizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResizeError::NotANumber { field, value } => {
+                write!(f, "`{field}` must be a whole number, got {value:?}")
+            }
+            ResizeError::OutOfRange { field, value } => {
+                write!(f, "`{field}` must be between 1 and {MAX_DIMENSION}, got {value}")
+            }
+            ResizeError::UnknownFit(fit) => write!(
+                f,
+                "unknown fit {fit:?}, expected one of: exact, contain, cover, width"
+            ),
+            ResizeError::UnknownFilter(filter) => write!(
+                f,
+                "unknown filter {filter:?}, expected one of: nearest, triangle, catmullrom, lanczos3"
+            ),
+            ResizeError::MissingHeight(fit) => {
+                write!(f, "fit={} needs both `w` and `h`", fit.as_str())
+            }
+            ResizeError::UnexpectedHeight => {
+                write!(f, "fit=width scales by `w` only, drop `h`")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ResizeError {}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResizeOptions {
+    pub width: u32,
+    /// `None` only for [`Fit::Width`].
+    pub height: Option<u32>,
+    pub fit: Fit,
+    pub filter: FilterType,
+}
+
+impl Default for ResizeOptions {
+    fn default() -> Self {
+        Self {
+            width: 100,
+            height: Some(100),
+            fit: Fit::Cover,
+            filter: FilterType::Triangle,
+        }
+    }
+}
+
+fn parse_dimension(field: &'static str, value: &str) -> Result<u32, ResizeError> {
+    let parsed = value.parse::<u32>().map_err(|_| ResizeError::NotANumber {
+        field,
+        value: value.to_string(),
+    })?;
+    if parsed == 0 || parsed > MAX_DIMENSION {
+        return Err(ResizeError::OutOfRange {
+            field,
+            value: parsed,
+        });
+    }
+    Ok(parsed)
+}
+
+impl ResizeOptions {
+    /// reads `w`, `h`, `fit` and `filter` through `param`,
+    /// blank values count as missing so an untouched form field is fine.
+    pub fn from_params<F>(param: F) -> Result<Self, ResizeError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        Self::from_params_or(param, Self::default())
+    }
+
+    /// like [`Self::from_params`], `defaults` is what is left unset.
+    pub fn from_params_or<F>(param: F, defaults: Self) -> Result<Self, ResizeError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let param = |name: &str| param(name).filter(|v| !v.trim().is_empty());
+
+        let fit = match param("fit") {
+            Some(fit) => Fit::parse(fit.trim())?,
+            None => defaults.fit,
+        };
+        let filter = match param("filter") {
+            Some(filter) => parse_filter(filter.trim())?,
+            None => defaults.filter,
+        };
+        let width = match param("w") {
+            Some(w) => Some(parse_dimension("w", w.trim())?),
+            None => None,
+        };
+        let height = match param("h") {
+            Some(h) => Some(parse_dimension("h", h.trim())?),
+            None => None,
+        };
+
+        let (width, height) = match (fit, width, height) {
+            (Fit::Width, _, Some(_)) => return Err(ResizeError::UnexpectedHeight),
+            (Fit::Width, w, None) => (w.unwrap_or(defaults.width), None),
+            //? nothing given: the default thumbnail
+            (_, None, None) => (defaults.width, defaults.height),
+            (_, Some(w), Some(h)) => (w, Some(h)),
+            (fit, _, _) => return Err(ResizeError::MissingHeight(fit)),
+        };
+
+        Ok(Self {
+            width,
+            height,
+            fit,
+            filter,
+        })
+    }
+
+    /// stable, filename-friendly description, e.g. `320x200-cover-lanczos3`.
+    pub fn key(&self) -> String {
+        let height = match self.height {
+            Some(h) => h.to_string(),
+            None => "auto".to_string(),
+        };
+        format!(
+            "{}x{height}-{}-{}",
+            self.width,
+            self.fit.as_str(),
+            filter_name(self.filter)
+        )
+    }
+
+    /// the query string that parses back into these options.
+    pub fn query(&self) -> String {
+        let mut query = format!("w={}", self.width);
+        if let Some(h) = self.height {
+            query.push_str(&format!("&h={h}"));
+        }
+        format!(
+            "{query}&fit={}&filter={}",
+            self.fit.as_str(),
+            filter_name(self.filter)
+        )
+    }
+
+    pub fn apply(&self, img: &DynamicImage) -> DynamicImage {
+        metrics::timed(Stage::Resize, || self.resize(img))
+    }
+
+    fn resize(&self, img: &DynamicImage) -> DynamicImage {
+        let (w, filter) = (self.width, self.filter);
+        let h = self.height.unwrap_or(w);
+        match self.fit {
+            Fit::Exact => img.resize_exact(w, h, filter),
+            Fit::Cover => {
+                //? crop to the target aspect first, scaling the whole source to
+                //? fill could need a buffer far bigger than `w`x`h`.
+                let (src_w, src_h) = img.dimensions();
+                let (crop_w, crop_h) = if src_w as u64 * h as u64 > src_h as u64 * w as u64 {
+                    ((src_h as u64 * w as u64 / h as u64).max(1) as u32, src_h)
+                } else {
+                    (src_w, (src_w as u64 * h as u64 / w as u64).max(1) as u32)
+                };
+                img.crop_imm((src_w - crop_w) / 2, (src_h - crop_h) / 2, crop_w, crop_h)
+                    .resize_exact(w, h, filter)
+            }
+            Fit::Width => {
+                let (src_w, src_h) = img.dimensions();
+                let (src_w, src_h) = (src_w.max(1) as u64, src_h as u64);
+                let h = (src_h * w as u64 / src_w).max(1);
+                //? a tall source would follow `w` past the limit, shrink both instead.
+                let (w, h) = if h > MAX_DIMENSION as u64 {
+                    let w = (src_w * MAX_DIMENSION as u64 / src_h).max(1);
+                    (w as u32, MAX_DIMENSION)
+                } else {
+                    (w, h as u32)
+                };
+                img.resize_exact(w, h, filter)
+            }
+            Fit::Contain => {
+                let inner = img.resize(w, h, filter);
+                let mut canvas = RgbaImage::new(w, h);
+                let x = (w - inner.width()) / 2;
+                let y = (h - inner.height()) / 2;
+                image::imageops::overlay(&mut canvas, &inner.to_rgba8(), x as i64, y as i64);
+                DynamicImage::ImageRgba8(canvas)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn opts(pairs: &[(&str, &str)]) -> Result<ResizeOptions, ResizeError> {
+        let params: HashMap<_, _> = pairs.iter().copied().collect();
+        ResizeOptions::from_params(|name| params.get(name).map(|v| v.to_string()))
+    }
+
+    #[test]
+    fn parses_and_validates() {
+        assert_eq!(opts(&[]).unwrap(), ResizeOptions::default());
+
+        let o = opts(&[
+            ("w", "320"),
+            ("h", "200"),
+            ("fit", "contain"),
+            ("filter", "lanczos3"),
+        ])
+        .unwrap();
+        assert_eq!((o.width, o.height, o.fit), (320, Some(200), Fit::Contain));
+
+        let o = opts(&[("w", "64"), ("h", ""), ("fit", "width")]).unwrap();
+        assert_eq!((o.width, o.height), (64, None));
+
+        assert!(matches!(
+            opts(&[("w", "64"), ("h", "64"), ("fit", "width")]),
+            Err(ResizeError::UnexpectedHeight)
+        ));
+        assert!(matches!(
+            opts(&[("w", "64")]),
+            Err(ResizeError::MissingHeight(Fit::Cover))
+        ));
+        assert!(matches!(
+            opts(&[("w", "0"), ("h", "1")]),
+            Err(ResizeError::OutOfRange { .. })
+        ));
+        assert!(matches!(
+            opts(&[("w", "abc"), ("h", "1")]),
+            Err(ResizeError::NotANumber { .. })
+        ));
+        assert!(matches!(
+            opts(&[("fit", "zoom")]),
+            Err(ResizeError::UnknownFit(_))
+        ));
+        assert!(matches!(
+            opts(&[("filter", "bicubic")]),
+            Err(ResizeError::UnknownFilter(_))
+        ));
+    }
+
+    #[test]
+    fn keeps_requested_box() {
+        let img = DynamicImage::new_rgb8(400, 100);
+        for fit in ["exact", "contain", "cover"] {
+            let o = opts(&[("w", "50"), ("h", "50"), ("fit", fit)]).unwrap();
+            assert_eq!(o.apply(&img).dimensions(), (50, 50), "{fit}");
+        }
+        let o = opts(&[("w", "200"), ("fit", "width")]).unwrap();
+        assert_eq!(o.apply(&img).dimensions(), (200, 50));
+
+        //? a sliver must not blow up past the limit on either side.
+        let img = DynamicImage::new_rgb8(1, 1000);
+        let o = opts(&[
+            ("w", "4096"),
+            ("h", "4096"),
+            ("fit", "cover"),
+            ("filter", "nearest"),
+        ])
+        .unwrap();
+        assert_eq!(o.apply(&img).dimensions(), (4096, 4096));
+        let o = opts(&[("w", "100"), ("fit", "width")]).unwrap();
+        assert_eq!(o.apply(&img).dimensions(), (4, MAX_DIMENSION));
+        let img = DynamicImage::new_rgb8(1000, 1);
+        let o = opts(&[("w", "40"), ("h", "40"), ("fit", "cover")]).unwrap();
+        assert_eq!(o.apply(&img).dimensions(), (40, 40));
+    }
+}
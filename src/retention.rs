@@ -0,0 +1,460 @@
+use std::{
+    collections::HashSet,
+    io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::jobs;
+use crate::listing;
+use crate::record;
+use crate::storage::Storage;
+use crate::store;
+use crate::tus;
+
+/// finished jobs stay pollable for a day.
+const FINISHED_JOB_TTL: u64 = 24 * 60 * 60;
+
+/// what the sweeper enforces, everything is off unless configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Policy {
+    /// images (original and variants) uploaded longer ago are deleted.
+    pub max_age: Option<Duration>,
+    /// budget for `public/` plus `out/`: least recently used variants go first,
+    /// then the oldest images if the originals alone are over it.
+    pub max_bytes: Option<u64>,
+    pub interval: Duration,
+}
+
+impl Default for Policy {
+    fn default() -> Self {
+        Self {
+            max_age: None,
+            max_bytes: None,
+            interval: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// what one sweep removed, logged by the sweeper and returned by `POST /admin/sweep`.
+#[derive(Debug, Default, Serialize)]
+pub struct SweepReport {
+    /// ids of images removed for being older than `max_age`.
+    pub expired: Vec<String>,
+    /// ids of images removed to get under `max_bytes`.
+    pub evicted_images: Vec<String>,
+    pub evicted_variants: usize,
+    /// variants of deleted originals, interrupted deletes and whatever the storage cleaned up.
+    pub orphans: usize,
+    pub finished_jobs: usize,
+    /// resumable uploads abandoned half way.
+    pub stale_uploads: usize,
+    pub bytes_freed: u64,
+    pub bytes_stored: u64,
+}
+
+impl SweepReport {
+    pub fn removed_anything(&self) -> bool {
+        !self.expired.is_empty()
+            || !self.evicted_images.is_empty()
+            || self.evicted_variants > 0
+            || self.orphans > 0
+            || self.finished_jobs > 0
+            || self.stale_uploads > 0
+    }
+}
+
+struct CachedVariant {
+    key: String,
+    bytes: u64,
+    last_access: SystemTime,
+}
+
+fn size_of(storage: &dyn Storage, key: &str) -> io::Result<u64> {
+    Ok(storage.stat(key)?.map_or(0, |object| object.size))
+}
+
+fn delete_image(storage: &dyn Storage, id: &str, report: &mut SweepReport) -> io::Result<u64> {
+    let mut bytes =
+        size_of(storage, &store::original_key(id))? + size_of(storage, &record::key(id))?;
+    for variant in storage.list(&store::variants_prefix(id))? {
+        bytes += variant.size;
+    }
+    if store::delete(storage, id)? {
+        report.bytes_freed += bytes;
+    }
+    Ok(bytes)
+}
+
+/// removes what the policy no longer allows, one sweep at a time.
+pub struct Retention {
+    pub policy: Policy,
+    storage: Arc<dyn Storage>,
+    /// holds the job files and the partial uploads.
+    data_dir: PathBuf,
+    running: Mutex<()>,
+}
+
+impl Retention {
+    pub fn new(policy: Policy, storage: Arc<dyn Storage>, data_dir: PathBuf) -> Self {
+        Self {
+            policy,
+            storage,
+            data_dir,
+            running: Mutex::new(()),
+        }
+    }
+
+    pub fn sweep(&self) -> io::Result<SweepReport> {
+        let _running = self
+            .running
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let storage = self.storage.as_ref();
+        let mut report = SweepReport::default();
+
+        //? interrupted deletes and writes, they were already gone for readers
+        report.orphans += storage.maintenance()?;
+
+        //? deletes that failed after moving the original aside
+        for object in storage.list(&format!("{}/", store::DELETED_DIR))? {
+            let id = &object.key[store::DELETED_DIR.len() + 1..];
+            if !store::is_content_id(id) {
+                continue;
+            }
+            //? uploaded again since, the record is the new upload's
+            if storage.exists(&store::original_key(id))? {
+                storage.delete(&object.key)?;
+            } else {
+                store::delete(storage, id)?;
+            }
+            report.orphans += 1;
+        }
+
+        report.finished_jobs =
+            jobs::prune_finished(&self.data_dir.join(jobs::JOBS_DIR), FINISHED_JOB_TTL)?;
+        report.stale_uploads =
+            tus::prune_stale(&self.data_dir.join(tus::UPLOADS_DIR), tus::STALE_UPLOAD_TTL)?;
+
+        let mut uploads = listing::uploads(storage)?;
+        if let Some(max_age) = self.policy.max_age {
+            let now = record::now();
+            let mut kept = vec![];
+            for (uploaded_at, id) in uploads {
+                if now.saturating_sub(uploaded_at) > max_age.as_secs() {
+                    delete_image(storage, &id, &mut report)?;
+                    report.expired.push(id);
+                } else {
+                    kept.push((uploaded_at, id));
+                }
+            }
+            uploads = kept;
+        }
+
+        let mut originals_bytes = 0;
+        for object in storage.list(&format!("{}/", store::ORIGINALS_DIR))? {
+            originals_bytes += object.size;
+        }
+        let live: HashSet<&str> = uploads.iter().map(|(_, id)| id.as_str()).collect();
+        let mut variants = scan_variants(storage, &live, &mut report)?;
+        let variants_bytes: u64 = variants.iter().map(|v| v.bytes).sum();
+        let mut stored = originals_bytes + variants_bytes;
+
+        if let Some(max_bytes) = self.policy.max_bytes {
+            //? variants can be rendered again, so they are evicted before any original
+            variants.sort_by_key(|v| v.last_access);
+            for variant in &variants {
+                if stored <= max_bytes {
+                    break;
+                }
+                //? the ETag first, it must never outlive the bytes it names
+                storage.delete(&store::etag_key(&variant.key))?;
+                if !storage.delete(&variant.key)? {
+                    continue;
+                }
+                stored -= variant.bytes;
+                report.bytes_freed += variant.bytes;
+                report.evicted_variants += 1;
+            }
+
+            uploads.sort();
+            for (_, id) in uploads {
+                if stored <= max_bytes {
+                    break;
+                }
+                //? its variants were all evicted above, this is only the original
+                stored = stored.saturating_sub(delete_image(storage, &id, &mut report)?);
+                report.evicted_images.push(id);
+            }
+        }
+
+        report.bytes_stored = stored;
+        Ok(report)
+    }
+}
+
+/// the image id of a `out/ab/cd/{id}/{name}` key.
+fn variant_owner(key: &str) -> Option<&str> {
+    let rest = key.strip_prefix(store::VARIANTS_DIR)?.strip_prefix('/')?;
+    let parts: Vec<&str> = rest.split('/').collect();
+    match parts[..] {
+        [_, _, id, name] if store::is_content_id(id) && !name.is_empty() => Some(id),
+        _ => None,
+    }
+}
+
+/// collects the cached variants of the `live` images,
+/// dropping those whose original is gone along the way.
+fn scan_variants(
+    storage: &dyn Storage,
+    live: &HashSet<&str>,
+    report: &mut SweepReport,
+) -> io::Result<Vec<CachedVariant>> {
+    let mut variants = vec![];
+    let mut orphaned = HashSet::new();
+    for object in storage.list(&format!("{}/", store::VARIANTS_DIR))? {
+        //? flat files from before content addressing are left alone
+        let Some(id) = variant_owner(&object.key) else {
+            continue;
+        };
+        if live.contains(id) {
+            //? evicted along with its variant
+            if object.key.ends_with(store::ETAG_SUFFIX) {
+                continue;
+            }
+            variants.push(CachedVariant {
+                key: object.key,
+                bytes: object.size,
+                last_access: object.modified,
+            });
+        } else {
+            orphaned.insert(id.to_string());
+        }
+    }
+    for id in orphaned {
+        //? uploaded since `live` was listed
+        if storage.exists(&store::original_key(&id))? {
+            continue;
+        }
+        let prefix = store::variants_prefix(&id);
+        let bytes: u64 = storage.list(&prefix)?.iter().map(|v| v.size).sum();
+        storage.delete_prefix(&prefix)?;
+        report.orphans += 1;
+        report.bytes_freed += bytes;
+    }
+    Ok(variants)
+}
+
+/// sweeps every `policy.interval` for as long as the server runs.
+pub fn spawn_sweeper(retention: Arc<Retention>) {
+    thread::Builder::new()
+        .name("retention".to_string())
+        .spawn(move || loop {
+            thread::sleep(retention.policy.interval);
+            match retention.sweep() {
+                Ok(report) if report.removed_anything() => println!(
+                    ">> sweep: {} expired, {} evicted, {} variants, {} orphans, {} jobs, {} uploads, {} bytes freed",
+                    report.expired.len(),
+                    report.evicted_images.len(),
+                    report.evicted_variants,
+                    report.orphans,
+                    report.finished_jobs,
+                    report.stale_uploads,
+                    report.bytes_freed
+                ),
+                Ok(_) => {}
+                Err(err) => println!(">> sweep failed: {err}"),
+            }
+        })
+        .expect("spawn retention sweeper");
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    /// an original of `size` bytes with its record, uploaded `age` seconds ago.
+    fn stored(storage: &dyn Storage, seed: u8, size: usize, age: u64) -> String {
+        let id = store::content_id(&[seed]);
+        storage
+            .put(&store::original_key(&id), &vec![0; size])
+            .unwrap();
+        let record = format!("{{\"uploaded_at\": {}}}", record::now() - age);
+        record::save(storage, &id, &serde_json::from_str(&record).unwrap()).unwrap();
+        id
+    }
+
+    fn retention(test: &str, policy: Policy) -> (Arc<MemoryStorage>, Retention) {
+        let data_dir =
+            std::env::temp_dir().join(format!("retention-{test}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&data_dir);
+        let storage = Arc::new(MemoryStorage::default());
+        (storage.clone(), Retention::new(policy, storage, data_dir))
+    }
+
+    fn stored_bytes(storage: &dyn Storage) -> u64 {
+        [store::ORIGINALS_DIR, store::VARIANTS_DIR]
+            .iter()
+            .flat_map(|dir| storage.list(&format!("{dir}/")).unwrap())
+            .map(|object| object.size)
+            .sum()
+    }
+
+    #[test]
+    fn expires_old_images() {
+        let (storage, retention) = retention(
+            "max-age",
+            Policy {
+                max_age: Some(Duration::from_secs(DAY)),
+                ..Policy::default()
+            },
+        );
+        let storage = storage.as_ref();
+        let old = stored(storage, 1, 100, 2 * DAY);
+        let new = stored(storage, 2, 100, 60);
+        let variant = store::variant_key(&old, "16x16-cover-lanczos3.png");
+        storage.put(&variant, &[0; 50]).unwrap();
+
+        let report = retention.sweep().unwrap();
+        assert_eq!(report.expired, [old.as_str()]);
+        assert!(!storage.exists(&store::original_key(&old)).unwrap());
+        assert!(!storage.exists(&record::key(&old)).unwrap());
+        assert!(!storage.exists(&variant).unwrap());
+        assert!(storage.exists(&store::original_key(&new)).unwrap());
+        assert_eq!(report.bytes_stored, stored_bytes(storage));
+        assert!(report.bytes_freed >= 150);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_variants_before_originals() {
+        let (storage, retention) = retention("lru", Policy::default());
+        let id = stored(storage.as_ref(), 1, 100, 60);
+        let variants: Vec<String> = ["a.png", "b.png", "c.png"]
+            .iter()
+            .map(|name| {
+                let key = store::variant_key(&id, name);
+                storage.put(&key, &[0; 1_000]).unwrap();
+                thread::sleep(Duration::from_millis(5));
+                key
+            })
+            .collect();
+        storage
+            .put(&store::etag_key(&variants[1]), b"\"b\"")
+            .unwrap();
+        //? read after the others were written, `a` is now the most recent
+        storage.touch(&variants[0]).unwrap();
+
+        let total = stored_bytes(storage.as_ref());
+        let retention = Retention {
+            policy: Policy {
+                max_bytes: Some(total - 1_500),
+                ..Policy::default()
+            },
+            ..retention
+        };
+        let report = retention.sweep().unwrap();
+        assert_eq!(report.evicted_variants, 2);
+        assert!(report.evicted_images.is_empty());
+        assert!(storage.exists(&variants[0]).unwrap());
+        assert!(!storage.exists(&variants[1]).unwrap());
+        assert!(!storage.exists(&store::etag_key(&variants[1])).unwrap());
+        assert!(!storage.exists(&variants[2]).unwrap());
+
+        //? the originals alone are over it, the oldest upload goes
+        let newer = stored(storage.as_ref(), 2, 500, 10);
+        let retention = Retention {
+            policy: Policy {
+                max_bytes: Some(700),
+                ..Policy::default()
+            },
+            ..retention
+        };
+        let report = retention.sweep().unwrap();
+        assert_eq!(report.evicted_variants, 1);
+        assert_eq!(report.evicted_images, [id.as_str()]);
+        assert!(!storage.exists(&store::original_key(&id)).unwrap());
+        assert!(storage.exists(&store::original_key(&newer)).unwrap());
+        assert!(report.bytes_stored <= 700);
+    }
+
+    #[test]
+    fn cleans_up_orphans_uploads_and_jobs() {
+        let (storage, retention) = retention("leftovers", Policy::default());
+        let storage = storage.as_ref();
+        let live = stored(storage, 1, 100, 60);
+        let kept = store::variant_key(&live, "a.png");
+        storage.put(&kept, &[0; 10]).unwrap();
+        let orphan = store::variant_key(&store::content_id(&[2]), "a.png");
+        storage.put(&orphan, &[0; 10]).unwrap();
+        //? a delete that stopped right after moving the original aside
+        let interrupted = stored(storage, 3, 100, 60);
+        storage
+            .rename(
+                &store::original_key(&interrupted),
+                &store::deleted_key(&interrupted),
+            )
+            .unwrap();
+
+        let uploads = retention.data_dir.join(tus::UPLOADS_DIR);
+        fs::create_dir_all(&uploads).unwrap();
+        let long_ago = SystemTime::now() - Duration::from_secs(2 * DAY);
+        for (id, modified) in [
+            ("a".repeat(32), long_ago),
+            ("b".repeat(32), SystemTime::now()),
+        ] {
+            for extension in ["json", "bin"] {
+                let path = uploads.join(format!("{id}.{extension}"));
+                fs::write(&path, b"{}").unwrap();
+                let file = fs::File::options().write(true).open(&path).unwrap();
+                file.set_modified(modified).unwrap();
+            }
+        }
+
+        let jobs_dir = retention.data_dir.join(jobs::JOBS_DIR);
+        fs::create_dir_all(&jobs_dir).unwrap();
+        for (id, status, finished_at) in [
+            (
+                "c".repeat(32),
+                jobs::Status::Done,
+                Some(record::now() - 2 * DAY),
+            ),
+            ("d".repeat(32), jobs::Status::Pending, None),
+        ] {
+            let job = jobs::Job {
+                id: id.clone(),
+                image_id: live.clone(),
+                status,
+                created_at: record::now() - 2 * DAY,
+                finished_at,
+                tasks: vec![],
+                outputs: vec![],
+                error: None,
+            };
+            let path = jobs_dir.join(format!("{id}.json"));
+            fs::write(path, serde_json::to_vec(&job).unwrap()).unwrap();
+        }
+
+        let report = retention.sweep().unwrap();
+        assert_eq!(report.orphans, 2);
+        assert!(!storage.exists(&orphan).unwrap());
+        assert!(!storage.exists(&record::key(&interrupted)).unwrap());
+        assert!(!storage.exists(&store::deleted_key(&interrupted)).unwrap());
+        assert!(storage.exists(&kept).unwrap());
+        assert_eq!(report.stale_uploads, 1);
+        assert!(!uploads.join(format!("{}.bin", "a".repeat(32))).exists());
+        assert!(uploads.join(format!("{}.bin", "b".repeat(32))).exists());
+        assert_eq!(report.finished_jobs, 1);
+        assert!(jobs::load(&jobs_dir, &"c".repeat(32)).is_none());
+        assert!(jobs::load(&jobs_dir, &"d".repeat(32)).is_some());
+        fs::remove_dir_all(&retention.data_dir).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+use std::{fmt, time::Duration};
+
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+
+/// how long the links handed to a browser stay valid, and the default `ttl` of a minted one.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(60 * 60);
+/// a signed link is a capability, it should not outlive a week.
+pub const MAX_TTL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// shorter secrets are a startup error, they can be brute forced offline from one link.
+pub const MIN_SECRET_BYTES: usize = 16;
+
+#[derive(Debug)]
+pub enum SignatureError {
+    Missing,
+    BadExpiry,
+    Expired,
+    Mismatch,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::Missing => write!(f, "the url is not signed"),
+            SignatureError::BadExpiry => write!(f, "`exp` must be unix seconds"),
+            SignatureError::Expired => write!(f, "the signed url has expired"),
+            SignatureError::Mismatch => write!(f, "the url signature does not match"),
+        }
+    }
+}
+
+impl std::error::Error for SignatureError {}
+
+#[derive(Debug)]
+pub struct SecretTooShort;
+
+impl fmt::Display for SecretTooShort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "URL_SIGNING_SECRET must be at least {MIN_SECRET_BYTES} bytes"
+        )
+    }
+}
+
+impl std::error::Error for SecretTooShort {}
+
+#[derive(Debug)]
+pub struct BadTtl(pub String);
+
+impl fmt::Display for BadTtl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "`ttl` must be a whole number of seconds between 1 and {}, got {:?}",
+            MAX_TTL.as_secs(),
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for BadTtl {}
+
+/// `ttl=` in seconds, [`DEFAULT_TTL`] when absent.
+pub fn parse_ttl(value: Option<&str>) -> Result<Duration, BadTtl> {
+    let Some(value) = value.map(str::trim).filter(|v| !v.is_empty()) else {
+        return Ok(DEFAULT_TTL);
+    };
+    match value.parse::<u64>() {
+        Ok(secs) if secs > 0 && secs <= MAX_TTL.as_secs() => Ok(Duration::from_secs(secs)),
+        _ => Err(BadTtl(value.to_string())),
+    }
+}
+
+/// what `GET /api/images/{id}/signed-url` answers.
+#[derive(Debug, Serialize)]
+pub struct SignedUrl {
+    pub url: String,
+    pub expires_at: u64,
+}
+
+/// the same url however the client escaped it: `%7C` and `|` sign alike.
+/// separators stay escaped, so a `%26` in a value never turns into a new parameter.
+fn normalize(url: &str) -> String {
+    let bytes = url.as_bytes();
+    let mut normalized = String::with_capacity(url.len());
+    let mut at = 0;
+    while at < bytes.len() {
+        let escaped = (bytes[at] == b'%')
+            .then(|| url.get(at + 1..at + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) if byte.is_ascii_graphic() && !b"&=?#%+".contains(&byte) => {
+                normalized.push(byte as char);
+                at += 3;
+            }
+            Some(byte) => {
+                normalized.push_str(&format!("%{byte:02X}"));
+                at += 3;
+            }
+            None => {
+                let ch = url[at..].chars().next().expect("at is on a char boundary");
+                normalized.push(ch);
+                at += ch.len_utf8();
+            }
+        }
+    }
+    normalized
+}
+
+/// `{url}&exp={unix seconds}&sig={hex hmac}`, the HMAC-SHA256 covers
+/// everything before `&sig=`: the path, every parameter and the expiry.
+/// nothing can be added after `sig`, it has to be the last parameter.
+pub struct Signer {
+    secret: Vec<u8>,
+}
+
+impl Signer {
+    pub fn new(secret: &[u8]) -> Result<Self, SecretTooShort> {
+        if secret.len() < MIN_SECRET_BYTES {
+            return Err(SecretTooShort);
+        }
+        Ok(Self {
+            secret: secret.to_vec(),
+        })
+    }
+
+    fn mac(&self, unsigned: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.secret).expect("hmac takes any key size");
+        mac.update(normalize(unsigned).as_bytes());
+        mac
+    }
+
+    /// `url` is a path with an optional query string, as the client will send it.
+    pub fn sign(&self, url: &str, expires_at: u64) -> String {
+        let separator = if url.contains('?') { '&' } else { '?' };
+        let unsigned = format!("{url}{separator}exp={expires_at}");
+        let sig = self.mac(&unsigned).finalize().into_bytes();
+        let sig: String = sig.iter().map(|byte| format!("{byte:02x}")).collect();
+        format!("{unsigned}&sig={sig}")
+    }
+
+    /// `raw_url` is the path and query exactly as received.
+    pub fn verify(&self, raw_url: &str, now: u64) -> Result<(), SignatureError> {
+        let (unsigned, sig) = raw_url
+            .rsplit_once("&sig=")
+            .ok_or(SignatureError::Missing)?;
+        let sig = decode_hex(sig).ok_or(SignatureError::Mismatch)?;
+        //? constant time, a byte by byte compare would leak the signature
+        self.mac(unsigned)
+            .verify_slice(&sig)
+            .map_err(|_| SignatureError::Mismatch)?;
+        let (_, query) = unsigned.split_once('?').ok_or(SignatureError::Missing)?;
+        let expires_at = query
+            .split('&')
+            .filter_map(|pair| pair.strip_prefix("exp="))
+            .next_back()
+            .ok_or(SignatureError::Missing)?
+            .parse::<u64>()
+            .map_err(|_| SignatureError::BadExpiry)?;
+        if expires_at < now {
+            return Err(SignatureError::Expired);
+        }
+        Ok(())
+    }
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|at| u8::from_str_radix(hex.get(at..at + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn signs_path_params_and_expiry() {
+        let signer = Signer::new(b"0123456789abcdef").unwrap();
+        let url = signer.sign("/img/abc.webp?w=100&h=100", 1_000);
+        assert!(url.starts_with("/img/abc.webp?w=100&h=100&exp=1000&sig="));
+        assert!(signer.verify(&url, 999).is_ok());
+        assert!(signer.verify(&url, 1_000).is_ok());
+        assert!(matches!(
+            signer.verify(&url, 1_001),
+            Err(SignatureError::Expired)
+        ));
+
+        let tampered = url.replace("w=100", "w=4000");
+        assert!(matches!(
+            signer.verify(&tampered, 0),
+            Err(SignatureError::Mismatch)
+        ));
+        let extended = url.replace("exp=1000", "exp=9999");
+        assert!(matches!(
+            signer.verify(&extended, 0),
+            Err(SignatureError::Mismatch)
+        ));
+        assert!(matches!(
+            signer.verify("/img/abc.webp?w=100", 0),
+            Err(SignatureError::Missing)
+        ));
+        let other = Signer::new(b"fedcba9876543210").unwrap();
+        assert!(other.verify(&url, 0).is_err());
+
+        assert!(signer.verify(&signer.sign("/img/abc", 5), 0).is_ok());
+        assert!(Signer::new(b"short").is_err());
+    }
+
+    #[test]
+    fn escaping_does_not_matter() {
+        let signer = Signer::new(b"0123456789abcdef").unwrap();
+        let url = signer.sign("/img/abc?ops=grayscale|blur:2&caption=a%26b", 1_000);
+        let escaped = url.replace('|', "%7c").replace(':', "%3A");
+        assert!(signer.verify(&escaped, 0).is_ok());
+        //? an escaped separator is not the separator
+        let split = url.replace("a%26b", "a&b");
+        assert!(signer.verify(&split, 0).is_err());
+
+        assert_eq!(normalize("/a%2fb%25%zz%e2%82%ac"), "/a/b%25%zz%E2%82%AC");
+        assert_eq!(parse_ttl(None).unwrap(), DEFAULT_TTL);
+        assert_eq!(parse_ttl(Some("60")).unwrap(), Duration::from_secs(60));
+        assert!(parse_ttl(Some("0")).is_err());
+        assert!(parse_ttl(Some("604801")).is_err());
+    }
+}
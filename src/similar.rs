@@ -0,0 +1,487 @@
+use std::{
+    fmt, fs, io,
+    io::Cursor,
+    path::PathBuf,
+    sync::{Mutex, MutexGuard},
+};
+
+use image::{imageops::FilterType, DynamicImage};
+use serde::{Deserialize, Serialize};
+
+use crate::metadata;
+use crate::metrics::{self, Stage};
+use crate::placeholder::Placeholder;
+use crate::record;
+use crate::storage::Storage;
+use crate::store;
+use crate::throttle::{self, Admission};
+use crate::validate;
+
+/// like the jobs, the index is local to this server whatever the storage is.
+pub const INDEX_FILE: &str = "index/phash.json";
+pub const DEFAULT_MAX_DISTANCE: u32 = 10;
+/// past this two 64 bit hashes have little in common.
+pub const MAX_DISTANCE: u32 = 32;
+
+#[derive(Debug)]
+pub enum SimilarError {
+    NotFound,
+    BadDistance(String),
+    Busy(throttle::Busy),
+    Image(image::ImageError),
+    Io(io::Error),
+}
+
+impl fmt::Display for SimilarError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimilarError::NotFound => write!(f, "no image with that id"),
+            SimilarError::BadDistance(value) => write!(
+                f,
+                "`max_distance` must be a whole number up to {MAX_DISTANCE}, got {value:?}"
+            ),
+            SimilarError::Busy(err) => err.fmt(f),
+            SimilarError::Image(err) => write!(f, "could not hash image: {err}"),
+            SimilarError::Io(err) => write!(f, "storage error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for SimilarError {}
+
+impl SimilarError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SimilarError::NotFound => "not_found",
+            SimilarError::BadDistance(_) => "bad_request",
+            SimilarError::Busy(_) => "busy",
+            SimilarError::Image(_) => "unreadable_image",
+            SimilarError::Io(_) => "storage_error",
+        }
+    }
+
+    pub fn status_code(&self) -> u16 {
+        match self {
+            SimilarError::NotFound => 404,
+            SimilarError::BadDistance(_) => 400,
+            SimilarError::Busy(_) => 503,
+            SimilarError::Image(_) => 422,
+            SimilarError::Io(_) => 500,
+        }
+    }
+}
+
+pub fn parse_max_distance(value: Option<&str>) -> Result<u32, SimilarError> {
+    let Some(value) = value.map(str::trim).filter(|v| !v.is_empty()) else {
+        return Ok(DEFAULT_MAX_DISTANCE);
+    };
+    match value.parse::<u32>() {
+        Ok(distance) if distance <= MAX_DISTANCE => Ok(distance),
+        _ => Err(SimilarError::BadDistance(value.to_string())),
+    }
+}
+
+/// dHash: is each pixel brighter than its right neighbour, on a 9x8 grayscale copy.
+/// survives rescaling and recompression, which is what re-uploads go through.
+pub fn dhash(img: &DynamicImage) -> u64 {
+    let small = img.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+pub fn to_hex(hash: u64) -> String {
+    format!("{hash:016x}")
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Node {
+    hash: u64,
+    /// every image with exactly this hash, empty once they are all removed.
+    ids: Vec<String>,
+    /// (distance to this node, index of the child).
+    children: Vec<(u32, usize)>,
+}
+
+/// a BK-tree over Hamming distance, kept flat so it (de)serializes without recursion.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct BkTree {
+    nodes: Vec<Node>,
+}
+
+impl BkTree {
+    pub fn insert(&mut self, hash: u64, id: &str) {
+        if self.nodes.is_empty() {
+            self.nodes.push(Node {
+                hash,
+                ids: vec![id.to_string()],
+                children: vec![],
+            });
+            return;
+        }
+        let mut at = 0;
+        loop {
+            let d = distance(self.nodes[at].hash, hash);
+            if d == 0 {
+                let ids = &mut self.nodes[at].ids;
+                if !ids.iter().any(|known| known == id) {
+                    ids.push(id.to_string());
+                }
+                return;
+            }
+            match self.nodes[at].children.iter().find(|(cd, _)| *cd == d) {
+                Some(&(_, child)) => at = child,
+                None => {
+                    let child = self.nodes.len();
+                    self.nodes.push(Node {
+                        hash,
+                        ids: vec![id.to_string()],
+                        children: vec![],
+                    });
+                    self.nodes[at].children.push((d, child));
+                    return;
+                }
+            }
+        }
+    }
+
+    /// the node stays behind to keep routing searches, only the id goes.
+    pub fn remove(&mut self, hash: u64, id: &str) -> bool {
+        for node in &mut self.nodes {
+            if node.hash == hash {
+                let before = node.ids.len();
+                node.ids.retain(|known| known != id);
+                return node.ids.len() != before;
+            }
+        }
+        false
+    }
+
+    /// every id within `max_distance` of `hash`, closest first.
+    pub fn find(&self, hash: u64, max_distance: u32) -> Vec<(u32, String)> {
+        let mut found = vec![];
+        let mut pending = if self.nodes.is_empty() {
+            vec![]
+        } else {
+            vec![0]
+        };
+        while let Some(at) = pending.pop() {
+            let node = &self.nodes[at];
+            let d = distance(node.hash, hash);
+            if d <= max_distance {
+                found.extend(node.ids.iter().map(|id| (d, id.clone())));
+            }
+            //? triangle inequality: only children between d - max and d + max can match
+            pending.extend(
+                node.children
+                    .iter()
+                    .filter(|(cd, _)| cd.abs_diff(d) <= max_distance)
+                    .map(|&(_, child)| child),
+            );
+        }
+        found.sort();
+        found
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct SimilarImage {
+    pub id: String,
+    pub distance: u32,
+    pub url: String,
+    pub metadata_url: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Similar {
+    pub id: String,
+    pub hash: String,
+    pub max_distance: u32,
+    pub images: Vec<SimilarImage>,
+}
+
+/// perceptual hashes of every stored original, looked up by distance.
+pub struct Index {
+    path: PathBuf,
+    tree: Mutex<BkTree>,
+}
+
+impl Index {
+    /// reads `path` (see [`INDEX_FILE`]), or rebuilds it from the hashes kept in the records.
+    pub fn open(storage: &dyn Storage, path: PathBuf) -> io::Result<Self> {
+        let loaded = fs::read(&path)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice::<BkTree>(&bytes).ok());
+        if let Some(tree) = loaded {
+            return Ok(Self {
+                path,
+                tree: Mutex::new(tree),
+            });
+        }
+        let mut tree = BkTree::default();
+        for object in storage.list(&format!("{}/", store::ORIGINALS_DIR))? {
+            let Some(id) = object.key.rsplit('/').next() else {
+                continue;
+            };
+            if !store::is_content_id(id) || object.key != store::original_key(id) {
+                continue;
+            }
+            if let Some(hash) = record::load(storage, id).and_then(|r| r.phash) {
+                tree.insert(hash, id);
+            }
+        }
+        let index = Self {
+            path,
+            tree: Mutex::new(tree),
+        };
+        index.save(&index.tree())?;
+        Ok(index)
+    }
+
+    fn tree(&self) -> MutexGuard<'_, BkTree> {
+        self.tree
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn save(&self, tree: &BkTree) -> io::Result<()> {
+        let bytes = serde_json::to_vec(tree)?;
+        store::write_atomically(&self.path, &bytes)
+    }
+
+    /// the hash of `id`, computed from the original and indexed the first time.
+    /// the gallery placeholder comes out of the same decode, see [`Placeholder`].
+    pub fn ensure(
+        &self,
+        storage: &dyn Storage,
+        id: &str,
+        admission: Admission,
+    ) -> Result<u64, SimilarError> {
+        let record = record::load(storage, id).ok_or(SimilarError::NotFound)?;
+        if let (Some(hash), Some(_)) = (record.phash, &record.placeholder) {
+            return Ok(hash);
+        }
+        let permit = throttle::decodes()
+            .admit(admission)
+            .map_err(SimilarError::Busy)?;
+        let original = match storage.read(&store::original_key(id)) {
+            Ok(original) => original,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(SimilarError::NotFound)
+            }
+            Err(err) => return Err(SimilarError::Io(err)),
+        };
+        let mut reader = image::io::Reader::new(Cursor::new(&original))
+            .with_guessed_format()
+            .map_err(SimilarError::Io)?;
+        reader.limits(validate::decode_limits());
+        let img = metrics::timed(Stage::Decode, || reader.decode()).map_err(SimilarError::Image)?;
+        //? a rotated re-upload of the same photo should still match
+        let img = metadata::apply_orientation(img, record.orientation);
+        let hash = dhash(&img);
+        let placeholder = match record.placeholder {
+            Some(_) => None,
+            None => Some(Placeholder::compute(&img).map_err(SimilarError::Image)?),
+        };
+        drop(permit);
+
+        //? the record may have gained an owner while we decoded, only the hash is ours to set
+        let found = record::update(storage, id, |record| {
+            let Some(record) = record else {
+                return Ok(false);
+            };
+            record.phash = Some(hash);
+            if record.placeholder.is_none() {
+                record.placeholder = placeholder;
+            }
+            Ok(true)
+        })
+        .map_err(SimilarError::Io)?;
+        if !found {
+            return Err(SimilarError::NotFound);
+        }
+        let mut tree = self.tree();
+        tree.insert(hash, id);
+        self.save(&tree).map_err(SimilarError::Io)?;
+        Ok(hash)
+    }
+
+    pub fn remove(&self, id: &str, hash: u64) -> io::Result<()> {
+        let mut tree = self.tree();
+        if tree.remove(hash, id) {
+            self.save(&tree)?;
+        }
+        Ok(())
+    }
+
+    /// other images within `max_distance` of `id`, closest first.
+    /// images deleted behind the index's back (retention) are dropped from it here.
+    pub fn similar(
+        &self,
+        storage: &dyn Storage,
+        id: &str,
+        max_distance: u32,
+    ) -> Result<Similar, SimilarError> {
+        let hash = self.ensure(storage, id, Admission::Reject)?;
+        let found = self.tree().find(hash, max_distance);
+        let mut images = vec![];
+        for (distance, other) in found {
+            if other == id {
+                continue;
+            }
+            if !storage
+                .exists(&store::original_key(&other))
+                .map_err(SimilarError::Io)?
+            {
+                let other_hash = hash_near(&self.tree(), &other, hash, max_distance);
+                if let Some(other_hash) = other_hash {
+                    self.remove(&other, other_hash).map_err(SimilarError::Io)?;
+                }
+                continue;
+            }
+            images.push(SimilarImage {
+                url: format!("/img/{other}"),
+                metadata_url: format!("/api/images/{other}"),
+                id: other,
+                distance,
+            });
+        }
+        Ok(Similar {
+            id: id.to_string(),
+            hash: to_hex(hash),
+            max_distance,
+            images,
+        })
+    }
+}
+
+/// the hash `id` was indexed under, it is within `max_distance` of `hash` by construction.
+fn hash_near(tree: &BkTree, id: &str, hash: u64, max_distance: u32) -> Option<u64> {
+    tree.nodes
+        .iter()
+        .find(|node| distance(node.hash, hash) <= max_distance && node.ids.iter().any(|i| i == id))
+        .map(|node| node.hash)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use super::*;
+    use crate::storage::{MemoryStorage, ObjectMeta};
+    use image::{ImageOutputFormat, Rgb, RgbImage};
+
+    /// a second owner uploads the same bytes while the original is being read for the hash.
+    #[derive(Default)]
+    struct UploadMidDecode {
+        inner: MemoryStorage,
+    }
+
+    impl Storage for UploadMidDecode {
+        fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+            self.inner.put(key, bytes)
+        }
+
+        fn get(&self, key: &str) -> io::Result<Box<dyn Read + Send>> {
+            let id = key.rsplit('/').next().unwrap();
+            if store::is_content_id(id) && key == store::original_key(id) {
+                record::update(&self.inner, id, |record| {
+                    record.as_mut().unwrap().owners.push("b".to_string());
+                    Ok(())
+                })?;
+            }
+            self.inner.get(key)
+        }
+
+        fn delete(&self, key: &str) -> io::Result<bool> {
+            self.inner.delete(key)
+        }
+
+        fn list(&self, prefix: &str) -> io::Result<Vec<ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+
+        fn stat(&self, key: &str) -> io::Result<Option<ObjectMeta>> {
+            self.inner.stat(key)
+        }
+    }
+
+    #[test]
+    fn finds_within_distance() {
+        let mut tree = BkTree::default();
+        tree.insert(0b0000, "a");
+        tree.insert(0b0001, "b");
+        tree.insert(0b0111, "c");
+        tree.insert(0b1111_1111, "d");
+        tree.insert(0b0001, "b2");
+        tree.insert(0b0001, "b2");
+
+        let ids =
+            |found: Vec<(u32, String)>| found.into_iter().map(|(_, id)| id).collect::<Vec<_>>();
+        assert_eq!(ids(tree.find(0, 0)), ["a"]);
+        assert_eq!(ids(tree.find(0, 1)), ["a", "b", "b2"]);
+        assert_eq!(ids(tree.find(0, 3)), ["a", "b", "b2", "c"]);
+        assert_eq!(tree.find(0b1111_1111, 2), [(0, "d".to_string())]);
+
+        assert!(tree.remove(0b0001, "b"));
+        assert!(!tree.remove(0b0001, "b"));
+        assert_eq!(ids(tree.find(0, 3)), ["a", "b2", "c"]);
+
+        let json = serde_json::to_vec(&tree).unwrap();
+        let back: BkTree = serde_json::from_slice(&json).unwrap();
+        assert_eq!(back.find(0, 8), tree.find(0, 8));
+    }
+
+    #[test]
+    fn hashes_survive_rescaling() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(300, 200, |x, y| {
+            Rgb([
+                (x * 255 / 300) as u8,
+                (y * 255 / 200) as u8,
+                ((x + y) % 256) as u8,
+            ])
+        }));
+        let hash = dhash(&img);
+        let small = img.resize_exact(120, 80, FilterType::Lanczos3);
+        assert!(distance(hash, dhash(&small)) <= 4);
+        assert!(distance(hash, dhash(&img.fliph())) > 16);
+        assert_eq!(parse_max_distance(None).unwrap(), DEFAULT_MAX_DISTANCE);
+        assert!(parse_max_distance(Some("33")).is_err());
+    }
+
+    #[test]
+    fn hashing_keeps_owners_added_meanwhile() {
+        let mut png = vec![];
+        DynamicImage::ImageRgb8(RgbImage::from_fn(32, 32, |x, y| {
+            Rgb([x as u8 * 8, y as u8 * 8, 0])
+        }))
+        .write_to(&mut Cursor::new(&mut png), ImageOutputFormat::Png)
+        .unwrap();
+        let storage = UploadMidDecode::default();
+        let id = store::content_id(&png);
+        storage.inner.put(&store::original_key(&id), &png).unwrap();
+        let record = serde_json::from_str("{\"uploaded_at\": 1, \"owners\": [\"a\"]}").unwrap();
+        record::save(&storage.inner, &id, &record).unwrap();
+
+        let path = std::env::temp_dir().join(format!("phash-race-{}.json", std::process::id()));
+        let index = Index::open(&storage, path.clone()).unwrap();
+        let hash = index.ensure(&storage, &id, Admission::Wait).unwrap();
+        let _ = fs::remove_file(&path);
+
+        let record = record::load(&storage, &id).unwrap();
+        assert_eq!(record.phash, Some(hash));
+        assert!(record.placeholder.is_some());
+        assert_eq!(record.owners, ["a", "b"]);
+    }
+}
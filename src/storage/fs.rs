@@ -0,0 +1,276 @@
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{ObjectMeta, Storage};
+use crate::store;
+
+/// deleted prefixes are moved here first, then removed for good.
+const TRASH_DIR: &str = "trash";
+/// maintenance leaves younger trash alone, a delete may still be removing it.
+const STALE_TRASH: Duration = Duration::from_secs(60 * 60);
+/// leftovers of an interrupted write are only removed once they are this old.
+const STALE_TEMP: Duration = Duration::from_secs(60 * 60);
+/// `touch` writes at most this often, so hot thumbnails do not write on every hit.
+const ACCESS_RESOLUTION: Duration = Duration::from_secs(60 * 60);
+
+/// keys are paths below `root`, which keeps the `public/` and `out/` layout on disk.
+pub struct FsStorage {
+    root: PathBuf,
+}
+
+impl FsStorage {
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    /// keys come from our own id and variant names, this only guards against mistakes.
+    fn path(&self, key: &str) -> io::Result<PathBuf> {
+        if key.is_empty() || key.split('/').any(|part| matches!(part, "" | "." | "..")) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid storage key {key:?}"),
+            ));
+        }
+        Ok(self.root.join(key))
+    }
+
+    fn meta(&self, key: String, metadata: &fs::Metadata) -> ObjectMeta {
+        ObjectMeta {
+            key,
+            size: metadata.len(),
+            modified: metadata.modified().unwrap_or(UNIX_EPOCH),
+        }
+    }
+
+    fn walk(&self, dir: &Path, key: &str, found: &mut Vec<ObjectMeta>) -> io::Result<()> {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            let key = if key.is_empty() {
+                name
+            } else {
+                format!("{key}/{name}")
+            };
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                self.walk(&entry.path(), &key, found)?;
+            } else if !is_temp(&key) {
+                found.push(self.meta(key, &metadata));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `{nanos}-{pid}-{n}-{dir}`, unique per delete and telling when it was trashed.
+fn trash_name(dir: &str) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    format!(
+        "{nanos}-{}-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed),
+        dir.replace('/', "_")
+    )
+}
+
+/// `None` for names not made by [`trash_name`].
+fn trashed_at(name: &str) -> Option<SystemTime> {
+    let nanos: u64 = name.split('-').next()?.parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_nanos(nanos))
+}
+
+/// temp files of `store::write_atomically` in flight.
+fn is_temp(key: &str) -> bool {
+    key.rsplit('/')
+        .next()
+        .is_some_and(|name| name.contains(".tmp-"))
+}
+
+impl Storage for FsStorage {
+    fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+        store::write_atomically(&self.path(key)?, bytes)
+    }
+
+    fn get(&self, key: &str) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(io::BufReader::new(fs::File::open(
+            self.path(key)?,
+        )?)))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<bool> {
+        match fs::remove_file(self.path(key)?) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<ObjectMeta>> {
+        //? walk from the deepest directory the prefix names, filter the rest by name
+        let dir = match prefix.rfind('/') {
+            Some(slash) => &prefix[..slash],
+            None => "",
+        };
+        let start = if dir.is_empty() {
+            self.root.clone()
+        } else {
+            self.path(dir)?
+        };
+        let mut found = vec![];
+        self.walk(&start, dir, &mut found)?;
+        found.retain(|object| object.key.starts_with(prefix));
+        Ok(found)
+    }
+
+    fn stat(&self, key: &str) -> io::Result<Option<ObjectMeta>> {
+        match fs::metadata(self.path(key)?) {
+            Ok(metadata) if metadata.is_file() => Ok(Some(self.meta(key.to_string(), &metadata))),
+            Ok(_) => Ok(None),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let to = self.path(to)?;
+        if let Some(dir) = to.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::rename(self.path(from)?, to)
+    }
+
+    /// a directory prefix is renamed into `trash/` in one step, so readers see
+    /// all of it or none of it, then removed at leisure.
+    fn delete_prefix(&self, prefix: &str) -> io::Result<usize> {
+        let Some(dir) = prefix.strip_suffix('/') else {
+            let objects = self.list(prefix)?;
+            for object in &objects {
+                self.delete(&object.key)?;
+            }
+            return Ok(objects.len());
+        };
+        let count = self.list(prefix)?.len();
+        let trash = self.root.join(TRASH_DIR).join(trash_name(dir));
+        fs::create_dir_all(self.root.join(TRASH_DIR))?;
+        match fs::rename(self.path(dir)?, &trash) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        }
+        match fs::remove_dir_all(&trash) {
+            Ok(()) => Ok(count),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(count),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// bumps the mtime, which retention reads as the last access.
+    fn touch(&self, key: &str) -> io::Result<()> {
+        let path = self.path(key)?;
+        let now = SystemTime::now();
+        let modified = fs::metadata(&path)?.modified()?;
+        if now.duration_since(modified).unwrap_or_default() > ACCESS_RESOLUTION {
+            fs::File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(now)?;
+        }
+        Ok(())
+    }
+
+    /// trash left by an interrupted delete and temp files of interrupted writes.
+    /// only trash from well before the sweep started, a delete running next to it
+    /// is still removing its own.
+    fn maintenance(&self) -> io::Result<usize> {
+        let started = SystemTime::now();
+        let mut removed = 0;
+        if let Ok(entries) = fs::read_dir(self.root.join(TRASH_DIR)) {
+            for entry in entries {
+                let entry = entry?;
+                let trashed = match trashed_at(&entry.file_name().to_string_lossy()) {
+                    Some(trashed) => trashed,
+                    None => entry.metadata()?.modified()?,
+                };
+                if started.duration_since(trashed).unwrap_or_default() <= STALE_TRASH {
+                    continue;
+                }
+                match fs::remove_dir_all(entry.path()) {
+                    Ok(()) => removed += 1,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        fn stale_temps(dir: &Path, removed: &mut usize) -> io::Result<()> {
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            for entry in entries {
+                let entry = entry?;
+                let metadata = entry.metadata()?;
+                if metadata.is_dir() {
+                    stale_temps(&entry.path(), removed)?;
+                } else if is_temp(&entry.file_name().to_string_lossy())
+                    && metadata
+                        .modified()
+                        .is_ok_and(|m| m.elapsed().unwrap_or_default() > STALE_TEMP)
+                {
+                    fs::remove_file(entry.path())?;
+                    *removed += 1;
+                }
+            }
+            Ok(())
+        }
+        for dir in [store::ORIGINALS_DIR, store::VARIANTS_DIR] {
+            stale_temps(&self.root.join(dir), &mut removed)?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maintenance_leaves_fresh_trash() {
+        let root = std::env::temp_dir().join(format!("fs-trash-{}", std::process::id()));
+        let storage = FsStorage::new(&root);
+        let trash = root.join(TRASH_DIR);
+        let fresh = trash.join(trash_name("out/ab/cd/x"));
+        let hour_ago = SystemTime::now() - STALE_TRASH - Duration::from_secs(60);
+        let nanos = hour_ago.duration_since(UNIX_EPOCH).unwrap().as_nanos();
+        let stale = trash.join(format!("{nanos}-1-0-out_ab_cd_y"));
+        for dir in [&fresh, &stale] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join("a.png"), b"a").unwrap();
+        }
+        assert_ne!(trash_name("x"), trash_name("x"));
+
+        assert_eq!(storage.maintenance().unwrap(), 1);
+        assert!(fresh.exists());
+        assert!(!stale.exists());
+        let _ = fs::remove_dir_all(root);
+    }
+}
@@ -0,0 +1,92 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Cursor, Read},
+    sync::{Arc, Mutex, MutexGuard},
+    time::SystemTime,
+};
+
+use super::{ObjectMeta, Storage};
+
+/// bytes and the time they were last written or touched, by key.
+type Objects = BTreeMap<String, (Arc<Vec<u8>>, SystemTime)>;
+
+/// keeps everything in a map, for tests and throwaway runs.
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: Mutex<Objects>,
+}
+
+impl MemoryStorage {
+    fn objects(&self) -> MutexGuard<'_, Objects> {
+        self.objects
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// lets a reader hold on to the bytes after the lock is released.
+struct SharedBytes(Arc<Vec<u8>>);
+
+impl AsRef<[u8]> for SharedBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+        self.objects().insert(
+            key.to_string(),
+            (Arc::new(bytes.to_vec()), SystemTime::now()),
+        );
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Box<dyn Read + Send>> {
+        match self.objects().get(key) {
+            Some((bytes, _)) => Ok(Box::new(Cursor::new(SharedBytes(bytes.clone())))),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, key.to_string())),
+        }
+    }
+
+    fn delete(&self, key: &str) -> io::Result<bool> {
+        Ok(self.objects().remove(key).is_some())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut objects = self.objects();
+        let object = objects
+            .remove(from)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, from.to_string()))?;
+        objects.insert(to.to_string(), object);
+        Ok(())
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<ObjectMeta>> {
+        Ok(self
+            .objects()
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (bytes, modified))| ObjectMeta {
+                key: key.clone(),
+                size: bytes.len() as u64,
+                modified: *modified,
+            })
+            .collect())
+    }
+
+    fn stat(&self, key: &str) -> io::Result<Option<ObjectMeta>> {
+        Ok(self.objects().get(key).map(|(bytes, modified)| ObjectMeta {
+            key: key.to_string(),
+            size: bytes.len() as u64,
+            modified: *modified,
+        }))
+    }
+
+    fn touch(&self, key: &str) -> io::Result<()> {
+        if let Some((_, modified)) = self.objects().get_mut(key) {
+            *modified = SystemTime::now();
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,181 @@
+use std::{
+    io::{self, Read},
+    path::Path,
+    sync::Arc,
+    time::SystemTime,
+};
+
+mod fs;
+mod memory;
+mod s3;
+
+pub use self::fs::FsStorage;
+pub use self::memory::MemoryStorage;
+pub use self::s3::{S3Config, S3Storage};
+
+/// one stored object, keys are `/` separated like `public/ab/cd/{id}`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectMeta {
+    pub key: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// where originals, records and variants live.
+/// `put` replaces the whole object at once, readers never see half of it.
+pub trait Storage: Send + Sync {
+    fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()>;
+
+    /// `NotFound` when there is no such object.
+    fn get(&self, key: &str) -> io::Result<Box<dyn Read + Send>>;
+
+    /// returns whether the object was there.
+    fn delete(&self, key: &str) -> io::Result<bool>;
+
+    /// every object whose key starts with `prefix`, in no particular order.
+    fn list(&self, prefix: &str) -> io::Result<Vec<ObjectMeta>>;
+
+    fn stat(&self, key: &str) -> io::Result<Option<ObjectMeta>>;
+
+    fn exists(&self, key: &str) -> io::Result<bool> {
+        Ok(self.stat(key)?.is_some())
+    }
+
+    fn read(&self, key: &str) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![];
+        self.get(key)?.read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// moves `from` to `to`, `NotFound` when there is no `from`.
+    /// the default copies and then deletes, a failure leaves `from` where it was.
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let bytes = self.read(from)?;
+        self.put(to, &bytes)?;
+        self.delete(from)?;
+        Ok(())
+    }
+
+    /// removes everything under `prefix`, returns how many objects went.
+    fn delete_prefix(&self, prefix: &str) -> io::Result<usize> {
+        let mut deleted = 0;
+        for object in self.list(prefix)? {
+            if self.delete(&object.key)? {
+                deleted += 1;
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// records a read of `key`, retention evicts the least recently used variants.
+    /// backends that cannot do it cheaply keep the write time instead.
+    fn touch(&self, _key: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// backend specific cleanup run by each retention sweep, returns what it removed.
+    fn maintenance(&self) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+/// which [`Storage`] the server runs on, see [`crate::config::Config`].
+#[derive(Debug, Clone)]
+pub enum Backend {
+    /// files under the data directory, the default.
+    Fs,
+    /// gone with the process, for tests and demos.
+    Memory,
+    S3(S3Config),
+}
+
+/// `data_dir` is the root of the fs storage, the other backends do not use it.
+pub fn open(backend: &Backend, data_dir: &Path) -> Arc<dyn Storage> {
+    match backend {
+        Backend::Fs => Arc::new(FsStorage::new(data_dir)),
+        Backend::Memory => Arc::new(MemoryStorage::default()),
+        Backend::S3(config) => Arc::new(S3Storage::new(config.clone())),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// what every backend has to honour.
+    fn contract(storage: &dyn Storage) {
+        assert!(!storage.exists("public/ab/cd/x").unwrap());
+        assert_eq!(
+            storage.get("public/ab/cd/x").err().map(|e| e.kind()),
+            Some(io::ErrorKind::NotFound)
+        );
+
+        storage.put("public/ab/cd/x", b"first").unwrap();
+        storage.put("public/ab/cd/x", b"second").unwrap();
+        storage.put("out/ab/cd/x/a.png", b"a").unwrap();
+        storage.put("out/ab/cd/x/b.png", b"bb").unwrap();
+        storage.put("out/ab/cd/y/a.png", b"c").unwrap();
+        assert_eq!(storage.read("public/ab/cd/x").unwrap(), b"second");
+        assert_eq!(storage.stat("out/ab/cd/x/b.png").unwrap().unwrap().size, 2);
+
+        let mut keys: Vec<_> = storage
+            .list("out/ab/cd/x/")
+            .unwrap()
+            .into_iter()
+            .map(|o| o.key)
+            .collect();
+        keys.sort();
+        assert_eq!(keys, ["out/ab/cd/x/a.png", "out/ab/cd/x/b.png"]);
+        assert_eq!(storage.list("nothing/").unwrap(), []);
+
+        storage
+            .rename("out/ab/cd/x/b.png", "out/ab/cd/z/b.png")
+            .unwrap();
+        assert!(!storage.exists("out/ab/cd/x/b.png").unwrap());
+        assert_eq!(storage.read("out/ab/cd/z/b.png").unwrap(), b"bb");
+        storage
+            .rename("out/ab/cd/z/b.png", "out/ab/cd/x/b.png")
+            .unwrap();
+        assert_eq!(
+            storage
+                .rename("out/ab/cd/z/b.png", "out/ab/cd/x/c.png")
+                .err()
+                .map(|e| e.kind()),
+            Some(io::ErrorKind::NotFound)
+        );
+
+        assert_eq!(storage.delete_prefix("out/ab/cd/x/").unwrap(), 2);
+        assert!(storage.exists("out/ab/cd/y/a.png").unwrap());
+        assert!(storage.delete("public/ab/cd/x").unwrap());
+        assert!(!storage.delete("public/ab/cd/x").unwrap());
+        assert!(storage.delete("out/ab/cd/y/a.png").unwrap());
+    }
+
+    #[test]
+    fn memory_storage_contract() {
+        contract(&MemoryStorage::default());
+    }
+
+    #[test]
+    fn fs_storage_contract() {
+        let root = std::env::temp_dir().join(format!("storage-contract-{}", std::process::id()));
+        contract(&FsStorage::new(&root));
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    /// against a real bucket, a throwaway MinIO one will do:
+    /// `S3_ENDPOINT=http://localhost:9000 S3_BUCKET=contract S3_ACCESS_KEY_ID=…
+    /// S3_SECRET_ACCESS_KEY=… cargo test s3_storage_contract -- --ignored`
+    #[test]
+    #[ignore = "needs an S3 bucket, configured from S3_* variables"]
+    fn s3_storage_contract() {
+        let var = |name: &str| std::env::var(name).unwrap_or_else(|_| panic!("{name} is not set"));
+        contract(&S3Storage::new(S3Config {
+            endpoint: var("S3_ENDPOINT").trim_end_matches('/').to_string(),
+            bucket: var("S3_BUCKET"),
+            region: std::env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string()),
+            access_key: var("S3_ACCESS_KEY_ID"),
+            secret_key: crate::config::Secret::new(var("S3_SECRET_ACCESS_KEY")),
+        }));
+    }
+}
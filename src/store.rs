@@ -0,0 +1,233 @@
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use sha2::{Digest, Sha256};
+
+use crate::listing;
+use crate::record;
+use crate::storage::Storage;
+
+pub const ORIGINALS_DIR: &str = "public";
+pub const VARIANTS_DIR: &str = "out";
+
+/// hex SHA-256 of the uploaded bytes, the same picture always gets the same id.
+pub fn content_id(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .fold(String::with_capacity(64), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+pub fn is_content_id(id: &str) -> bool {
+    id.len() == 64 && id.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// `ab/cd/abcd…`, two levels so no directory grows past 65k entries.
+fn sharded(root: &str, id: &str) -> String {
+    format!("{root}/{}/{}/{id}", &id[0..2], &id[2..4])
+}
+
+pub fn original_key(id: &str) -> String {
+    sharded(ORIGINALS_DIR, id)
+}
+
+/// every variant of `id` lives under this prefix.
+pub fn variants_prefix(id: &str) -> String {
+    format!("{}/", sharded(VARIANTS_DIR, id))
+}
+
+pub fn variant_key(id: &str, name: &str) -> String {
+    format!("{}{name}", variants_prefix(id))
+}
+
+/// the ETag a variant is served with sits next to it, under `{variant_key}.etag`.
+pub const ETAG_SUFFIX: &str = ".etag";
+
+pub fn etag_key(variant_key: &str) -> String {
+    format!("{variant_key}{ETAG_SUFFIX}")
+}
+
+/// stores `bytes` as the original of `id` unless it is already there,
+/// returns whether it was new.
+pub fn put_original(storage: &dyn Storage, id: &str, bytes: &[u8]) -> io::Result<bool> {
+    let key = original_key(id);
+    if storage.exists(&key)? {
+        return Ok(false);
+    }
+    storage.put(&key, bytes)?;
+    Ok(true)
+}
+
+/// two requests may write the same file at once,
+/// each writes its own temp file and the last rename wins.
+pub fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension(format!(
+        "tmp-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })
+}
+
+/// where [`delete`] moves an original before removing anything else.
+pub const DELETED_DIR: &str = "deleted";
+
+pub fn deleted_key(id: &str) -> String {
+    format!("{DELETED_DIR}/{id}")
+}
+
+/// removes the original of `id`, its variants, listing entries and record,
+/// returns whether it existed.
+/// moving the original aside is the commit point: from then on readers find no image,
+/// while the record still names its owners, so only they can retry a delete that failed
+/// after it. the record goes last, then the moved original, whose leftover tells the
+/// retention sweep to finish the delete. the variants are best effort, the sweep removes
+/// those of a missing original too.
+pub fn delete(storage: &dyn Storage, id: &str) -> io::Result<bool> {
+    let aside = deleted_key(id);
+    let had_original = match storage.rename(&original_key(id), &aside) {
+        Ok(()) => true,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+        Err(err) => return Err(err),
+    };
+    if let Err(err) = storage.delete_prefix(&variants_prefix(id)) {
+        println!(">> delete {id}: variants left for the sweep: {err}");
+    }
+    let record = record::load(storage, id);
+    listing::reindex(storage, id, record.as_ref(), None)?;
+    let had_record = storage.delete(&record::key(id))?;
+    if let Err(err) = storage.delete(&aside) {
+        println!(">> delete {id}: original left for the sweep: {err}");
+    }
+    Ok(had_record || had_original)
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Read, sync::Mutex};
+
+    use super::*;
+    use crate::storage::{MemoryStorage, ObjectMeta};
+
+    /// picks the keys a [`Stuck`] storage fails on.
+    type Pick = fn(&str) -> bool;
+
+    /// fails to change the keys `stuck` picks, like a backend going away half way.
+    #[derive(Default)]
+    struct Stuck {
+        inner: MemoryStorage,
+        stuck: Mutex<Option<Pick>>,
+    }
+
+    impl Stuck {
+        fn check(&self, key: &str) -> io::Result<()> {
+            match *self.stuck.lock().unwrap() {
+                Some(stuck) if stuck(key) => Err(io::Error::other("storage went away")),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    impl Storage for Stuck {
+        fn put(&self, key: &str, bytes: &[u8]) -> io::Result<()> {
+            self.check(key)?;
+            self.inner.put(key, bytes)
+        }
+
+        fn get(&self, key: &str) -> io::Result<Box<dyn Read + Send>> {
+            self.inner.get(key)
+        }
+
+        fn delete(&self, key: &str) -> io::Result<bool> {
+            self.check(key)?;
+            self.inner.delete(key)
+        }
+
+        fn list(&self, prefix: &str) -> io::Result<Vec<ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+
+        fn stat(&self, key: &str) -> io::Result<Option<ObjectMeta>> {
+            self.inner.stat(key)
+        }
+    }
+
+    fn stored(storage: &dyn Storage) -> (String, Vec<String>) {
+        let id = content_id(b"pixels");
+        storage.put(&original_key(&id), b"pixels").unwrap();
+        record::update(storage, &id, |record| {
+            *record = Some(serde_json::from_str(
+                r#"{"uploaded_at": 1, "owners": ["alice"]}"#,
+            )?);
+            Ok(())
+        })
+        .unwrap();
+        let variants = vec![variant_key(&id, "a.png"), variant_key(&id, "b.png")];
+        for key in &variants {
+            storage.put(key, b"variant").unwrap();
+        }
+        (id, variants)
+    }
+
+    #[test]
+    fn deletes_everything_of_an_image() {
+        let storage = MemoryStorage::default();
+        let (id, variants) = stored(&storage);
+        let other = variant_key(&content_id(b"other"), "a.png");
+        storage.put(&other, b"variant").unwrap();
+
+        assert!(delete(&storage, &id).unwrap());
+        assert!(!storage.exists(&original_key(&id)).unwrap());
+        assert!(!storage.exists(&record::key(&id)).unwrap());
+        assert!(!storage.exists(&deleted_key(&id)).unwrap());
+        for key in &variants {
+            assert!(!storage.exists(key).unwrap());
+        }
+        assert_eq!(
+            storage.list(&format!("{}/", listing::INDEX_DIR)).unwrap(),
+            []
+        );
+        assert!(storage.exists(&other).unwrap());
+        assert!(!delete(&storage, &id).unwrap());
+    }
+
+    #[test]
+    fn the_original_goes_first() {
+        let storage = Stuck::default();
+        let (id, variants) = stored(&storage);
+
+        //? nothing moved, nothing lost
+        *storage.stuck.lock().unwrap() = Some(|key| key.starts_with(ORIGINALS_DIR));
+        assert!(delete(&storage, &id).is_err());
+        assert!(storage.exists(&original_key(&id)).unwrap());
+        assert!(record::load(&storage, &id).is_some());
+
+        //? gone for readers, still owned for the retry
+        *storage.stuck.lock().unwrap() = Some(|key| key.ends_with(".json"));
+        assert!(delete(&storage, &id).is_err());
+        assert!(!storage.exists(&original_key(&id)).unwrap());
+        assert!(record::load(&storage, &id).unwrap().is_owned_by("alice"));
+        for key in &variants {
+            assert!(!storage.exists(key).unwrap());
+        }
+
+        *storage.stuck.lock().unwrap() = None;
+        assert!(delete(&storage, &id).unwrap());
+        assert!(!storage.exists(&record::key(&id)).unwrap());
+        assert!(!storage.exists(&deleted_key(&id)).unwrap());
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Condvar, Mutex, MutexGuard, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// what a client is told to wait when every decode slot is taken, renders are short.
+pub const BUSY_RETRY_AFTER: Duration = Duration::from_secs(1);
+/// past this many clients, the buckets that refilled are forgotten.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+static DECODES: OnceLock<Decodes> = OnceLock::new();
+
+/// how hard one client may push, and how many images decode at once.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// uploads refill at this rate per client IP.
+    pub uploads_per_minute: u32,
+    /// uploads a client can make back to back before the rate applies.
+    pub upload_burst: u32,
+    /// shared by the job workers and the on-demand renders of `/img`.
+    pub max_decodes: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            uploads_per_minute: 30,
+            upload_burst: 10,
+            max_decodes: thread::available_parallelism().map_or(2, |n| n.get()),
+        }
+    }
+}
+
+/// whole seconds for a `Retry-After` header, never 0.
+pub fn retry_after_secs(wait: Duration) -> u64 {
+    (wait.as_secs() + u64::from(wait.subsec_nanos() > 0)).max(1)
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// a token bucket per client IP.
+pub struct RateLimiter {
+    per_second: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(per_minute: u32, burst: u32) -> Self {
+        Self {
+            per_second: per_minute as f64 / 60.0,
+            burst: burst as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// takes a token from `client`, or says how long until the next one.
+    pub fn check(&self, client: IpAddr) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: IpAddr, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self
+            .buckets
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            //? a full bucket is the same as no bucket
+            let (per_second, burst) = (self.per_second, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second
+                    < burst
+            });
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let refilled = now.duration_since(bucket.updated).as_secs_f64() * self.per_second;
+        bucket.tokens = (bucket.tokens + refilled).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.per_second,
+            ))
+        }
+    }
+}
+
+/// what to do when every decode slot is taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    /// background work, it can take its turn.
+    Wait,
+    /// a request, the client is better off retrying than hanging.
+    Reject,
+}
+
+#[derive(Debug)]
+pub struct Busy;
+
+impl fmt::Display for Busy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "too many images are being processed, try again shortly")
+    }
+}
+
+impl std::error::Error for Busy {}
+
+/// a counting semaphore over full decodes, they are what eats the CPU and memory.
+pub struct Decodes {
+    max: usize,
+    running: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// a decode slot, given back on drop.
+pub struct Permit<'a> {
+    decodes: &'a Decodes,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.decodes.running() -= 1;
+        self.decodes.freed.notify_one();
+    }
+}
+
+impl Decodes {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            running: Mutex::new(0),
+            freed: Condvar::new(),
+        }
+    }
+
+    fn running(&self) -> MutexGuard<'_, usize> {
+        self.running
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn admit(&self, admission: Admission) -> Result<Permit<'_>, Busy> {
+        let mut running = self.running();
+        while *running >= self.max {
+            if admission == Admission::Reject {
+                return Err(Busy);
+            }
+            running = self
+                .freed
+                .wait(running)
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        *running += 1;
+        Ok(Permit { decodes: self })
+    }
+}
+
+/// the cap set at startup, see [`Limits::max_decodes`].
+pub fn set_max_decodes(max: usize) {
+    let _ = DECODES.set(Decodes::new(max));
+}
+
+pub fn decodes() -> &'static Decodes {
+    DECODES.get_or_init(|| Decodes::new(Limits::default().max_decodes))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_per_client() {
+        let limiter = RateLimiter::new(60, 2);
+        let (a, b) = ("10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap());
+        let start = Instant::now();
+        assert!(limiter.check_at(a, start).is_ok());
+        assert!(limiter.check_at(a, start).is_ok());
+        let wait = limiter.check_at(a, start).unwrap_err();
+        assert_eq!(retry_after_secs(wait), 1);
+        //? another client has its own bucket
+        assert!(limiter.check_at(b, start).is_ok());
+
+        assert!(limiter
+            .check_at(a, start + Duration::from_millis(1_500))
+            .is_ok());
+        assert!(limiter
+            .check_at(a, start + Duration::from_millis(1_500))
+            .is_err());
+        //? an idle client gets the burst back, no more
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.check_at(a, later).is_ok());
+        assert!(limiter.check_at(a, later).is_ok());
+        assert!(limiter.check_at(a, later).is_err());
+    }
+
+    #[test]
+    fn caps_concurrent_decodes() {
+        let decodes = Decodes::new(2);
+        let first = decodes.admit(Admission::Reject).unwrap();
+        let _second = decodes.admit(Admission::Reject).unwrap();
+        assert!(decodes.admit(Admission::Reject).is_err());
+        drop(first);
+        assert!(decodes.admit(Admission::Reject).is_ok());
+
+        let decodes = std::sync::Arc::new(Decodes::new(1));
+        let held = decodes.admit(Admission::Wait).unwrap();
+        let waiter = {
+            let decodes = decodes.clone();
+            thread::spawn(move || decodes.admit(Admission::Wait).is_ok())
+        };
+        thread::sleep(Duration::from_millis(20));
+        drop(held);
+        assert!(waiter.join().unwrap());
+    }
+}
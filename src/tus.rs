@@ -0,0 +1,424 @@
+use std::{
+    collections::{hash_map::RandomState, BTreeMap, BTreeSet},
+    fmt,
+    fs::{self, OpenOptions},
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::{Duration, SystemTime},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::{record, store};
+
+/// partial uploads, on the local disk like the jobs: `{id}.json` and `{id}.bin`.
+pub const UPLOADS_DIR: &str = "uploads";
+/// the only protocol version spoken, see <https://tus.io/protocols/resumable-upload>.
+pub const TUS_VERSION: &str = "1.0.0";
+pub const TUS_EXTENSIONS: &str = "creation,termination";
+/// an upload nobody appended to for this long is dropped by the retention sweep.
+pub const STALE_UPLOAD_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// a `Upload-Metadata` header is a few short pairs, not a payload.
+const MAX_METADATA_BYTES: usize = 4_096;
+
+#[derive(Debug)]
+pub enum TusError {
+    BadVersion(String),
+    MissingLength,
+    BadLength(String),
+    TooLarge { length: u64, limit: u64 },
+    BadMetadata,
+    NotFound,
+    BadContentType,
+    MissingOffset,
+    OffsetMismatch { expected: u64, given: u64 },
+    PastLength { remaining: u64 },
+    Locked,
+    Io(io::Error),
+}
+
+impl fmt::Display for TusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TusError::BadVersion(version) => {
+                write!(f, "`Tus-Resumable` must be {TUS_VERSION}, got {version:?}")
+            }
+            TusError::MissingLength => write!(
+                f,
+                "`Upload-Length` is required, deferred lengths are not supported"
+            ),
+            TusError::BadLength(value) => write!(
+                f,
+                "`Upload-Length` must be a number of bytes greater than 0, got {value:?}"
+            ),
+            TusError::TooLarge { length, limit } => {
+                write!(f, "{length} bytes is over the {limit} bytes limit")
+            }
+            TusError::BadMetadata => write!(
+                f,
+                "`Upload-Metadata` must be comma separated `key base64value` pairs"
+            ),
+            TusError::NotFound => write!(f, "no such upload"),
+            TusError::BadContentType => {
+                write!(f, "`Content-Type` must be application/offset+octet-stream")
+            }
+            TusError::MissingOffset => {
+                write!(f, "`Upload-Offset` must be a number of bytes")
+            }
+            TusError::OffsetMismatch { expected, given } => write!(
+                f,
+                "`Upload-Offset` is {given} but the upload is at {expected}"
+            ),
+            TusError::PastLength { remaining } => {
+                write!(
+                    f,
+                    "the body goes past `Upload-Length`, {remaining} bytes are left"
+                )
+            }
+            TusError::Locked => write!(f, "the upload is being written by another request"),
+            TusError::Io(err) => write!(f, "cannot store the upload: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for TusError {}
+
+impl TusError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            TusError::BadVersion(_) => 412,
+            TusError::MissingLength
+            | TusError::BadLength(_)
+            | TusError::BadMetadata
+            | TusError::MissingOffset => 400,
+            TusError::TooLarge { .. } | TusError::PastLength { .. } => 413,
+            TusError::NotFound => 404,
+            TusError::BadContentType => 415,
+            TusError::OffsetMismatch { .. } => 409,
+            TusError::Locked => 423,
+            TusError::Io(_) => 500,
+        }
+    }
+}
+
+/// `Tus-Resumable` on every request but `OPTIONS`.
+pub fn check_version(header: Option<&str>) -> Result<(), TusError> {
+    match header.map(str::trim) {
+        Some(TUS_VERSION) => Ok(()),
+        other => Err(TusError::BadVersion(other.unwrap_or("").to_string())),
+    }
+}
+
+/// `Upload-Length`, capped at `limit`.
+pub fn parse_length(header: Option<&str>, limit: u64) -> Result<u64, TusError> {
+    let value = header.map(str::trim).ok_or(TusError::MissingLength)?;
+    match value.parse::<u64>() {
+        Ok(length) if length > limit => Err(TusError::TooLarge { length, limit }),
+        Ok(length) if length > 0 => Ok(length),
+        _ => Err(TusError::BadLength(value.to_string())),
+    }
+}
+
+/// `Upload-Offset`.
+pub fn parse_offset(header: Option<&str>) -> Result<u64, TusError> {
+    header
+        .and_then(|value| value.trim().parse().ok())
+        .ok_or(TusError::MissingOffset)
+}
+
+/// `key base64,key base64`, a key alone has an empty value.
+pub fn parse_metadata(header: Option<&str>) -> Result<BTreeMap<String, String>, TusError> {
+    let mut metadata = BTreeMap::new();
+    let Some(header) = header.filter(|header| !header.trim().is_empty()) else {
+        return Ok(metadata);
+    };
+    if header.len() > MAX_METADATA_BYTES {
+        return Err(TusError::BadMetadata);
+    }
+    for pair in header.split(',') {
+        let mut parts = pair.split_whitespace();
+        let key = parts.next().ok_or(TusError::BadMetadata)?;
+        let value = match parts.next() {
+            Some(value) => STANDARD
+                .decode(value)
+                .ok()
+                .and_then(|bytes| String::from_utf8(bytes).ok())
+                .ok_or(TusError::BadMetadata)?,
+            None => String::new(),
+        };
+        if parts.next().is_some() || metadata.insert(key.to_string(), value).is_some() {
+            return Err(TusError::BadMetadata);
+        }
+    }
+    Ok(metadata)
+}
+
+/// the metadata back as a header, for `HEAD`.
+pub fn encode_metadata(metadata: &BTreeMap<String, String>) -> String {
+    metadata
+        .iter()
+        .map(|(key, value)| {
+            if value.is_empty() {
+                key.clone()
+            } else {
+                format!("{key} {}", STANDARD.encode(value))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// what was announced when the upload was created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Info {
+    pub length: u64,
+    pub metadata: BTreeMap<String, String>,
+    /// only the same owner may continue, inspect or cancel it.
+    pub owner: Option<String>,
+    pub created_at: u64,
+}
+
+pub fn is_upload_id(id: &str) -> bool {
+    id.len() == 32
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase())
+}
+
+fn new_id() -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    //? the id is all a client needs to append, it must not be guessable
+    let random = RandomState::new().build_hasher().finish();
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let seed = format!(
+        "{random}-{nanos}-{}-{}",
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    );
+    store::content_id(seed.as_bytes())[..32].to_string()
+}
+
+fn info_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.json"))
+}
+
+fn data_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.bin"))
+}
+
+/// the uploads a request is writing to.
+static WRITING: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+fn writing() -> MutexGuard<'static, BTreeSet<String>> {
+    WRITING
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// an upload being written, released on drop.
+struct Writing {
+    id: String,
+}
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        writing().remove(&self.id);
+    }
+}
+
+/// one request at a time per upload, two appends at the same offset would interleave.
+fn lock(id: &str) -> Result<Writing, TusError> {
+    if !writing().insert(id.to_string()) {
+        return Err(TusError::Locked);
+    }
+    Ok(Writing { id: id.to_string() })
+}
+
+/// an empty upload of `length` bytes in `dir` (see [`UPLOADS_DIR`]), returns its id.
+pub fn create(
+    dir: &Path,
+    length: u64,
+    metadata: BTreeMap<String, String>,
+    owner: Option<String>,
+) -> Result<String, TusError> {
+    let id = new_id();
+    fs::create_dir_all(dir).map_err(TusError::Io)?;
+    //? the data first, an info file always has its data next to it
+    fs::write(data_path(dir, &id), b"").map_err(TusError::Io)?;
+    let info = Info {
+        length,
+        metadata,
+        owner,
+        created_at: record::now(),
+    };
+    let json = serde_json::to_vec_pretty(&info).expect("info serializes");
+    store::write_atomically(&info_path(dir, &id), &json).map_err(TusError::Io)?;
+    Ok(id)
+}
+
+/// the upload and how many of its bytes arrived.
+pub fn get(dir: &Path, id: &str) -> Result<(Info, u64), TusError> {
+    if !is_upload_id(id) {
+        return Err(TusError::NotFound);
+    }
+    let not_found = |err: io::Error| match err.kind() {
+        io::ErrorKind::NotFound => TusError::NotFound,
+        _ => TusError::Io(err),
+    };
+    let json = fs::read(info_path(dir, id)).map_err(not_found)?;
+    let info: Info = serde_json::from_slice(&json)
+        .map_err(|err| TusError::Io(io::Error::new(io::ErrorKind::InvalidData, err)))?;
+    //? the file is the offset, whatever a request managed to write before it broke
+    let offset = fs::metadata(data_path(dir, id)).map_err(not_found)?.len();
+    Ok((info, offset))
+}
+
+/// writes `body` at `offset`, which has to be where the upload is.
+/// returns the new offset; bytes read before a broken connection are kept.
+pub fn append(
+    dir: &Path,
+    id: &str,
+    offset: u64,
+    content_length: Option<u64>,
+    body: &mut dyn Read,
+) -> Result<u64, TusError> {
+    let _writing = lock(id)?;
+    let (info, current) = get(dir, id)?;
+    if offset != current {
+        return Err(TusError::OffsetMismatch {
+            expected: current,
+            given: offset,
+        });
+    }
+    let remaining = info.length.saturating_sub(current);
+    if content_length.is_some_and(|length| length > remaining) {
+        return Err(TusError::PastLength { remaining });
+    }
+
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(data_path(dir, id))
+        .map_err(TusError::Io)?;
+    //? one byte more than fits, to notice a body without `Content-Length` that is too long
+    let copied = io::copy(&mut body.take(remaining + 1), &mut file);
+    let flushed = file.flush().and_then(|()| file.sync_data());
+    let copied = copied.map_err(TusError::Io)?;
+    flushed.map_err(TusError::Io)?;
+    if copied > remaining {
+        file.set_len(current).map_err(TusError::Io)?;
+        return Err(TusError::PastLength { remaining });
+    }
+    Ok(current + copied)
+}
+
+/// the bytes of a complete upload.
+pub fn read(dir: &Path, id: &str) -> Result<Vec<u8>, TusError> {
+    fs::read(data_path(dir, id)).map_err(TusError::Io)
+}
+
+/// termination, returns whether there was such an upload.
+pub fn remove(dir: &Path, id: &str) -> Result<bool, TusError> {
+    if !is_upload_id(id) {
+        return Ok(false);
+    }
+    let _writing = lock(id)?;
+    let mut removed = false;
+    for path in [info_path(dir, id), data_path(dir, id)] {
+        match fs::remove_file(path) {
+            Ok(()) => removed = true,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(TusError::Io(err)),
+        }
+    }
+    Ok(removed)
+}
+
+/// removes the uploads nothing was appended to for `max_age`, returns how many went.
+pub fn prune_stale(dir: &Path, max_age: Duration) -> io::Result<usize> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    //? an upload is as fresh as the newest of its two files
+    let mut touched: BTreeMap<String, SystemTime> = BTreeMap::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some((id, _)) = name.split_once('.') else {
+            continue;
+        };
+        if !is_upload_id(id) {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        let newest = touched.entry(id.to_string()).or_insert(modified);
+        *newest = (*newest).max(modified);
+    }
+    let mut pruned = 0;
+    for (id, modified) in touched {
+        if modified.elapsed().unwrap_or_default() > max_age {
+            let _ = fs::remove_file(info_path(dir, &id));
+            let _ = fs::remove_file(data_path(dir, &id));
+            pruned += 1;
+        }
+    }
+    Ok(pruned)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_headers() {
+        assert!(check_version(Some("1.0.0")).is_ok());
+        assert_eq!(check_version(Some("0.2.2")).unwrap_err().status_code(), 412);
+        assert!(check_version(None).is_err());
+
+        assert_eq!(parse_length(Some("100"), 100).unwrap(), 100);
+        assert!(matches!(
+            parse_length(Some("101"), 100),
+            Err(TusError::TooLarge { .. })
+        ));
+        assert!(matches!(
+            parse_length(Some("0"), 100),
+            Err(TusError::BadLength(_))
+        ));
+        assert!(matches!(
+            parse_length(None, 100),
+            Err(TusError::MissingLength)
+        ));
+        assert_eq!(parse_offset(Some(" 42")).unwrap(), 42);
+        assert!(parse_offset(Some("-1")).is_err());
+
+        let metadata = parse_metadata(Some(
+            "filename d29ybGRfZG9taW5hdGlvbl9wbGFuLnBkZg==,is_confidential,w MzIw",
+        ))
+        .unwrap();
+        assert_eq!(metadata["filename"], "world_domination_plan.pdf");
+        assert_eq!(metadata["is_confidential"], "");
+        assert_eq!(metadata["w"], "320");
+        assert_eq!(
+            parse_metadata(Some(&encode_metadata(&metadata))).unwrap(),
+            metadata
+        );
+        assert!(parse_metadata(None).unwrap().is_empty());
+        assert!(parse_metadata(Some("w !!!")).is_err());
+        assert!(parse_metadata(Some("w MzIw,w MzIw")).is_err());
+        assert!(parse_metadata(Some("w MzIw extra")).is_err());
+
+        assert!(is_upload_id(&new_id()));
+        assert_ne!(new_id(), new_id());
+        assert!(!is_upload_id("../../etc/passwd"));
+    }
+}
@@ -0,0 +1,179 @@
+use std::{fmt, io};
+
+use serde::Serialize;
+
+use crate::jobs::{self, JobError, JobRef, Task};
+use crate::metadata;
+use crate::presets::Presets;
+use crate::record::{self, Record};
+use crate::storage::Storage;
+use crate::store;
+use crate::validate::{self, ValidationError};
+use crate::variant::Variant;
+
+#[derive(Debug, Serialize)]
+pub struct Upload {
+    pub id: String,
+    /// the same bytes were uploaded before, nothing new was stored.
+    pub duplicate: bool,
+    pub original: Original,
+    /// the variants are rendered in the background, the job tells when they are ready.
+    /// `None` when it could not be queued, `/img` still renders them on demand.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job: Option<JobRef>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_error: Option<JobFailure>,
+}
+
+/// why an upload that was stored has no job.
+#[derive(Debug, Serialize)]
+pub struct JobFailure {
+    pub code: &'static str,
+    pub message: String,
+    /// uploading the same bytes again stores nothing new and queues another job.
+    pub retryable: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Original {
+    pub format: &'static str,
+    pub width: u32,
+    pub height: u32,
+    pub frames: u32,
+    pub bytes: usize,
+    pub url: String,
+}
+
+#[derive(Debug)]
+pub enum UploadError {
+    Empty,
+    Rejected(ValidationError),
+    Storage(io::Error),
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UploadError::Empty => write!(f, "the file is empty"),
+            UploadError::Rejected(err) => err.fmt(f),
+            UploadError::Storage(err) => write!(f, "storage error: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl UploadError {
+    /// stable, machine friendly name for the json api.
+    pub fn code(&self) -> &'static str {
+        match self {
+            UploadError::Empty => "empty_file",
+            UploadError::Rejected(err) => err.code(),
+            UploadError::Storage(_) => "storage_error",
+        }
+    }
+
+    pub fn status_code(&self) -> u16 {
+        match self {
+            UploadError::Empty => 400,
+            UploadError::Rejected(err) => err.status_code(),
+            UploadError::Storage(_) => 500,
+        }
+    }
+}
+
+/// the requested `thumbnail` followed by every configured preset.
+pub fn tasks(thumbnail: &Variant, presets: &Presets) -> Vec<Task> {
+    std::iter::once(Task {
+        preset: None,
+        variant: thumbnail.clone(),
+    })
+    .chain(presets.iter().map(|preset| Task {
+        preset: Some(preset.name.clone()),
+        variant: preset.variant.clone(),
+    }))
+    .collect()
+}
+
+/// stores one uploaded file and queues the rendering of `tasks` from it.
+/// with `strip` the stored original loses its EXIF/XMP too, not only the variants.
+/// `owner` is added to the image's owners, a duplicate is shared rather than stolen.
+pub fn process(
+    storage: &dyn Storage,
+    bytes: &[u8],
+    tasks: &[Task],
+    strip: bool,
+    owner: Option<&str>,
+    queue: &jobs::Queue,
+) -> Result<Upload, UploadError> {
+    if bytes.is_empty() {
+        return Err(UploadError::Empty);
+    }
+    //? nothing is stored before the bytes pass the allow-list and size limits
+    let inspected = validate::inspect(bytes).map_err(UploadError::Rejected)?;
+
+    //? same bytes, same id: a duplicate upload reuses the stored original
+    let id = store::content_id(bytes);
+    let orientation = metadata::orientation(bytes);
+    let stored_bytes = if strip {
+        metadata::strip(bytes, inspected.format)
+    } else {
+        bytes.to_vec()
+    };
+    let is_new = store::put_original(storage, &id, &stored_bytes).map_err(UploadError::Storage)?;
+    record::update(storage, &id, |record| {
+        let Some(existing) = record else {
+            *record = Some(Record {
+                uploaded_at: record::now(),
+                orientation,
+                metadata_stripped: strip,
+                frames: inspected.frames,
+                phash: None,
+                placeholder: None,
+                owners: owner.map(str::to_string).into_iter().collect(),
+            });
+            return Ok(());
+        };
+        println!(">> duplicate upload {id}");
+        //? asking for privacy on a re-upload scrubs the copy we already had
+        if strip && !existing.metadata_stripped {
+            storage.put(&store::original_key(&id), &stored_bytes)?;
+            existing.metadata_stripped = true;
+        }
+        if let Some(owner) = owner.filter(|owner| !existing.is_owned_by(owner)) {
+            existing.owners.push(owner.to_string());
+        }
+        Ok(())
+    })
+    .map_err(UploadError::Storage)?;
+
+    //? decoding and resizing happen on the worker pool, not on this request thread.
+    //? the image is stored by now, a job that cannot be queued does not undo that
+    let (job, job_error) = match queue.submit(&id, tasks.to_vec()) {
+        Ok(job) => (Some(job), None),
+        Err(err) => {
+            println!(">> upload {id}: no job: {err}");
+            let failure = JobFailure {
+                code: err.code(),
+                message: err.to_string(),
+                retryable: matches!(err, JobError::QueueFull),
+            };
+            (None, Some(failure))
+        }
+    };
+
+    Ok(Upload {
+        original: Original {
+            format: validate::format_name(inspected.format),
+            width: inspected.width,
+            height: inspected.height,
+            frames: inspected.frames,
+            bytes: bytes.len(),
+            url: format!("/img/{id}"),
+        },
+        id,
+        duplicate: !is_new,
+        job,
+        job_error,
+    })
+}
@@ -0,0 +1,184 @@
+use std::{fmt, io::Cursor};
+
+use image::{io::Limits, ImageFormat};
+
+use crate::animation::{self, AnimationError};
+
+pub const MAX_WIDTH: u32 = 8_192;
+pub const MAX_HEIGHT: u32 = 8_192;
+/// 40 megapixels, ~160 MB once decoded to RGBA.
+pub const MAX_PIXELS: u64 = 40_000_000;
+
+/// the only formats we accept as uploads, checked against the leading bytes.
+const ALLOWED: [(ImageFormat, &str); 5] = [
+    (ImageFormat::Png, "png"),
+    (ImageFormat::Jpeg, "jpeg"),
+    (ImageFormat::Gif, "gif"),
+    (ImageFormat::WebP, "webp"),
+    (ImageFormat::Bmp, "bmp"),
+];
+
+#[derive(Debug)]
+pub enum ValidationError {
+    UnsupportedType,
+    TooLarge { width: u32, height: u32 },
+    Animation(AnimationError),
+    Corrupt(image::ImageError),
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::UnsupportedType => {
+                write!(f, "not an allowed image type, send png, jpeg, gif, webp or bmp")
+            }
+            ValidationError::TooLarge { width, height } => write!(
+                f,
+                "{width}x{height} is too large, the limit is {MAX_WIDTH}x{MAX_HEIGHT} and {MAX_PIXELS} pixels"
+            ),
+            ValidationError::Animation(err) => err.fmt(f),
+            ValidationError::Corrupt(err) => write!(f, "corrupt image: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl ValidationError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ValidationError::UnsupportedType => "unsupported_type",
+            ValidationError::TooLarge { .. } => "image_too_large",
+            ValidationError::Animation(AnimationError::TooManyFrames(_)) => "too_many_frames",
+            ValidationError::Animation(_) => "animation_too_large",
+            ValidationError::Corrupt(_) => "corrupt_image",
+        }
+    }
+
+    pub fn status_code(&self) -> u16 {
+        match self {
+            ValidationError::UnsupportedType => 415,
+            ValidationError::TooLarge { .. }
+            | ValidationError::Animation(_)
+            | ValidationError::Corrupt(_) => 422,
+        }
+    }
+}
+
+/// the format announced by the magic bytes, `None` when it is not on the allow-list.
+pub fn sniff(bytes: &[u8]) -> Option<ImageFormat> {
+    let format = match bytes {
+        [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, ..] => ImageFormat::Png,
+        [0xFF, 0xD8, 0xFF, ..] => ImageFormat::Jpeg,
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => ImageFormat::Gif,
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => ImageFormat::WebP,
+        [b'B', b'M', ..] => ImageFormat::Bmp,
+        _ => return None,
+    };
+    Some(format)
+}
+
+pub fn format_name(format: ImageFormat) -> &'static str {
+    ALLOWED
+        .iter()
+        .find(|(allowed, _)| *allowed == format)
+        .map(|(_, name)| *name)
+        .unwrap_or("unknown")
+}
+
+/// caps what the decoder may allocate, so a tiny file cannot
+/// expand into gigabytes of pixels (decompression bomb).
+pub fn decode_limits() -> Limits {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_WIDTH);
+    limits.max_image_height = Some(MAX_HEIGHT);
+    //? RGBA8 of the biggest image we allow plus some slack for the decoder
+    limits.max_alloc = Some(MAX_PIXELS * 4 + 64 * 1_024 * 1_024);
+    limits
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Inspected {
+    pub format: ImageFormat,
+    pub width: u32,
+    pub height: u32,
+    /// 1 unless the image is animated.
+    pub frames: u32,
+}
+
+/// checks type, dimensions and frame count from the headers only, nothing is decoded yet.
+pub fn inspect(bytes: &[u8]) -> Result<Inspected, ValidationError> {
+    let format = sniff(bytes).ok_or(ValidationError::UnsupportedType)?;
+    let (width, height) = image::io::Reader::with_format(Cursor::new(bytes), format)
+        .into_dimensions()
+        .map_err(ValidationError::Corrupt)?;
+    if width > MAX_WIDTH || height > MAX_HEIGHT || width as u64 * height as u64 > MAX_PIXELS {
+        return Err(ValidationError::TooLarge { width, height });
+    }
+    let frames = animation::count_frames(bytes, format);
+    animation::check(frames, width, height).map_err(ValidationError::Animation)?;
+    Ok(Inspected {
+        format,
+        width,
+        height,
+        frames,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sniffs_only_allowed_types() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n...."), Some(ImageFormat::Png));
+        assert_eq!(sniff(b"\xff\xd8\xff\xe0"), Some(ImageFormat::Jpeg));
+        assert_eq!(sniff(b"GIF89a"), Some(ImageFormat::Gif));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some(ImageFormat::WebP));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WAVEfmt "), None);
+        assert_eq!(sniff(b"<svg xmlns="), None);
+        assert_eq!(sniff(b"II*\0"), None);
+        assert_eq!(sniff(b""), None);
+    }
+
+    #[test]
+    fn rejects_huge_headers() {
+        //? a valid PNG header claiming 100k x 100k pixels, no pixel data needed
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&100_000u32.to_be_bytes());
+        png.extend_from_slice(&100_000u32.to_be_bytes());
+        png.extend_from_slice(&[8, 6, 0, 0, 0]);
+        let crc = crc32(&png[12..]);
+        png.extend_from_slice(&crc.to_be_bytes());
+        //? the decoder reads up to the first (here empty) IDAT chunk
+        png.extend_from_slice(b"\0\0\0\0IDAT");
+        png.extend_from_slice(&crc32(b"IDAT").to_be_bytes());
+
+        assert!(matches!(
+            inspect(&png),
+            Err(ValidationError::TooLarge {
+                width: 100_000,
+                height: 100_000
+            })
+        ));
+        assert!(matches!(
+            inspect(b"hello"),
+            Err(ValidationError::UnsupportedType)
+        ));
+    }
+
+    fn crc32(bytes: &[u8]) -> u32 {
+        let mut crc = 0xFFFF_FFFFu32;
+        for byte in bytes {
+            crc ^= *byte as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+            }
+        }
+        !crc
+    }
+}
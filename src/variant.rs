@@ -0,0 +1,545 @@
+use std::{collections::HashMap, fmt, io, io::Cursor};
+
+use image::{DynamicImage, Frame};
+use serde::{Deserialize, Serialize};
+
+use crate::animation::{self, AnimationError};
+use crate::format::{self, FormatError, OutputFormat};
+use crate::http_cache;
+use crate::metadata;
+use crate::metrics::{self, Stage};
+use crate::ops::{Ops, OpsError};
+use crate::overlay::{self, OverlayError};
+use crate::record;
+use crate::resize::{ResizeError, ResizeOptions};
+use crate::storage::Storage;
+use crate::store;
+use crate::throttle::{self, Admission, Busy};
+use crate::validate;
+
+/// any of these asks for a resized variant instead of the full size original.
+const RESIZE_PARAMS: [&str; 4] = ["w", "h", "fit", "filter"];
+
+#[derive(Debug)]
+pub enum VariantError {
+    Resize(ResizeError),
+    Format(FormatError),
+    Ops(OpsError),
+    Overlay(OverlayError),
+    Animation(AnimationError),
+    MissingOriginal,
+    Busy(Busy),
+    Io(io::Error),
+    Image(image::ImageError),
+}
+
+impl fmt::Display for VariantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VariantError::Resize(err) => err.fmt(f),
+            VariantError::Format(err) => err.fmt(f),
+            VariantError::Ops(err) => err.fmt(f),
+            VariantError::Overlay(err) => err.fmt(f),
+            VariantError::Animation(err) => err.fmt(f),
+            VariantError::MissingOriginal => write!(f, "no original for that image"),
+            VariantError::Busy(err) => err.fmt(f),
+            VariantError::Io(err) => write!(f, "storage error: {err}"),
+            VariantError::Image(err) => write!(f, "could not process image: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for VariantError {}
+
+impl From<ResizeError> for VariantError {
+    fn from(err: ResizeError) -> Self {
+        VariantError::Resize(err)
+    }
+}
+
+impl From<FormatError> for VariantError {
+    fn from(err: FormatError) -> Self {
+        VariantError::Format(err)
+    }
+}
+
+impl From<OpsError> for VariantError {
+    fn from(err: OpsError) -> Self {
+        VariantError::Ops(err)
+    }
+}
+
+impl From<OverlayError> for VariantError {
+    fn from(err: OverlayError) -> Self {
+        VariantError::Overlay(err)
+    }
+}
+
+impl From<AnimationError> for VariantError {
+    fn from(err: AnimationError) -> Self {
+        VariantError::Animation(err)
+    }
+}
+
+impl From<Busy> for VariantError {
+    fn from(err: Busy) -> Self {
+        VariantError::Busy(err)
+    }
+}
+
+impl From<io::Error> for VariantError {
+    fn from(err: io::Error) -> Self {
+        VariantError::Io(err)
+    }
+}
+
+impl From<image::ImageError> for VariantError {
+    fn from(err: image::ImageError) -> Self {
+        VariantError::Image(err)
+    }
+}
+
+impl VariantError {
+    pub fn status_code(&self) -> u16 {
+        match self {
+            VariantError::Resize(_)
+            | VariantError::Format(_)
+            | VariantError::Ops(_)
+            | VariantError::Overlay(_) => 400,
+            VariantError::Animation(err) => err.status_code(),
+            VariantError::MissingOriginal => 404,
+            VariantError::Busy(_) => 503,
+            VariantError::Image(_) => 422,
+            VariantError::Io(_) => 500,
+        }
+    }
+}
+
+/// (de)serialized as its [`Variant::query`], which is how queued jobs keep it on disk.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(into = "String", try_from = "String")]
+pub struct Variant {
+    /// `None` keeps the original dimensions and only re-encodes.
+    pub resize: Option<ResizeOptions>,
+    /// edits applied before the resize, empty for a plain thumbnail.
+    pub ops: Ops,
+    /// stamp the configured [`overlay::Watermark`] on the result.
+    pub watermark: bool,
+    pub caption: Option<String>,
+    /// a still of this frame, otherwise animations keep every frame when the output is GIF.
+    pub frame: Option<u32>,
+    pub format: OutputFormat,
+    pub quality: u8,
+    /// the format came from `Accept`, responses must say `Vary: Accept`.
+    pub negotiated: bool,
+}
+
+impl Variant {
+    /// the output format comes from `format`, else from the extension in the url
+    /// (`/img/{id}.webp`), else it is negotiated from `accept`.
+    pub fn from_params<F>(
+        param: F,
+        extension: Option<&str>,
+        accept: Option<&str>,
+    ) -> Result<Self, VariantError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let param = |name: &str| param(name).filter(|v| !v.trim().is_empty());
+
+        let resize = if RESIZE_PARAMS.iter().any(|name| param(name).is_some()) {
+            Some(ResizeOptions::from_params(param)?)
+        } else {
+            None
+        };
+        let ops = match param("ops") {
+            Some(ops) => Ops::parse(&ops)?,
+            None => Ops::default(),
+        };
+        let watermark = matches!(
+            param("watermark").as_deref().map(str::trim),
+            Some("1" | "true" | "on" | "yes")
+        );
+        if watermark && overlay::watermark().is_none() {
+            return Err(OverlayError::NoWatermark.into());
+        }
+        let caption = param("caption");
+        if let Some(caption) = &caption {
+            overlay::check_caption(caption)?;
+        }
+        let frame = param("frame")
+            .map(|frame| animation::parse_frame(&frame))
+            .transpose()?;
+        let (format, negotiated) = match (param("format"), extension) {
+            (Some(format), _) => (OutputFormat::parse(&format)?, false),
+            (None, Some(extension)) => (OutputFormat::parse(extension)?, false),
+            (None, None) => (format::negotiate(accept), true),
+        };
+        let quality = match param("q") {
+            Some(q) => format::parse_quality(&q)?,
+            None => format::DEFAULT_QUALITY,
+        };
+
+        Ok(Self {
+            resize,
+            ops,
+            watermark,
+            caption,
+            frame,
+            format,
+            quality,
+            negotiated,
+        })
+    }
+
+    /// cache file name, e.g. `320x200-cover-triangle-q80.jpg`,
+    /// ops add a hash of their canonical form: `320x200-cover-triangle-ops3f9a0c1d22be.png`,
+    /// watermarks and captions do the same so a new watermark never serves stale files.
+    pub fn key(&self) -> String {
+        let mut key = match &self.resize {
+            Some(resize) => resize.key(),
+            None => "original".to_string(),
+        };
+        if !self.ops.is_empty() {
+            key.push_str(&format!("-ops{}", self.ops.key()));
+        }
+        if let Some(watermark) = self.watermark.then(overlay::watermark).flatten() {
+            key.push_str(&format!("-wm{}", watermark.fingerprint()));
+        }
+        if let Some(caption) = &self.caption {
+            key.push_str(&format!(
+                "-cap{}",
+                &store::content_id(caption.as_bytes())[..12]
+            ));
+        }
+        if let Some(frame) = self.frame {
+            key.push_str(&format!("-frame{frame}"));
+        }
+        if self.format.uses_quality() {
+            key.push_str(&format!("-q{}", self.quality));
+        }
+        format!("{key}.{}", self.format.extension())
+    }
+
+    /// every parameter spelled out, e.g. `w=320&h=200&fit=cover&filter=triangle&format=jpg&q=80`.
+    pub fn query(&self) -> String {
+        let mut query = vec![];
+        if let Some(resize) = &self.resize {
+            query.push(resize.query());
+        }
+        self.edit_query(&mut query);
+        query.push(format!("format={}", self.format.extension()));
+        if self.format.uses_quality() {
+            query.push(format!("q={}", self.quality));
+        }
+        query.join("&")
+    }
+
+    pub fn from_query(query: &str) -> Result<Self, VariantError> {
+        let params: HashMap<&str, String> = query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| (name, decode_component(value)))
+            .collect();
+        Self::from_params(|name| params.get(name).cloned(), None, None)
+    }
+
+    /// `ops`, `watermark`, `caption` and `frame` as query pairs, shared by [`Variant::query`] and [`Variant::url`].
+    fn edit_query(&self, query: &mut Vec<String>) {
+        if !self.ops.is_empty() {
+            query.push(format!("ops={}", self.ops.canonical()));
+        }
+        if self.watermark {
+            query.push("watermark=1".to_string());
+        }
+        if let Some(caption) = &self.caption {
+            query.push(format!("caption={}", encode_component(caption)));
+        }
+        if let Some(frame) = self.frame {
+            query.push(format!("frame={frame}"));
+        }
+    }
+
+    /// `/img/…` url that renders this variant of `id`.
+    /// a negotiated format is left out, so the url keeps following `Accept`.
+    pub fn url(&self, id: &str) -> String {
+        let mut query = vec![];
+        if let Some(resize) = &self.resize {
+            query.push(resize.query());
+        }
+        self.edit_query(&mut query);
+        let quality = if self.negotiated {
+            self.quality != format::DEFAULT_QUALITY
+        } else {
+            self.format.uses_quality()
+        };
+        if quality {
+            query.push(format!("q={}", self.quality));
+        }
+        let url = if self.negotiated {
+            format!("/img/{id}")
+        } else {
+            format!("/img/{id}.{}", self.format.extension())
+        };
+        if query.is_empty() {
+            url
+        } else {
+            format!("{url}?{}", query.join("&"))
+        }
+    }
+
+    /// `orientation` is the EXIF one, applied before resizing so photos come out upright.
+    /// animated originals rendered as GIF go through this frame by frame, delays untouched.
+    pub fn render(&self, original: &[u8], orientation: u16) -> Result<Vec<u8>, VariantError> {
+        let mut reader = image::io::Reader::new(Cursor::new(original)).with_guessed_format()?;
+        reader.limits(validate::decode_limits());
+        let format = reader.format();
+        let frames = format.map_or(1, |format| animation::count_frames(original, format));
+
+        let img = match (format, self.frame) {
+            (Some(format), None) if frames > 1 && self.format == OutputFormat::Gif => {
+                let frames = metrics::timed(Stage::Decode, || {
+                    animation::decode_frames(original, format, frames)
+                })?
+                .into_iter()
+                .map(|frame| {
+                    let delay = frame.delay();
+                    let img = self.edit(DynamicImage::ImageRgba8(frame.into_buffer()), 1)?;
+                    Ok(Frame::from_parts(img.to_rgba8(), 0, 0, delay))
+                })
+                .collect::<Result<Vec<_>, VariantError>>()?;
+                return Ok(animation::encode_gif(frames)?);
+            }
+            (Some(format), Some(frame)) if frame > 0 => {
+                let decoded = metrics::timed(Stage::Decode, || {
+                    animation::decode_frames(original, format, frame + 1)
+                })?;
+                if decoded.len() <= frame as usize {
+                    return Err(AnimationError::NoSuchFrame {
+                        frame,
+                        frames: decoded.len() as u32,
+                    }
+                    .into());
+                }
+                let still = decoded
+                    .into_iter()
+                    .last()
+                    .expect("checked above")
+                    .into_buffer();
+                self.edit(DynamicImage::ImageRgba8(still), 1)?
+            }
+            //? a plain decode is the first frame, the poster
+            _ => self.edit(
+                metrics::timed(Stage::Decode, || reader.decode())?,
+                orientation,
+            )?,
+        };
+        Ok(self.format.encode(&img, self.quality)?)
+    }
+
+    /// orientation, ops, resize and overlays, the same for a still and every frame.
+    fn edit(&self, img: DynamicImage, orientation: u16) -> Result<DynamicImage, VariantError> {
+        let img = metadata::apply_orientation(img, orientation);
+        let img = self.ops.apply(img)?;
+        let img = match &self.resize {
+            Some(resize) => resize.apply(&img),
+            None => img,
+        };
+        Ok(overlay::apply(img, self.watermark, self.caption.as_deref()))
+    }
+}
+
+impl From<Variant> for String {
+    fn from(variant: Variant) -> Self {
+        variant.query()
+    }
+}
+
+impl TryFrom<String> for Variant {
+    type Error = VariantError;
+
+    fn try_from(query: String) -> Result<Self, Self::Error> {
+        Variant::from_query(&query)
+    }
+}
+
+/// percent-encodes everything but the unreserved characters.
+fn encode_component(value: &str) -> String {
+    value.bytes().fold(String::new(), |mut encoded, byte| {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+        encoded
+    })
+}
+
+fn decode_component(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// `/img/{id}.webp` -> (`{id}`, `Some("webp")`).
+pub fn split_name(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    }
+}
+
+/// returns the cached variant, rendering it from the original on first use.
+/// variants live next to each other in the sharded `out/` directory of `id`,
+/// keyed by size and format, so one original is cached as PNG and WebP side by side.
+/// variants are always re-encoded, so they never carry the original's EXIF/GPS.
+/// only a cache miss decodes, and it needs one of the [`throttle::decodes`] slots first.
+pub fn get_or_render(
+    storage: &dyn Storage,
+    id: &str,
+    variant: &Variant,
+    admission: Admission,
+) -> Result<Vec<u8>, VariantError> {
+    let cached = store::variant_key(id, &variant.key());
+    match storage.read(&cached) {
+        Ok(bytes) => {
+            if let Err(err) = storage.touch(&cached) {
+                println!(">> touch {cached}: {err}");
+            }
+            return Ok(bytes);
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let _permit = throttle::decodes().admit(admission)?;
+    let original = match storage.read(&store::original_key(id)) {
+        Ok(original) => original,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(VariantError::MissingOriginal)
+        }
+        Err(err) => return Err(err.into()),
+    };
+    //? a stripped original lost its EXIF, the record remembers the orientation
+    let orientation = match record::load(storage, id) {
+        Some(record) => record.orientation,
+        None => metadata::orientation(&original),
+    };
+    let bytes = variant.render(&original, orientation)?;
+    storage.put(&cached, &bytes)?;
+    save_etag(storage, &cached, &bytes);
+    Ok(bytes)
+}
+
+/// what [`get_or_render`] stored next to the variant, revalidating needs neither
+/// its bytes nor a hash of them.
+pub fn stored_etag(storage: &dyn Storage, id: &str, variant: &Variant) -> Option<String> {
+    let key = store::etag_key(&store::variant_key(id, &variant.key()));
+    String::from_utf8(storage.read(&key).ok()?).ok()
+}
+
+/// variants cached before ETags were stored get theirs when they are next served.
+pub fn save_etag(storage: &dyn Storage, cached: &str, bytes: &[u8]) -> String {
+    let etag = http_cache::etag(bytes);
+    if let Err(err) = storage.put(&store::etag_key(cached), etag.as_bytes()) {
+        println!(">> etag {cached}: {err}");
+    }
+    etag
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn query_round_trips() {
+        let params = [("w", "320"), ("h", "200"), ("fit", "contain"), ("q", "70")];
+        let variant = Variant::from_params(
+            |name| {
+                params
+                    .iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            },
+            Some("jpg"),
+            None,
+        )
+        .unwrap();
+        let json = serde_json::to_string(&variant).unwrap();
+        assert_eq!(
+            json,
+            "\"w=320&h=200&fit=contain&filter=triangle&format=jpg&q=70\""
+        );
+        assert_eq!(serde_json::from_str::<Variant>(&json).unwrap(), variant);
+        assert!(serde_json::from_str::<Variant>("\"w=0&format=png\"").is_err());
+
+        let captioned = Variant {
+            caption: Some("a&b = ✓".to_string()),
+            ..variant
+        };
+        let json = serde_json::to_string(&captioned).unwrap();
+        assert!(json.contains("&caption=a%26b%20%3D%20%E2%9C%93&"), "{json}");
+        assert_eq!(serde_json::from_str::<Variant>(&json).unwrap(), captioned);
+    }
+
+    #[test]
+    fn renders_once_then_serves_the_cache() {
+        let storage = crate::storage::MemoryStorage::default();
+        let mut png = Cursor::new(vec![]);
+        DynamicImage::new_rgb8(32, 32)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+        let id = store::content_id(png.get_ref());
+        storage
+            .put(&store::original_key(&id), png.get_ref())
+            .unwrap();
+        let params = [("w", "8"), ("h", "8")];
+        let variant = Variant::from_params(
+            |name| {
+                params
+                    .iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            },
+            Some("png"),
+            None,
+        )
+        .unwrap();
+
+        let rendered = get_or_render(&storage, &id, &variant, Admission::Wait).unwrap();
+        let cached = store::variant_key(&id, &variant.key());
+        assert_eq!(storage.read(&cached).unwrap(), rendered);
+        assert_eq!(
+            stored_etag(&storage, &id, &variant),
+            Some(http_cache::etag(&rendered))
+        );
+
+        //? without its original a second render would fail, so this one is read back
+        storage.delete(&store::original_key(&id)).unwrap();
+        assert_eq!(
+            get_or_render(&storage, &id, &variant, Admission::Wait).unwrap(),
+            rendered
+        );
+    }
+}